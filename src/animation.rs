@@ -0,0 +1,300 @@
+//! Keyframed animation of the camera and of the objects of a scene.
+
+use crate::math::{Quat, Vec3};
+use crate::scene::Scene;
+use crate::shape::Shape;
+
+/// Easing curve used between a keyframe and the next one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interpolation {
+    /// Hold the value until the next keyframe.
+    Step,
+    Linear,
+    EaseIn,
+    EaseOut,
+    EaseInOut,
+}
+
+impl Interpolation {
+    /// Remaps a linear progress in [0, 1] along the curve.
+    pub fn ease(&self, t: f64) -> f64 {
+        match self {
+            Interpolation::Step => 0.0,
+            Interpolation::Linear => t,
+            Interpolation::EaseIn => t * t,
+            Interpolation::EaseOut => t * (2.0 - t),
+            Interpolation::EaseInOut => t * t * (3.0 - 2.0 * t),
+        }
+    }
+}
+
+/// A value that can be blended between two keyframes.
+pub trait Animatable: Copy {
+    fn interpolate(&self, other: Self, t: f64) -> Self;
+}
+
+impl Animatable for f64 {
+    fn interpolate(&self, other: f64, t: f64) -> f64 {
+        self + (other - self) * t
+    }
+}
+
+impl Animatable for Vec3 {
+    fn interpolate(&self, other: Vec3, t: f64) -> Vec3 {
+        self.lerp(other, t)
+    }
+}
+
+impl Animatable for Quat {
+    fn interpolate(&self, other: Quat, t: f64) -> Quat {
+        self.slerp(other, t)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Keyframe<T> {
+    pub time: f64,
+    pub value: T,
+    /// Curve used from this keyframe to the next one.
+    pub interpolation: Interpolation,
+}
+
+#[derive(Clone, Debug)]
+pub struct Track<T> {
+    keyframes: Vec<Keyframe<T>>,
+}
+
+impl<T> Default for Track<T> {
+    fn default() -> Track<T> {
+        Track {
+            keyframes: Vec::new(),
+        }
+    }
+}
+
+impl<T: Animatable> Track<T> {
+    pub fn new() -> Track<T> {
+        Track::default()
+    }
+
+    /// Adds a keyframe, replacing any keyframe already at that exact time.
+    pub fn insert(&mut self, time: f64, value: T, interpolation: Interpolation) {
+        let keyframe = Keyframe {
+            time,
+            value,
+            interpolation,
+        };
+        match self.keyframes.binary_search_by(|k| k.time.total_cmp(&time)) {
+            Ok(index) => self.keyframes[index] = keyframe,
+            Err(index) => self.keyframes.insert(index, keyframe),
+        }
+    }
+
+    pub fn keyframes(&self) -> &[Keyframe<T>] {
+        &self.keyframes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keyframes.is_empty()
+    }
+
+    /// Value of the track at `time`, held constant before the first and after the last keyframe.
+    pub fn sample(&self, time: f64) -> Option<T> {
+        let first = self.keyframes.first()?;
+        if time <= first.time {
+            return Some(first.value);
+        }
+        let next = self.keyframes.partition_point(|k| k.time <= time);
+        if next == self.keyframes.len() {
+            return self.keyframes.last().map(|k| k.value);
+        }
+        let from = &self.keyframes[next - 1];
+        let to = &self.keyframes[next];
+        let t = (time - from.time) / (to.time - from.time);
+        Some(from.value.interpolate(to.value, from.interpolation.ease(t)))
+    }
+
+    /// Time of the last keyframe.
+    pub fn end(&self) -> f64 {
+        self.keyframes.last().map_or(0.0, |k| k.time)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CameraTracks {
+    pub position: Track<Vec3>,
+    /// Point the camera looks at.
+    pub target: Track<Vec3>,
+    /// Roll around the view direction, applied after aiming at the target.
+    pub roll: Track<f64>,
+    pub fov: Track<f64>,
+    pub focal_distance: Track<f64>,
+    pub aperture_radius: Track<f64>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ObjectTracks {
+    pub position: Track<Vec3>,
+    pub rotation: Track<Quat>,
+    pub scale: Track<Vec3>,
+    pub inflate: Track<f64>,
+    /// Power of a Mandelbulb, ignored for other shapes.
+    pub power: Track<f64>,
+}
+
+impl ObjectTracks {
+    fn end(&self) -> f64 {
+        self.position
+            .end()
+            .max(self.rotation.end())
+            .max(self.scale.end())
+            .max(self.inflate.end())
+            .max(self.power.end())
+    }
+}
+
+/// Animation of a whole scene. Objects are referred to by their index in [`Scene::objects`].
+/// The objects inside instances are shared, and their tracks are ignored.
+#[derive(Clone, Debug, Default)]
+pub struct Timeline {
+    pub camera: CameraTracks,
+    pub objects: Vec<(usize, ObjectTracks)>,
+}
+
+impl Timeline {
+    pub fn new() -> Timeline {
+        Timeline::default()
+    }
+
+    /// Tracks of the object at `index`, created empty the first time.
+    pub fn object(&mut self, index: usize) -> &mut ObjectTracks {
+        let position = match self.objects.iter().position(|(i, _)| *i == index) {
+            Some(position) => position,
+            None => {
+                self.objects.push((index, ObjectTracks::default()));
+                self.objects.len() - 1
+            }
+        };
+        &mut self.objects[position].1
+    }
+
+    /// Time of the last keyframe of any track.
+    pub fn duration(&self) -> f64 {
+        let camera = &self.camera;
+        self.objects.iter().map(|(_, tracks)| tracks.end()).fold(
+            camera
+                .position
+                .end()
+                .max(camera.target.end())
+                .max(camera.roll.end())
+                .max(camera.fov.end())
+                .max(camera.focal_distance.end())
+                .max(camera.aperture_radius.end()),
+            f64::max,
+        )
+    }
+
+    /// Poses the scene as it is at `time`. Properties without keyframes are left untouched.
+    pub fn apply(&self, scene: &mut Scene, time: f64) {
+        scene.time = time;
+        let tracks = &self.camera;
+        let camera = &mut scene.camera;
+        if let Some(position) = tracks.position.sample(time) {
+            camera.position = position;
+        }
+        if let Some(target) = tracks.target.sample(time) {
+            camera.look_at(target);
+        }
+        if let Some(roll) = tracks.roll.sample(time) {
+            camera.roll(roll);
+        }
+        if let Some(fov) = tracks.fov.sample(time) {
+            camera.fov = fov;
+        }
+        if let Some(focal_distance) = tracks.focal_distance.sample(time) {
+            camera.focal_distance = focal_distance;
+        }
+        if let Some(aperture_radius) = tracks.aperture_radius.sample(time) {
+            camera.aperture_radius = aperture_radius;
+        }
+
+        let mut objects = scene.objects_mut();
+        for (index, tracks) in &self.objects {
+            let Some(Some(object)) = objects.get_mut(*index) else {
+                continue;
+            };
+            if let Some(position) = tracks.position.sample(time) {
+                object.position = position;
+            }
+            if let Some(rotation) = tracks.rotation.sample(time) {
+                object.rotation = rotation;
+            }
+            if let Some(scale) = tracks.scale.sample(time) {
+                object.scale = scale;
+            }
+            if let Some(inflate) = tracks.inflate.sample(time) {
+                object.inflate = inflate;
+            }
+            if let (Some(new_power), Shape::Mandelbulb { power, .. }) =
+                (tracks.power.sample(time), &mut object.shape)
+            {
+                *power = new_power;
+            }
+        }
+    }
+
+    /// Renders every frame of `sequence` to `dir_name/frame_00000.png`, `frame_00001.png`, ...
+    pub fn render_sequence(
+        &self,
+        scene: &Scene,
+        sequence: &Sequence,
+        width: u32,
+        height: u32,
+        dir_name: &str,
+    ) {
+        std::fs::create_dir_all(dir_name).unwrap();
+        let frames = sequence.frame_count();
+        for frame in 0..frames {
+            let frame_time = sequence.frame_time(frame);
+            let mut posed = scene.clone();
+            self.apply(&mut posed, frame_time);
+            posed.frame = frame;
+            println!("Frame {}/{}", frame + 1, frames);
+            posed
+                .render_posed(width, height, |time| {
+                    let mut shutter_pose = scene.clone();
+                    self.apply(&mut shutter_pose, time);
+                    shutter_pose.frame = frame;
+                    // velocities move objects relative to where the keyframes put them at the frame time
+                    shutter_pose.time = frame_time;
+                    shutter_pose.advanced(time)
+                })
+                .final_image(width, height)
+                .save(format!("{}/frame_{:05}.png", dir_name, frame))
+                .unwrap();
+        }
+    }
+}
+
+/// Range of time to render, and at which frame rate.
+#[derive(Clone, Copy, Debug)]
+pub struct Sequence {
+    pub start: f64,
+    pub end: f64,
+    pub fps: f64,
+}
+
+impl Sequence {
+    pub fn new(start: f64, end: f64, fps: f64) -> Sequence {
+        Sequence { start, end, fps }
+    }
+
+    /// Number of frames, the last one being at `end` or just before.
+    pub fn frame_count(&self) -> u32 {
+        ((self.end - self.start) * self.fps).floor() as u32 + 1
+    }
+
+    pub fn frame_time(&self, frame: u32) -> f64 {
+        self.start + frame as f64 / self.fps
+    }
+}
@@ -0,0 +1,96 @@
+//! Bounding volumes of the tree, for compiled scenes to skip the children of a union that
+//! can't be the nearest one.
+//!
+//! A bound is a box and a factor: anywhere outside the box, a subtree evaluates to at least
+//! the distance to the box divided by the factor. The factor makes up for scaled objects,
+//! whose distances are measured in their own units.
+
+use crate::interval::Aabb;
+use crate::math::Vec3;
+use crate::scene::{Instance, Operation, TreeNode};
+use crate::shape::{Object, Shape};
+
+/// Slack on the factors, so that rounding can't put a bound above the distance it bounds.
+const ROUNDING: f64 = 1.0 + 1e-9;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Bounds {
+    pub aabb: Aabb,
+    pub factor: f64,
+}
+
+impl Bounds {
+    /// Lower bound on the distance of the subtree at `point`, zero inside the box.
+    pub fn distance(&self, point: Vec3) -> f64 {
+        self.aabb.distance(point) / self.factor
+    }
+
+    /// Bounds of a union of the two subtrees.
+    pub fn union(&self, other: &Bounds) -> Bounds {
+        Bounds {
+            aabb: self.aabb.union(&other.aabb),
+            factor: self.factor.max(other.factor),
+        }
+    }
+}
+
+impl Object {
+    /// Bounds of the object, `None` for objects with a vertex shader or a displacement,
+    /// which can end up anywhere.
+    pub fn bounds(&self) -> Option<Bounds> {
+        if self.vertex_shader.is_some() || self.displacement.is_some() {
+            return None;
+        }
+        // beyond its escape radius, a Mandelbulb is further away than the sphere of radius 2
+        let extent = match self.shape {
+            Shape::Sphere | Shape::Cube => 1.0,
+            Shape::Mandelbulb { .. } => 2.0,
+        } + self.inflate.max(0.0);
+        let local = Aabb::new(
+            Vec3::new(-extent, -extent, -extent),
+            Vec3::new(extent, extent, extent),
+        );
+        let factor = self.scale.abs().max_element();
+        (factor > 0.0).then(|| Bounds {
+            aabb: local.transformed(self.position, self.rotation, self.scale),
+            factor: factor * ROUNDING,
+        })
+    }
+}
+
+impl Instance {
+    /// Bounds of the shared subtree, placed like the instance.
+    pub fn bounds(&self) -> Option<Bounds> {
+        if self.scale <= 0.0 {
+            return None;
+        }
+        let bounds = self.tree.bounds()?;
+        let scale = Vec3::new(self.scale, self.scale, self.scale);
+        Some(Bounds {
+            aabb: bounds.aabb.transformed(self.position, self.rotation, scale),
+            factor: bounds.factor,
+        })
+    }
+}
+
+impl TreeNode {
+    /// Bounds of the subtree, `None` if some of it can't be bounded. Smooth unions aren't,
+    /// as blending pulls their surface out of their children's boxes.
+    pub fn bounds(&self) -> Option<Bounds> {
+        match self {
+            TreeNode::Leaf(object) => object.bounds(),
+            TreeNode::Node(tree) => match tree.operation {
+                Operation::Union => tree
+                    .children
+                    .iter()
+                    .map(TreeNode::bounds)
+                    .reduce(|left, right| Some(left?.union(&right?)))
+                    .flatten(),
+                // an intersection is inside each of its children
+                Operation::Intersection => tree.children.iter().find_map(TreeNode::bounds),
+                Operation::SmoothUnion(_) => None,
+            },
+            TreeNode::Instance(instance) => instance.bounds(),
+        }
+    }
+}
@@ -1,16 +1,40 @@
 //! Camera for a ray-marching renderer.
 
-use crate::math::{Quat, Vec3};
+use crate::math::{Mat4, Quat, Vec3};
 
 #[derive(Clone, Copy, Debug)]
 pub struct Ray {
     pub origin: Vec3,
     pub direction: Vec3,
+    /// Instant the ray samples, within the camera's shutter interval.
+    pub time: f64,
+    /// Width of the cone covered by the ray per unit of distance, zero for an infinitely thin ray.
+    pub footprint: f64,
+    /// Distance along the ray known to be empty, where marching starts.
+    pub near: f64,
 }
 
 impl Ray {
     pub fn new(origin: Vec3, direction: Vec3) -> Ray {
-        Ray { origin, direction }
+        Ray {
+            origin,
+            direction,
+            time: 0.0,
+            footprint: 0.0,
+            near: 0.0,
+        }
+    }
+
+    pub fn at_time(self, time: f64) -> Ray {
+        Ray { time, ..self }
+    }
+
+    pub fn with_footprint(self, footprint: f64) -> Ray {
+        Ray { footprint, ..self }
+    }
+
+    pub fn starting_at(self, near: f64) -> Ray {
+        Ray { near, ..self }
     }
 
     pub fn point(&self, t: f64) -> Vec3 {
@@ -18,6 +42,60 @@ impl Ray {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ApertureShape {
+    Circle,
+    /// Regular polygon, giving n-gon shaped bokeh.
+    Polygon {
+        sides: u32,
+        rotation: f64,
+    },
+}
+
+impl ApertureShape {
+    /// Maps a uniform sample in [0, 1)^2 to a uniformly distributed point of the unit aperture.
+    pub fn sample(&self, u: f64, v: f64) -> (f64, f64) {
+        match *self {
+            ApertureShape::Circle => {
+                // concentric mapping keeps stratified samples well spread over the disk
+                let a = 2.0 * u - 1.0;
+                let b = 2.0 * v - 1.0;
+                if a == 0.0 && b == 0.0 {
+                    return (0.0, 0.0);
+                }
+                let (r, theta) = if a.abs() > b.abs() {
+                    (a, std::f64::consts::FRAC_PI_4 * (b / a))
+                } else {
+                    (
+                        b,
+                        std::f64::consts::FRAC_PI_2 - std::f64::consts::FRAC_PI_4 * (a / b),
+                    )
+                };
+                (r * theta.cos(), r * theta.sin())
+            }
+            ApertureShape::Polygon { sides, rotation } => {
+                let sides = sides.max(3);
+                let slice = 2.0 * std::f64::consts::PI / sides as f64;
+                // pick one of the triangles fanning out of the centre, then a point inside it
+                let scaled = u * sides as f64;
+                let index = (scaled.floor() as u32).min(sides - 1);
+                let u = scaled - index as f64;
+                let (mut a, mut b) = (u, v);
+                if a + b > 1.0 {
+                    a = 1.0 - a;
+                    b = 1.0 - b;
+                }
+                let angle0 = rotation + slice * index as f64;
+                let angle1 = angle0 + slice;
+                (
+                    a * angle0.cos() + b * angle1.cos(),
+                    a * angle0.sin() + b * angle1.sin(),
+                )
+            }
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct Camera {
     pub position: Vec3,
@@ -26,6 +104,15 @@ pub struct Camera {
     pub right: Vec3,
     pub fov: f64,
     pub aspect_ratio: f64,
+    /// Radius of the thin lens. Zero makes a perfect pinhole camera.
+    pub aperture_radius: f64,
+    /// Distance along the view direction of the plane that is in perfect focus.
+    pub focal_distance: f64,
+    pub aperture_shape: ApertureShape,
+    /// Shutter interval relative to the frame time, in seconds. Motion blur is
+    /// rendered when `shutter_close` is after `shutter_open`.
+    pub shutter_open: f64,
+    pub shutter_close: f64,
 }
 
 impl Camera {
@@ -39,6 +126,11 @@ impl Camera {
             right,
             fov,
             aspect_ratio,
+            aperture_radius: 0.0,
+            focal_distance: 1.0,
+            aperture_shape: ApertureShape::Circle,
+            shutter_open: 0.0,
+            shutter_close: 0.0,
         }
     }
 
@@ -55,6 +147,36 @@ impl Camera {
         )
     }
 
+    /// Width of a pixel per unit of distance from the camera, for an image `height` pixels tall.
+    pub fn pixel_footprint(&self, height: u32) -> f64 {
+        self.fov.tan() / height as f64
+    }
+
+    /// Ray through the thin lens, `(u, v)` being a uniform sample in [0, 1)^2 of the aperture.
+    pub fn lens_ray(&self, x: f64, y: f64, u: f64, v: f64) -> Ray {
+        let pinhole = self.ray(x, y);
+        if self.aperture_radius <= 0.0 {
+            return pinhole;
+        }
+        let focus = pinhole.point(self.focal_distance / pinhole.direction.dot(self.direction));
+        let (lx, ly) = self.aperture_shape.sample(u, v);
+        let origin = self.position
+            + self.right * (lx * self.aperture_radius)
+            + self.up * (ly * self.aperture_radius);
+        Ray::new(origin, (focus - origin).normalize())
+    }
+
+    pub fn set_shutter(&mut self, open: f64, close: f64) {
+        self.shutter_open = open;
+        self.shutter_close = close;
+    }
+
+    pub fn set_aperture(&mut self, radius: f64, focal_distance: f64, shape: ApertureShape) {
+        self.aperture_radius = radius;
+        self.focal_distance = focal_distance;
+        self.aperture_shape = shape;
+    }
+
     pub fn rotate(&mut self, yaw: f64, pitch: f64) {
         let yaw = Quat::from_axis_angle(self.up, yaw);
         let pitch = Quat::from_axis_angle(self.right, pitch);
@@ -70,4 +192,98 @@ impl Camera {
     pub fn move_right(&mut self, amount: f64) {
         self.position += self.right * amount;
     }
+
+    /// Turns the camera towards `target`, keeping the world Y axis up.
+    /// Any roll is reset.
+    pub fn look_at(&mut self, target: Vec3) {
+        self.look_at_with_up(target, Vec3::new(0.0, 1.0, 0.0));
+    }
+
+    /// Does nothing when `target` is the camera's position, which has no direction to it.
+    pub fn look_at_with_up(&mut self, target: Vec3, up: Vec3) {
+        let offset = target - self.position;
+        if offset.length() == 0.0 {
+            return;
+        }
+        let direction = offset.normalize();
+        // looking straight along the up vector, fall back to the current up
+        let up = if direction.cross(up).length() < 1e-9 {
+            self.up
+        } else {
+            up
+        };
+        self.direction = direction;
+        self.right = self.direction.cross(up).normalize();
+        self.up = self.right.cross(self.direction).normalize();
+    }
+
+    /// Rotates the camera around its view direction.
+    pub fn roll(&mut self, angle: f64) {
+        let roll = Quat::from_axis_angle(self.direction, angle);
+        self.up = roll.rotate(self.up).normalize();
+        self.right = self.direction.cross(self.up).normalize();
+    }
+
+    /// Moves the camera around `target`, yaw around the camera's up axis and pitch around its
+    /// right axis, turning it so that the target stays at the same place in the frame.
+    pub fn orbit(&mut self, target: Vec3, yaw: f64, pitch: f64) {
+        let yaw = Quat::from_axis_angle(self.up, yaw);
+        let pitch = Quat::from_axis_angle(self.right, pitch);
+        let rotation = yaw * pitch;
+        self.position = target + rotation.rotate(self.position - target);
+        self.direction = rotation.rotate(self.direction).normalize();
+        self.up = rotation.rotate(self.up).normalize();
+        self.right = self.direction.cross(self.up).normalize();
+    }
+
+    /// Moves the camera towards `target` by `amount`, never going past it.
+    /// Negative amounts move away from the target.
+    pub fn dolly(&mut self, target: Vec3, amount: f64) {
+        let offset = target - self.position;
+        let distance = offset.length();
+        if distance == 0.0 {
+            return;
+        }
+        // closer than that, moving forward stays put rather than going backwards
+        let amount = if amount > 0.0 {
+            amount.min(distance - 1e-6).max(0.0)
+        } else {
+            amount
+        };
+        self.position += offset.normalize() * amount;
+    }
+
+    /// Narrows the field of view by `factor` (greater than 1 zooms in). Factors that aren't
+    /// positive and finite would give no field of view at all, and are ignored.
+    pub fn zoom(&mut self, factor: f64) {
+        if !(factor > 0.0 && factor.is_finite()) {
+            return;
+        }
+        self.fov = (self.fov.tan() / factor).atan();
+    }
+
+    /// World to camera matrix, with the camera looking down -Z and Y up.
+    pub fn view_matrix(&self) -> Mat4 {
+        let r = self.right;
+        let u = self.up;
+        let f = self.direction;
+        let p = self.position;
+        Mat4::new([
+            [r.x, r.y, r.z, -r.dot(p)],
+            [u.x, u.y, u.z, -u.dot(p)],
+            [-f.x, -f.y, -f.z, f.dot(p)],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+
+    /// Builds a camera from a world to camera matrix as returned by [`Camera::view_matrix`].
+    pub fn from_view_matrix(view: Mat4, fov: f64, aspect_ratio: f64) -> Camera {
+        let right = view.row(0).normalize();
+        let up = view.row(1).normalize();
+        let direction = -view.row(2).normalize();
+        let translation = Vec3::new(view.m[0][3], view.m[1][3], view.m[2][3]);
+        // inverse of the rotation is its transpose
+        let position = -(right * translation.x + up * translation.y - direction * translation.z);
+        Camera::new(position, direction, up, fov, aspect_ratio)
+    }
 }
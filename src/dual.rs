@@ -0,0 +1,300 @@
+//! Forward-mode automatic differentiation: values carrying their gradient with respect to
+//! a point in space, so that a distance and its gradient come out of a single evaluation.
+
+use crate::math::{Quat, Vec3};
+
+/// A value and its gradient with respect to the evaluated point.
+#[derive(Clone, Copy, Debug)]
+pub struct Dual {
+    pub value: f64,
+    pub gradient: Vec3,
+}
+
+impl Dual {
+    pub fn new(value: f64, gradient: Vec3) -> Dual {
+        Dual { value, gradient }
+    }
+
+    /// A value that doesn't depend on the point.
+    pub fn constant(value: f64) -> Dual {
+        Dual::new(value, Vec3::new(0.0, 0.0, 0.0))
+    }
+
+    /// Applies a function given its value and derivative at `self.value`.
+    fn chain(self, value: f64, derivative: f64) -> Dual {
+        Dual::new(value, self.gradient * derivative)
+    }
+
+    pub fn sqrt(self) -> Dual {
+        let root = self.value.sqrt();
+        let derivative = if root > 0.0 { 0.5 / root } else { 0.0 };
+        self.chain(root, derivative)
+    }
+
+    pub fn abs(self) -> Dual {
+        self.chain(self.value.abs(), if self.value < 0.0 { -1.0 } else { 1.0 })
+    }
+
+    pub fn min(self, other: Dual) -> Dual {
+        if other.value < self.value {
+            other
+        } else {
+            self
+        }
+    }
+
+    pub fn max(self, other: Dual) -> Dual {
+        if other.value > self.value {
+            other
+        } else {
+            self
+        }
+    }
+
+    pub fn sin(self) -> Dual {
+        self.chain(self.value.sin(), self.value.cos())
+    }
+
+    pub fn cos(self) -> Dual {
+        self.chain(self.value.cos(), -self.value.sin())
+    }
+
+    pub fn acos(self) -> Dual {
+        let slope = 1.0 - self.value * self.value;
+        let derivative = if slope > 0.0 {
+            -1.0 / slope.sqrt()
+        } else {
+            0.0
+        };
+        self.chain(self.value.acos(), derivative)
+    }
+
+    /// Angle of the point (`x`, `self`), like [`f64::atan2`].
+    pub fn atan2(self, x: Dual) -> Dual {
+        let y = self;
+        let length_squared = x.value * x.value + y.value * y.value;
+        let gradient = if length_squared > 0.0 {
+            (y.gradient * x.value - x.gradient * y.value) / length_squared
+        } else {
+            Vec3::new(0.0, 0.0, 0.0)
+        };
+        Dual::new(y.value.atan2(x.value), gradient)
+    }
+
+    pub fn powf(self, exponent: f64) -> Dual {
+        self.chain(
+            self.value.powf(exponent),
+            exponent * self.value.powf(exponent - 1.0),
+        )
+    }
+
+    pub fn ln(self) -> Dual {
+        self.chain(self.value.ln(), 1.0 / self.value)
+    }
+}
+
+impl std::ops::Add for Dual {
+    type Output = Dual;
+
+    fn add(self, other: Dual) -> Dual {
+        Dual::new(self.value + other.value, self.gradient + other.gradient)
+    }
+}
+
+impl std::ops::Add<f64> for Dual {
+    type Output = Dual;
+
+    fn add(self, scalar: f64) -> Dual {
+        Dual::new(self.value + scalar, self.gradient)
+    }
+}
+
+impl std::ops::Sub for Dual {
+    type Output = Dual;
+
+    fn sub(self, other: Dual) -> Dual {
+        Dual::new(self.value - other.value, self.gradient - other.gradient)
+    }
+}
+
+impl std::ops::Sub<f64> for Dual {
+    type Output = Dual;
+
+    fn sub(self, scalar: f64) -> Dual {
+        Dual::new(self.value - scalar, self.gradient)
+    }
+}
+
+impl std::ops::Mul for Dual {
+    type Output = Dual;
+
+    fn mul(self, other: Dual) -> Dual {
+        Dual::new(
+            self.value * other.value,
+            self.gradient * other.value + other.gradient * self.value,
+        )
+    }
+}
+
+impl std::ops::Mul<f64> for Dual {
+    type Output = Dual;
+
+    fn mul(self, scalar: f64) -> Dual {
+        Dual::new(self.value * scalar, self.gradient * scalar)
+    }
+}
+
+impl std::ops::Div for Dual {
+    type Output = Dual;
+
+    fn div(self, other: Dual) -> Dual {
+        Dual::new(
+            self.value / other.value,
+            (self.gradient * other.value - other.gradient * self.value)
+                / (other.value * other.value),
+        )
+    }
+}
+
+impl std::ops::Div<f64> for Dual {
+    type Output = Dual;
+
+    fn div(self, scalar: f64) -> Dual {
+        Dual::new(self.value / scalar, self.gradient / scalar)
+    }
+}
+
+impl std::ops::Neg for Dual {
+    type Output = Dual;
+
+    fn neg(self) -> Dual {
+        Dual::new(-self.value, -self.gradient)
+    }
+}
+
+/// A point whose coordinates carry their gradient.
+#[derive(Clone, Copy, Debug)]
+pub struct DualVec3 {
+    pub x: Dual,
+    pub y: Dual,
+    pub z: Dual,
+}
+
+impl DualVec3 {
+    pub fn new(x: Dual, y: Dual, z: Dual) -> DualVec3 {
+        DualVec3 { x, y, z }
+    }
+
+    /// The point everything is differentiated with respect to.
+    pub fn variable(p: Vec3) -> DualVec3 {
+        DualVec3::new(
+            Dual::new(p.x, Vec3::new(1.0, 0.0, 0.0)),
+            Dual::new(p.y, Vec3::new(0.0, 1.0, 0.0)),
+            Dual::new(p.z, Vec3::new(0.0, 0.0, 1.0)),
+        )
+    }
+
+    pub fn constant(v: Vec3) -> DualVec3 {
+        DualVec3::new(
+            Dual::constant(v.x),
+            Dual::constant(v.y),
+            Dual::constant(v.z),
+        )
+    }
+
+    pub fn value(&self) -> Vec3 {
+        Vec3::new(self.x.value, self.y.value, self.z.value)
+    }
+
+    pub fn cross(&self, other: DualVec3) -> DualVec3 {
+        DualVec3::new(
+            self.y * other.z - self.z * other.y,
+            self.z * other.x - self.x * other.z,
+            self.x * other.y - self.y * other.x,
+        )
+    }
+
+    pub fn length(&self) -> Dual {
+        (self.x * self.x + self.y * self.y + self.z * self.z).sqrt()
+    }
+
+    pub fn abs(&self) -> DualVec3 {
+        DualVec3::new(self.x.abs(), self.y.abs(), self.z.abs())
+    }
+
+    pub fn max(&self, other: DualVec3) -> DualVec3 {
+        DualVec3::new(
+            self.x.max(other.x),
+            self.y.max(other.y),
+            self.z.max(other.z),
+        )
+    }
+
+    pub fn min(&self, other: DualVec3) -> DualVec3 {
+        DualVec3::new(
+            self.x.min(other.x),
+            self.y.min(other.y),
+            self.z.min(other.z),
+        )
+    }
+
+    pub fn max_element(&self) -> Dual {
+        self.x.max(self.y).max(self.z)
+    }
+
+    /// Rotates the point like [`Quat::rotate`].
+    pub fn rotate(&self, q: Quat) -> DualVec3 {
+        let qv = DualVec3::constant(Vec3::new(q.x, q.y, q.z));
+        let uv = qv.cross(*self);
+        let uuv = qv.cross(uv);
+        uv * (q.w * 2.0) + uuv * 2.0 + *self
+    }
+}
+
+impl std::ops::Add for DualVec3 {
+    type Output = DualVec3;
+
+    fn add(self, other: DualVec3) -> DualVec3 {
+        DualVec3::new(self.x + other.x, self.y + other.y, self.z + other.z)
+    }
+}
+
+impl std::ops::Sub for DualVec3 {
+    type Output = DualVec3;
+
+    fn sub(self, other: DualVec3) -> DualVec3 {
+        DualVec3::new(self.x - other.x, self.y - other.y, self.z - other.z)
+    }
+}
+
+impl std::ops::Sub<Vec3> for DualVec3 {
+    type Output = DualVec3;
+
+    fn sub(self, other: Vec3) -> DualVec3 {
+        DualVec3::new(self.x - other.x, self.y - other.y, self.z - other.z)
+    }
+}
+
+impl std::ops::Mul<f64> for DualVec3 {
+    type Output = DualVec3;
+
+    fn mul(self, scalar: f64) -> DualVec3 {
+        DualVec3::new(self.x * scalar, self.y * scalar, self.z * scalar)
+    }
+}
+
+impl std::ops::Mul<Vec3> for DualVec3 {
+    type Output = DualVec3;
+
+    fn mul(self, other: Vec3) -> DualVec3 {
+        DualVec3::new(self.x * other.x, self.y * other.y, self.z * other.z)
+    }
+}
+
+impl std::ops::Div<Vec3> for DualVec3 {
+    type Output = DualVec3;
+
+    fn div(self, other: Vec3) -> DualVec3 {
+        DualVec3::new(self.x / other.x, self.y / other.y, self.z / other.z)
+    }
+}
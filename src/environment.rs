@@ -0,0 +1,317 @@
+//! What rays that escape the scene see: the background, and the light coming from it.
+
+use crate::math::Vec3;
+use crate::sampling::Rng;
+use image::error::{ImageError, ParameterError, ParameterErrorKind};
+use std::f64::consts::PI;
+use std::fmt::Debug;
+use std::fmt::Formatter;
+use std::path::Path;
+use std::sync::Arc;
+
+#[derive(Clone, Debug, Default)]
+pub enum Environment {
+    /// Nothing at all: misses stay black and get the silhouette glow of `Render::to_png`.
+    #[default]
+    Void,
+    Solid(Vec3),
+    /// Blend from the horizon up to the zenith, and a flat ground colour below the horizon.
+    Gradient {
+        zenith: Vec3,
+        horizon: Vec3,
+        ground: Vec3,
+    },
+    Sky(PreethamSky),
+    Map(Arc<EnvironmentMap>),
+}
+
+impl Environment {
+    /// Radiance coming from `direction`.
+    pub fn sample(&self, direction: Vec3) -> Vec3 {
+        match self {
+            Environment::Void => Vec3::new(0.0, 0.0, 0.0),
+            Environment::Solid(colour) => *colour,
+            Environment::Gradient {
+                zenith,
+                horizon,
+                ground,
+            } => {
+                if direction.y < 0.0 {
+                    *ground
+                } else {
+                    horizon.lerp(*zenith, direction.y.sqrt())
+                }
+            }
+            Environment::Sky(sky) => sky.sample(direction),
+            Environment::Map(map) => map.sample(direction),
+        }
+    }
+
+    /// Picks a direction to gather light from. Maps are importance sampled, other
+    /// environments are sampled uniformly over the sphere. Returns the direction and its
+    /// probability density, per steradian.
+    pub fn sample_direction(&self, rng: &mut Rng) -> (Vec3, f64) {
+        match self {
+            Environment::Map(map) => map.sample_direction(rng.next_f64(), rng.next_f64()),
+            _ => {
+                let z = 1.0 - 2.0 * rng.next_f64();
+                let r = (1.0 - z * z).max(0.0).sqrt();
+                let phi = 2.0 * PI * rng.next_f64();
+                (Vec3::new(r * phi.cos(), z, r * phi.sin()), 1.0 / (4.0 * PI))
+            }
+        }
+    }
+
+    pub fn is_void(&self) -> bool {
+        matches!(self, Environment::Void)
+    }
+}
+
+/// Preetham, Shirley and Smits' analytic daylight model.
+#[derive(Clone, Copy, Debug)]
+pub struct PreethamSky {
+    /// Direction towards the sun.
+    pub sun_direction: Vec3,
+    /// Haziness of the atmosphere, from 2 (very clear) to 10 (hazy).
+    pub turbidity: f64,
+    /// Scales the sky's luminance, in kcd/m^2, down to displayable values.
+    pub exposure: f64,
+    /// Angular radius of the visible sun disk, in radians.
+    pub sun_radius: f64,
+    pub sun_intensity: f64,
+    /// Colour below the horizon.
+    pub ground: Vec3,
+}
+
+impl PreethamSky {
+    pub fn new(sun_direction: Vec3, turbidity: f64) -> PreethamSky {
+        PreethamSky {
+            sun_direction: sun_direction.normalize(),
+            turbidity,
+            exposure: 0.05,
+            sun_radius: 0.02,
+            sun_intensity: 20.0,
+            ground: Vec3::new(0.3, 0.28, 0.25),
+        }
+    }
+
+    fn perez(theta: f64, gamma: f64, coefficients: [f64; 5]) -> f64 {
+        let [a, b, c, d, e] = coefficients;
+        (1.0 + a * (b / theta.cos().max(0.01)).exp())
+            * (1.0 + c * (d * gamma).exp() + e * gamma.cos() * gamma.cos())
+    }
+
+    pub fn sample(&self, direction: Vec3) -> Vec3 {
+        let direction = direction.normalize();
+        if direction.y < 0.0 {
+            return self.ground;
+        }
+        let t = self.turbidity;
+        let sun = self.sun_direction;
+        let theta_sun = sun.y.clamp(-1.0, 1.0).acos().min(PI / 2.0);
+        let theta = direction.y.clamp(-1.0, 1.0).acos();
+        let gamma = direction.dot(sun).clamp(-1.0, 1.0).acos();
+
+        let luminance = [
+            0.1787 * t - 1.4630,
+            -0.3554 * t + 0.4275,
+            -0.0227 * t + 5.3251,
+            0.1206 * t - 2.5771,
+            -0.0670 * t + 0.3703,
+        ];
+        let x_coefficients = [
+            -0.0193 * t - 0.2592,
+            -0.0665 * t + 0.0008,
+            -0.0004 * t + 0.2125,
+            -0.0641 * t - 0.8989,
+            -0.0033 * t + 0.0452,
+        ];
+        let y_coefficients = [
+            -0.0167 * t - 0.2608,
+            -0.0950 * t + 0.0092,
+            -0.0079 * t + 0.2102,
+            -0.0441 * t - 1.6537,
+            -0.0109 * t + 0.0529,
+        ];
+
+        // values at the zenith
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_sun);
+        let zenith_luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let (s, s2, s3) = (
+            theta_sun,
+            theta_sun * theta_sun,
+            theta_sun * theta_sun * theta_sun,
+        );
+        let zenith_x = t * t * (0.00166 * s3 - 0.00375 * s2 + 0.00209 * s)
+            + t * (-0.02903 * s3 + 0.06377 * s2 - 0.03202 * s + 0.00394)
+            + (0.11693 * s3 - 0.21196 * s2 + 0.06052 * s + 0.25886);
+        let zenith_y = t * t * (0.00275 * s3 - 0.00610 * s2 + 0.00317 * s)
+            + t * (-0.04214 * s3 + 0.08970 * s2 - 0.04153 * s + 0.00516)
+            + (0.15346 * s3 - 0.26756 * s2 + 0.06670 * s + 0.26688);
+
+        let relative = |coefficients| {
+            PreethamSky::perez(theta, gamma, coefficients)
+                / PreethamSky::perez(0.0, theta_sun, coefficients)
+        };
+        let big_y = zenith_luminance * relative(luminance);
+        let x = zenith_x * relative(x_coefficients);
+        let y = zenith_y * relative(y_coefficients);
+
+        // xyY to XYZ to linear sRGB
+        let big_x = x / y * big_y;
+        let big_z = (1.0 - x - y) / y * big_y;
+        let rgb = Vec3::new(
+            3.2406 * big_x - 1.5372 * big_y - 0.4986 * big_z,
+            -0.9689 * big_x + 1.8758 * big_y + 0.0415 * big_z,
+            0.0557 * big_x - 0.2040 * big_y + 1.0570 * big_z,
+        )
+        .max(Vec3::new(0.0, 0.0, 0.0))
+            * self.exposure;
+
+        if gamma < self.sun_radius {
+            rgb + Vec3::new(1.0, 0.95, 0.85) * self.sun_intensity
+        } else {
+            rgb
+        }
+    }
+}
+
+/// Equirectangular (latitude-longitude) image of the surroundings, usually an HDR file.
+pub struct EnvironmentMap {
+    width: usize,
+    height: usize,
+    pixels: Vec<Vec3>,
+    /// Cumulative distribution of each row, `width + 1` entries per row.
+    conditional: Vec<f64>,
+    /// Cumulative distribution over rows, `height + 1` entries.
+    marginal: Vec<f64>,
+}
+
+impl Debug for EnvironmentMap {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        write!(
+            f,
+            "EnvironmentMap {{ width: {}, height: {} }}",
+            self.width, self.height
+        )
+    }
+}
+
+impl EnvironmentMap {
+    /// Fails on images without pixels, which have nothing to sample.
+    pub fn load(path: impl AsRef<Path>) -> image::ImageResult<EnvironmentMap> {
+        let image = image::open(path)?.into_rgb32f();
+        let (width, height) = image.dimensions();
+        if width == 0 || height == 0 {
+            return Err(ImageError::Parameter(ParameterError::from_kind(
+                ParameterErrorKind::DimensionMismatch,
+            )));
+        }
+        let pixels = image
+            .pixels()
+            .map(|p| Vec3::new(p[0] as f64, p[1] as f64, p[2] as f64))
+            .collect();
+        Ok(EnvironmentMap::new(width as usize, height as usize, pixels))
+    }
+
+    /// `pixels` are row major, the first row looking straight up.
+    ///
+    /// Panics if the map is empty or `pixels` doesn't hold `width * height` of them.
+    pub fn new(width: usize, height: usize, pixels: Vec<Vec3>) -> EnvironmentMap {
+        assert!(
+            width > 0 && height > 0,
+            "an environment map needs at least one pixel, got {width}x{height}"
+        );
+        assert_eq!(
+            pixels.len(),
+            width * height,
+            "an environment map of {width}x{height} needs as many pixels"
+        );
+        // each texel weighted by its luminance and by the solid angle it covers
+        let mut conditional = Vec::with_capacity((width + 1) * height);
+        let mut marginal = Vec::with_capacity(height + 1);
+        marginal.push(0.0);
+        for row in 0..height {
+            let sin_theta = (PI * (row as f64 + 0.5) / height as f64).sin();
+            let mut total = 0.0;
+            conditional.push(0.0);
+            for column in 0..width {
+                let p = pixels[row * width + column];
+                total += (0.2126 * p.x + 0.7152 * p.y + 0.0722 * p.z) * sin_theta + 1e-9;
+                conditional.push(total);
+            }
+            marginal.push(marginal[row] + total);
+        }
+        EnvironmentMap {
+            width,
+            height,
+            pixels,
+            conditional,
+            marginal,
+        }
+    }
+
+    fn uv(direction: Vec3) -> (f64, f64) {
+        let direction = direction.normalize();
+        let u = 0.5 + direction.x.atan2(-direction.z) / (2.0 * PI);
+        let v = direction.y.clamp(-1.0, 1.0).acos() / PI;
+        (u, v)
+    }
+
+    fn direction(u: f64, v: f64) -> Vec3 {
+        let phi = (u - 0.5) * 2.0 * PI;
+        let theta = v * PI;
+        Vec3::new(
+            theta.sin() * phi.sin(),
+            theta.cos(),
+            -theta.sin() * phi.cos(),
+        )
+    }
+
+    fn texel(&self, u: f64, v: f64) -> (usize, usize) {
+        let column = ((u * self.width as f64) as usize).min(self.width - 1);
+        let row = ((v * self.height as f64) as usize).min(self.height - 1);
+        (column, row)
+    }
+
+    pub fn sample(&self, direction: Vec3) -> Vec3 {
+        let (u, v) = EnvironmentMap::uv(direction);
+        let (column, row) = self.texel(u, v);
+        self.pixels[row * self.width + column]
+    }
+
+    /// Probability density, per steradian, of [`EnvironmentMap::sample_direction`] picking `direction`.
+    pub fn pdf(&self, direction: Vec3) -> f64 {
+        let (u, v) = EnvironmentMap::uv(direction);
+        let (column, row) = self.texel(u, v);
+        let row_start = row * (self.width + 1);
+        let texel_weight =
+            self.conditional[row_start + column + 1] - self.conditional[row_start + column];
+        let total = self.marginal[self.height];
+        let sin_theta = (PI * v).sin().max(1e-6);
+        // density over the image, then over the sphere
+        let pdf_uv = texel_weight / total * (self.width * self.height) as f64;
+        pdf_uv / (2.0 * PI * PI * sin_theta)
+    }
+
+    /// Picks a direction with a probability proportional to how much light comes from it.
+    /// Returns the direction and its probability density, per steradian.
+    pub fn sample_direction(&self, u1: f64, u2: f64) -> (Vec3, f64) {
+        let total = self.marginal[self.height];
+        let row = (self.marginal.partition_point(|&c| c <= u1 * total) - 1).min(self.height - 1);
+        let row_start = row * (self.width + 1);
+        let row_cdf = &self.conditional[row_start..row_start + self.width + 1];
+        let row_total = row_cdf[self.width];
+        let column = (row_cdf.partition_point(|&c| c <= u2 * row_total) - 1).min(self.width - 1);
+
+        // uniform within the texel
+        let row_fraction =
+            (u1 * total - self.marginal[row]) / (self.marginal[row + 1] - self.marginal[row]);
+        let column_fraction =
+            (u2 * row_total - row_cdf[column]) / (row_cdf[column + 1] - row_cdf[column]);
+        let u = (column as f64 + column_fraction.clamp(0.0, 1.0)) / self.width as f64;
+        let v = (row as f64 + row_fraction.clamp(0.0, 1.0)) / self.height as f64;
+        let direction = EnvironmentMap::direction(u, v);
+        (direction, self.pdf(direction))
+    }
+}
@@ -0,0 +1,187 @@
+//! Floats and vectors of either precision, so that distance functions are written once for
+//! f64 and f32.
+//!
+//! Every operation forwards to the one of [`crate::math`] or [`crate::single`], so generic
+//! code gives to the bit what code written for either type would.
+
+use crate::math::{Quat, Vec3};
+use crate::single::{Quatf, Vec3f};
+use std::fmt::Debug;
+use std::ops::{Add, Div, Mul, MulAssign, Sub};
+
+pub trait Float:
+    Copy
+    + Debug
+    + PartialOrd
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Div<Output = Self>
+    + MulAssign
+{
+    const ZERO: Self;
+    const INFINITY: Self;
+
+    /// The nearest value of this precision.
+    fn from_f64(value: f64) -> Self;
+    fn to_f64(self) -> f64;
+    fn abs(self) -> Self;
+    fn min(self, other: Self) -> Self;
+    fn max(self, other: Self) -> Self;
+    fn sqrt(self) -> Self;
+    fn ln(self) -> Self;
+    fn powf(self, power: Self) -> Self;
+    fn sin(self) -> Self;
+    fn cos(self) -> Self;
+    fn acos(self) -> Self;
+    fn atan2(self, other: Self) -> Self;
+}
+
+/// Vector of three [`Float`]s.
+pub trait Vector:
+    Copy
+    + Debug
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Sub<Self::Float, Output = Self>
+    + Mul<Output = Self>
+    + Mul<Self::Float, Output = Self>
+    + Div<Output = Self>
+    + Div<Self::Float, Output = Self>
+{
+    type Float: Float;
+
+    fn new(x: Self::Float, y: Self::Float, z: Self::Float) -> Self;
+    /// The nearest vector of this precision.
+    fn from_f64(v: Vec3) -> Self;
+    fn to_f64(self) -> Vec3;
+    fn x(&self) -> Self::Float;
+    fn y(&self) -> Self::Float;
+    fn z(&self) -> Self::Float;
+    fn length(&self) -> Self::Float;
+    fn abs(&self) -> Self;
+    fn min(&self, other: Self) -> Self;
+    fn max(&self, other: Self) -> Self;
+    fn max_element(&self) -> Self::Float;
+    /// Rotates the vector by `q` brought to this precision.
+    fn rotate(&self, q: Quat) -> Self;
+}
+
+macro_rules! float {
+    ($float:ty) => {
+        impl Float for $float {
+            const ZERO: $float = 0.0;
+            const INFINITY: $float = <$float>::INFINITY;
+
+            fn from_f64(value: f64) -> $float {
+                value as $float
+            }
+
+            fn to_f64(self) -> f64 {
+                self as f64
+            }
+
+            fn abs(self) -> $float {
+                <$float>::abs(self)
+            }
+
+            fn min(self, other: $float) -> $float {
+                <$float>::min(self, other)
+            }
+
+            fn max(self, other: $float) -> $float {
+                <$float>::max(self, other)
+            }
+
+            fn sqrt(self) -> $float {
+                <$float>::sqrt(self)
+            }
+
+            fn ln(self) -> $float {
+                <$float>::ln(self)
+            }
+
+            fn powf(self, power: $float) -> $float {
+                <$float>::powf(self, power)
+            }
+
+            fn sin(self) -> $float {
+                <$float>::sin(self)
+            }
+
+            fn cos(self) -> $float {
+                <$float>::cos(self)
+            }
+
+            fn acos(self) -> $float {
+                <$float>::acos(self)
+            }
+
+            fn atan2(self, other: $float) -> $float {
+                <$float>::atan2(self, other)
+            }
+        }
+    };
+}
+
+float!(f64);
+float!(f32);
+
+macro_rules! vector {
+    ($vector:ty, $float:ty, $rotate:expr) => {
+        impl Vector for $vector {
+            type Float = $float;
+
+            fn new(x: $float, y: $float, z: $float) -> $vector {
+                <$vector>::new(x, y, z)
+            }
+
+            fn from_f64(v: Vec3) -> $vector {
+                <$vector>::new(v.x as $float, v.y as $float, v.z as $float)
+            }
+
+            fn to_f64(self) -> Vec3 {
+                Vec3::new(self.x as f64, self.y as f64, self.z as f64)
+            }
+
+            fn x(&self) -> $float {
+                self.x
+            }
+
+            fn y(&self) -> $float {
+                self.y
+            }
+
+            fn z(&self) -> $float {
+                self.z
+            }
+
+            fn length(&self) -> $float {
+                <$vector>::length(self)
+            }
+
+            fn abs(&self) -> $vector {
+                <$vector>::abs(self)
+            }
+
+            fn min(&self, other: $vector) -> $vector {
+                <$vector>::min(self, other)
+            }
+
+            fn max(&self, other: $vector) -> $vector {
+                <$vector>::max(self, other)
+            }
+
+            fn max_element(&self) -> $float {
+                <$vector>::max_element(self)
+            }
+
+            fn rotate(&self, q: Quat) -> $vector {
+                $rotate(q, *self)
+            }
+        }
+    };
+}
+
+vector!(Vec3, f64, |q: Quat, v| q.rotate(v));
+vector!(Vec3f, f32, |q: Quat, v| Quatf::from(q).rotate(v));
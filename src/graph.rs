@@ -0,0 +1,314 @@
+//! Finding and editing the parts of a scene's tree: nodes by path, name or tag, the leaves
+//! with their transforms, and moving subtrees around.
+//!
+//! The path of a node lists the index of the child taken at every level down from the root.
+//! Names are the easier way in: the names of a node's named
+//! ancestors followed by its own, joined by `/`, make up its name path, and a query finds the
+//! nodes whose name path ends with it. `"mandelbulb"` matches an object of that name
+//! anywhere, `"props/mandelbulb"` only one whose closest named ancestor is `props`.
+//!
+//! An instance has its shared subtree as only child. The subtree can be looked into through
+//! any of its instances but not edited, as that would change all of them.
+
+use crate::math::Mat4;
+use crate::matte;
+use crate::scene::{ObjectTree, Operation, Scene, TreeNode};
+use crate::shape::Object;
+
+/// An object of the tree, with where it is and how it is placed in the world.
+#[derive(Clone, Debug)]
+pub struct SceneLeaf<'a> {
+    pub object: &'a Object,
+    pub path: Vec<usize>,
+    /// Names of the named nodes from the root down to the object, joined by `/`.
+    pub name_path: String,
+    /// From the object's own space to world space, through all the nodes above it.
+    pub transform: Mat4,
+}
+
+impl TreeNode {
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            TreeNode::Leaf(object) => object.name.as_deref(),
+            TreeNode::Node(tree) => tree.name.as_deref(),
+            TreeNode::Instance(instance) => instance.name.as_deref(),
+        }
+    }
+
+    pub fn set_name(&mut self, name: &str) {
+        let slot = match self {
+            TreeNode::Leaf(object) => &mut object.name,
+            TreeNode::Node(tree) => &mut tree.name,
+            TreeNode::Instance(instance) => &mut instance.name,
+        };
+        *slot = Some(name.to_string());
+    }
+
+    pub fn tags(&self) -> &[String] {
+        match self {
+            TreeNode::Leaf(object) => &object.tags,
+            TreeNode::Node(tree) => &tree.tags,
+            TreeNode::Instance(instance) => &instance.tags,
+        }
+    }
+
+    pub fn add_tag(&mut self, tag: &str) {
+        if self.has_tag(tag) {
+            return;
+        }
+        match self {
+            TreeNode::Leaf(object) => object.tags.push(tag.to_string()),
+            TreeNode::Node(tree) => tree.tags.push(tag.to_string()),
+            TreeNode::Instance(instance) => instance.tags.push(tag.to_string()),
+        }
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags().iter().any(|other| other == tag)
+    }
+
+    /// The children of a node, in path order. Leaves have none, and an instance has its
+    /// shared subtree.
+    pub fn children(&self) -> Vec<&TreeNode> {
+        match self {
+            TreeNode::Leaf(_) => Vec::new(),
+            TreeNode::Node(tree) => tree.children.iter().collect(),
+            TreeNode::Instance(instance) => vec![instance.tree.as_ref()],
+        }
+    }
+
+    /// Shared subtrees can't be changed through their instances.
+    fn child_mut(&mut self, index: usize) -> Option<&mut TreeNode> {
+        match self {
+            TreeNode::Leaf(_) | TreeNode::Instance(_) => None,
+            TreeNode::Node(tree) => tree.children.get_mut(index),
+        }
+    }
+
+    /// The node at `path` below this one.
+    pub fn get(&self, path: &[usize]) -> Option<&TreeNode> {
+        path.iter()
+            .try_fold(self, |node, &index| node.children().get(index).copied())
+    }
+
+    /// [`TreeNode::get`], unless the path goes into an instance.
+    pub fn get_mut(&mut self, path: &[usize]) -> Option<&mut TreeNode> {
+        path.iter()
+            .try_fold(self, |node, &index| node.child_mut(index))
+    }
+
+    /// Path of the first node, depth first, whose name path ends with `query`.
+    pub fn find(&self, query: &str) -> Option<Vec<usize>> {
+        self.find_all(query).into_iter().next()
+    }
+
+    /// Paths of all the nodes whose name path ends with `query`, depth first.
+    pub fn find_all(&self, query: &str) -> Vec<Vec<usize>> {
+        let query: Vec<&str> = query.split('/').filter(|part| !part.is_empty()).collect();
+        let mut found = Vec::new();
+        if query.is_empty() {
+            return found;
+        }
+        walk(self, &mut |node, path, names, _| {
+            if node.name().is_some() && names.ends_with(&query) {
+                found.push(path.to_vec());
+            }
+        });
+        found
+    }
+
+    /// Paths of the nodes with `tag`, depth first.
+    pub fn tagged(&self, tag: &str) -> Vec<Vec<usize>> {
+        let mut found = Vec::new();
+        walk(self, &mut |node, path, _, _| {
+            if node.has_tag(tag) {
+                found.push(path.to_vec());
+            }
+        });
+        found
+    }
+
+    /// Every object below this node, depth first and left to right like
+    /// [`Scene::objects`].
+    pub fn leaves(&self) -> Vec<SceneLeaf<'_>> {
+        let mut leaves = Vec::new();
+        walk(self, &mut |node, path, names, transform| {
+            if let TreeNode::Leaf(object) = node {
+                leaves.push(SceneLeaf {
+                    object,
+                    path: path.to_vec(),
+                    name_path: names.join("/"),
+                    transform: transform * object.matrix(),
+                });
+            }
+        });
+        leaves
+    }
+
+    /// Puts `node` in place of the subtree at `path`, and returns that subtree.
+    pub fn replace(&mut self, path: &[usize], node: TreeNode) -> Option<TreeNode> {
+        self.get_mut(path).map(|old| std::mem::replace(old, node))
+    }
+
+    /// Takes the subtree at `path` out of the tree. A parent left with a single child gives
+    /// its place to it, unless it has a name or tags to be found by. The root can't be
+    /// removed.
+    pub fn remove(&mut self, path: &[usize]) -> Option<TreeNode> {
+        self.remove_at(path).map(|(removed, _)| removed)
+    }
+
+    /// [`TreeNode::remove`], also telling whether the parent gave its place to its last
+    /// child.
+    fn remove_at(&mut self, path: &[usize]) -> Option<(TreeNode, bool)> {
+        let (&index, parent_path) = path.split_last()?;
+        let parent = self.get_mut(parent_path)?;
+        let TreeNode::Node(tree) = parent else {
+            return None;
+        };
+        if index >= tree.children.len() {
+            return None;
+        }
+        let removed = tree.children.remove(index);
+        let collapse = tree.children.len() == 1 && tree.name.is_none() && tree.tags.is_empty();
+        if collapse {
+            let child = tree.children.pop().expect("one child is left");
+            *parent = child;
+        }
+        Some((removed, collapse))
+    }
+
+    /// Moves the subtree at `from` next to the node at `to`: the two become the children of
+    /// a new node combining them with `operation`, in the place the node at `to` had. Both
+    /// paths are in the tree as it is before the move. Returns whether the subtree moved,
+    /// which it can't from the root or into itself.
+    pub fn reparent(&mut self, from: &[usize], to: &[usize], operation: Operation) -> bool {
+        if to.starts_with(from) || self.get(to).is_none() {
+            return false;
+        }
+        let Some((subtree, collapsed)) = self.remove_at(from) else {
+            return false;
+        };
+        // paths through the old parent of the subtree lost a child, or the parent itself
+        let (&index, parent) = from.split_last().expect("the root can't be removed");
+        let to: Vec<usize> = match to.get(parent.len()) {
+            Some(&sibling) if to.starts_with(parent) => {
+                let below = &to[parent.len() + 1..];
+                let sibling = if collapsed {
+                    None
+                } else if sibling > index {
+                    Some(sibling - 1)
+                } else {
+                    Some(sibling)
+                };
+                parent
+                    .iter()
+                    .copied()
+                    .chain(sibling)
+                    .chain(below.iter().copied())
+                    .collect()
+            }
+            _ => to.to_vec(),
+        };
+        let target = self
+            .get_mut(&to)
+            .expect("the target is outside the subtree that moved");
+        let sibling = take(target);
+        *target = TreeNode::Node(ObjectTree::new(operation, vec![sibling, subtree]));
+        true
+    }
+}
+
+impl Scene {
+    /// The first object whose name path ends with `query`, see [`TreeNode::find`].
+    pub fn object(&self, query: &str) -> Option<&Object> {
+        self.scene
+            .find_all(query)
+            .into_iter()
+            .find_map(|path| match self.scene.get(&path) {
+                Some(TreeNode::Leaf(object)) => Some(object),
+                _ => None,
+            })
+    }
+
+    /// [`Scene::object`], leaving out the objects inside instances.
+    pub fn object_mut(&mut self, query: &str) -> Option<&mut Object> {
+        let path = self
+            .scene
+            .find_all(query)
+            .into_iter()
+            .find(|path| matches!(self.scene.get_mut(path), Some(TreeNode::Leaf(_))))?;
+        match self.scene.get_mut(&path) {
+            Some(TreeNode::Leaf(object)) => Some(object),
+            _ => None,
+        }
+    }
+
+    /// Objects that have `tag` themselves, in the order of [`Scene::objects`].
+    pub fn tagged(&self, tag: &str) -> Vec<&Object> {
+        self.objects()
+            .into_iter()
+            .filter(|object| object.has_tag(tag))
+            .collect()
+    }
+
+    /// See [`TreeNode::leaves`].
+    pub fn leaves(&self) -> Vec<SceneLeaf<'_>> {
+        self.scene.leaves()
+    }
+
+    /// Name paths of the objects, by id, for the mattes of a render. Objects without a name
+    /// of their own go by their id.
+    pub fn object_names(&self) -> Vec<String> {
+        self.leaves()
+            .into_iter()
+            .enumerate()
+            .map(|(id, leaf)| match leaf.object.name {
+                Some(_) => leaf.name_path,
+                None => matte::object_name(id as u32),
+            })
+            .collect()
+    }
+}
+
+/// Calls `visit` on every node depth first, with its path, its name path and the transform
+/// its parent places it in.
+fn walk<'a>(node: &'a TreeNode, visit: &mut impl FnMut(&'a TreeNode, &[usize], &[&'a str], Mat4)) {
+    fn recurse<'a>(
+        node: &'a TreeNode,
+        path: &mut Vec<usize>,
+        names: &mut Vec<&'a str>,
+        transform: Mat4,
+        visit: &mut impl FnMut(&'a TreeNode, &[usize], &[&'a str], Mat4),
+    ) {
+        let named = node.name().map(|name| names.push(name)).is_some();
+        visit(node, path, names, transform);
+        // nodes combine their children where they are, instances move them
+        let inner = match node {
+            TreeNode::Instance(instance) => transform * instance.matrix(),
+            _ => transform,
+        };
+        for (index, child) in node.children().into_iter().enumerate() {
+            path.push(index);
+            recurse(child, path, names, inner, visit);
+            path.pop();
+        }
+        if named {
+            names.pop();
+        }
+    }
+    recurse(
+        node,
+        &mut Vec::new(),
+        &mut Vec::new(),
+        Mat4::identity(),
+        visit,
+    );
+}
+
+/// Moves a node out of the tree, leaving an empty node in its place.
+fn take(node: &mut TreeNode) -> TreeNode {
+    std::mem::replace(
+        node,
+        TreeNode::Node(ObjectTree::new(Operation::Union, Vec::new())),
+    )
+}
@@ -0,0 +1,301 @@
+//! Interval arithmetic: bounds on what a distance can be anywhere inside a box, to cull
+//! empty space with certainty and find surfaces by subdividing space.
+
+use crate::math::{Quat, Vec3};
+
+/// Closed range of values, possibly unbounded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Interval {
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Interval {
+    pub fn new(min: f64, max: f64) -> Interval {
+        Interval { min, max }
+    }
+
+    pub fn point(value: f64) -> Interval {
+        Interval::new(value, value)
+    }
+
+    /// Every value, for what can't be bounded.
+    pub fn entire() -> Interval {
+        Interval::new(f64::NEG_INFINITY, f64::INFINITY)
+    }
+
+    pub fn contains(&self, value: f64) -> bool {
+        self.min <= value && value <= self.max
+    }
+
+    /// Grows the interval on both sides, to absorb rounding errors.
+    pub fn widen(&self, amount: f64) -> Interval {
+        Interval::new(self.min - amount, self.max + amount)
+    }
+
+    pub fn abs(&self) -> Interval {
+        if self.min >= 0.0 {
+            *self
+        } else if self.max <= 0.0 {
+            -*self
+        } else {
+            Interval::new(0.0, (-self.min).max(self.max))
+        }
+    }
+
+    pub fn square(&self) -> Interval {
+        let abs = self.abs();
+        Interval::new(abs.min * abs.min, abs.max * abs.max)
+    }
+
+    pub fn sqrt(&self) -> Interval {
+        Interval::new(self.min.max(0.0).sqrt(), self.max.max(0.0).sqrt())
+    }
+
+    pub fn min(&self, other: Interval) -> Interval {
+        Interval::new(self.min.min(other.min), self.max.min(other.max))
+    }
+
+    pub fn max(&self, other: Interval) -> Interval {
+        Interval::new(self.min.max(other.min), self.max.max(other.max))
+    }
+}
+
+impl std::ops::Add for Interval {
+    type Output = Interval;
+
+    fn add(self, other: Interval) -> Interval {
+        Interval::new(self.min + other.min, self.max + other.max)
+    }
+}
+
+impl std::ops::Sub for Interval {
+    type Output = Interval;
+
+    fn sub(self, other: Interval) -> Interval {
+        Interval::new(self.min - other.max, self.max - other.min)
+    }
+}
+
+impl std::ops::Mul for Interval {
+    type Output = Interval;
+
+    fn mul(self, other: Interval) -> Interval {
+        let products = [
+            self.min * other.min,
+            self.min * other.max,
+            self.max * other.min,
+            self.max * other.max,
+        ];
+        Interval::new(
+            products.into_iter().fold(f64::INFINITY, f64::min),
+            products.into_iter().fold(f64::NEG_INFINITY, f64::max),
+        )
+    }
+}
+
+impl std::ops::Mul<f64> for Interval {
+    type Output = Interval;
+
+    fn mul(self, scalar: f64) -> Interval {
+        self * Interval::point(scalar)
+    }
+}
+
+impl std::ops::Div<f64> for Interval {
+    type Output = Interval;
+
+    fn div(self, scalar: f64) -> Interval {
+        self * Interval::point(1.0 / scalar)
+    }
+}
+
+impl std::ops::Neg for Interval {
+    type Output = Interval;
+
+    fn neg(self) -> Interval {
+        Interval::new(-self.max, -self.min)
+    }
+}
+
+/// Axis-aligned box.
+#[derive(Clone, Copy, Debug)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub fn new(min: Vec3, max: Vec3) -> Aabb {
+        Aabb { min, max }
+    }
+
+    pub fn centre(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn size(&self) -> Vec3 {
+        self.max - self.min
+    }
+
+    pub fn contains(&self, p: Vec3) -> bool {
+        (self.min.x..=self.max.x).contains(&p.x)
+            && (self.min.y..=self.max.y).contains(&p.y)
+            && (self.min.z..=self.max.z).contains(&p.z)
+    }
+
+    /// Smallest box holding both.
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb::new(self.min.min(other.min), self.max.max(other.max))
+    }
+
+    /// Euclidean distance from a point outside the box, zero inside.
+    pub fn distance(&self, p: Vec3) -> f64 {
+        (self.min - p)
+            .max(p - self.max)
+            .max(Vec3::new(0.0, 0.0, 0.0))
+            .length()
+    }
+
+    /// Box around the corners of this one once scaled, rotated and moved.
+    pub fn transformed(&self, position: Vec3, rotation: Quat, scale: Vec3) -> Aabb {
+        let corners: [Vec3; 8] = std::array::from_fn(|i| {
+            let pick = |bit: usize, low: f64, high: f64| if i & bit == 0 { low } else { high };
+            let corner = Vec3::new(
+                pick(1, self.min.x, self.max.x),
+                pick(2, self.min.y, self.max.y),
+                pick(4, self.min.z, self.max.z),
+            );
+            rotation.rotate(corner * scale) + position
+        });
+        let first = Aabb::new(corners[0], corners[0]);
+        corners[1..].iter().fold(first, |bounds, &corner| {
+            bounds.union(&Aabb::new(corner, corner))
+        })
+    }
+
+    /// The eight boxes of an octree subdivision.
+    pub fn octants(&self) -> [Aabb; 8] {
+        let centre = self.centre();
+        std::array::from_fn(|i| {
+            let pick = |bit: usize, low: f64, mid: f64, high: f64| {
+                if i & bit == 0 {
+                    (low, mid)
+                } else {
+                    (mid, high)
+                }
+            };
+            let (x0, x1) = pick(1, self.min.x, centre.x, self.max.x);
+            let (y0, y1) = pick(2, self.min.y, centre.y, self.max.y);
+            let (z0, z1) = pick(4, self.min.z, centre.z, self.max.z);
+            Aabb::new(Vec3::new(x0, y0, z0), Vec3::new(x1, y1, z1))
+        })
+    }
+
+    pub fn intervals(&self) -> IntervalVec3 {
+        IntervalVec3 {
+            x: Interval::new(self.min.x, self.max.x),
+            y: Interval::new(self.min.y, self.max.y),
+            z: Interval::new(self.min.z, self.max.z),
+        }
+    }
+}
+
+/// A box as one interval per coordinate.
+#[derive(Clone, Copy, Debug)]
+pub struct IntervalVec3 {
+    pub x: Interval,
+    pub y: Interval,
+    pub z: Interval,
+}
+
+impl IntervalVec3 {
+    pub fn constant(v: Vec3) -> IntervalVec3 {
+        IntervalVec3 {
+            x: Interval::point(v.x),
+            y: Interval::point(v.y),
+            z: Interval::point(v.z),
+        }
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        Aabb::new(
+            Vec3::new(self.x.min, self.y.min, self.z.min),
+            Vec3::new(self.x.max, self.y.max, self.z.max),
+        )
+    }
+
+    pub fn length(&self) -> Interval {
+        (self.x.square() + self.y.square() + self.z.square()).sqrt()
+    }
+
+    pub fn abs(&self) -> IntervalVec3 {
+        IntervalVec3 {
+            x: self.x.abs(),
+            y: self.y.abs(),
+            z: self.z.abs(),
+        }
+    }
+
+    pub fn max(&self, other: IntervalVec3) -> IntervalVec3 {
+        IntervalVec3 {
+            x: self.x.max(other.x),
+            y: self.y.max(other.y),
+            z: self.z.max(other.z),
+        }
+    }
+
+    pub fn min(&self, other: IntervalVec3) -> IntervalVec3 {
+        IntervalVec3 {
+            x: self.x.min(other.x),
+            y: self.y.min(other.y),
+            z: self.z.min(other.z),
+        }
+    }
+
+    pub fn max_element(&self) -> Interval {
+        self.x.max(self.y).max(self.z)
+    }
+
+    /// Rotates the box as a linear map, which is tighter than going through
+    /// [`Quat::rotate`] with intervals.
+    pub fn rotate(&self, q: Quat) -> IntervalVec3 {
+        let columns = [
+            q.rotate(Vec3::new(1.0, 0.0, 0.0)),
+            q.rotate(Vec3::new(0.0, 1.0, 0.0)),
+            q.rotate(Vec3::new(0.0, 0.0, 1.0)),
+        ];
+        let row = |pick: fn(Vec3) -> f64| {
+            self.x * pick(columns[0]) + self.y * pick(columns[1]) + self.z * pick(columns[2])
+        };
+        IntervalVec3 {
+            x: row(|v| v.x),
+            y: row(|v| v.y),
+            z: row(|v| v.z),
+        }
+    }
+}
+
+impl std::ops::Sub for IntervalVec3 {
+    type Output = IntervalVec3;
+
+    fn sub(self, other: IntervalVec3) -> IntervalVec3 {
+        IntervalVec3 {
+            x: self.x - other.x,
+            y: self.y - other.y,
+            z: self.z - other.z,
+        }
+    }
+}
+
+impl std::ops::Div<Vec3> for IntervalVec3 {
+    type Output = IntervalVec3;
+
+    fn div(self, other: Vec3) -> IntervalVec3 {
+        IntervalVec3 {
+            x: self.x / other.x,
+            y: self.y / other.y,
+            z: self.z / other.z,
+        }
+    }
+}
@@ -0,0 +1,20 @@
+pub mod animation;
+pub mod bounds;
+pub mod camera;
+pub mod dual;
+pub mod environment;
+pub mod float;
+pub mod graph;
+pub mod interval;
+pub mod math;
+pub mod matte;
+pub mod noise;
+pub mod packet;
+pub mod program;
+pub mod sampling;
+pub mod scene;
+pub mod shape;
+pub mod single;
+pub mod stats;
+pub mod texture;
+pub mod volume;
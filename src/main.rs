@@ -1,60 +1,41 @@
-use std::rc::Rc;
+use std::sync::Arc;
 
-use scene::Scene;
-
-use crate::{
+use surplace::{
+    animation::{Interpolation, Sequence, Timeline},
     math::{Quat, Vec3},
-    scene::{ObjectTree, TreeNode},
+    noise::{Basis, Displacement, Fractal, Noise},
+    sampling::{AntiAliasing, Filter, SamplePattern},
+    scene::{self, ObjectTree, Region, Scene, TreeNode},
     shape::{Object, Shape},
+    texture::{brick, Gradient},
 };
 
-mod camera;
-mod math;
-mod scene;
-mod shape;
-
 fn main() {
     const WIDTH: u32 = 2000;
     const HEIGHT: u32 = 1000;
-    let mut scene = Scene::empty();
-    scene.camera.set_aspect_ratio(WIDTH, HEIGHT);
-    scene.camera.position = Vec3::new(0.0, 0.0, 1.0);
-    let mut object1 = Object::new(
-        Vec3::new(-3.0, 0.0, -4.0),
-        Quat::identity(),
-        Vec3::new(1.0, 1.0, 1.0),
-        Shape::Sphere,
-    );
-    object1.fragment_shader = Rc::new(|point| Vec3::new(1.0, 0.0, 0.0));
-    scene.set_first_object(object1);
-
-    let mut object2 = Object::new(
-        Vec3::new(3.0, 0.0, -4.0),
-        Quat::rot_y(0.5),
-        Vec3::new(1.0, 2.0, 1.0),
-        Shape::Cube,
-    );
-    object2.set_inflate(0.1);
-    object2.fragment_shader = Rc::new(|point| Vec3::new(0.0, 1.0, 0.0));
-    scene.add_object(object2);
-
-    let mut object3 = Object::new(
-        Vec3::new(0.0, 0.0, -4.0),
-        Quat::rot_x(0.5),
-        Vec3::new(1.0, 1.0, 1.0),
-        Shape::Mandelbulb {
-            iterations: 10,
-            power: 8.0,
-        },
-    );
-    object3.fragment_shader =
-        Rc::new(|point| Vec3::new(0.4 * (5.0 * point.x).sin().min(1.0).max(0.0), 0.0, 1.0));
-    object3.set_inflate(0.001);
-    scene.add_object(object3);
-
-    scene.camera.rotate(0.0, 0.0);
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("sequence") {
+        let dir_name = args.get(2).map_or("renders/sequence", String::as_str);
+        render_turntable(dir_name);
+        return;
+    }
+    if args.get(1).map(String::as_str) == Some("tiles") {
+        // tiles <size> <index>...: re-renders tiles of the first scene into renders/output1
+        let size = args.get(2).and_then(|size| size.parse().ok()).unwrap_or(64);
+        let indices: Vec<usize> = args[3.min(args.len())..]
+            .iter()
+            .filter_map(|index| index.parse().ok())
+            .collect();
+        rerender_tiles(WIDTH, HEIGHT, size, &indices, "renders/output1");
+        return;
+    }
+
+    let scene = first_scene(WIDTH, HEIGHT);
     let render = scene.render(WIDTH, HEIGHT);
     render.to_png(WIDTH, HEIGHT, "renders/output1");
+    if let Err(error) = render.to_exr("renders/output1/render.exr") {
+        println!("Couldn't write the cryptomatte EXR: {error}");
+    }
     println!("{scene:?}");
 
     let mut scene = Scene::empty();
@@ -64,7 +45,7 @@ fn main() {
         Vec3::new(1.0, 1.0, 1.0),
         Shape::Sphere,
     );
-    object1.fragment_shader = Rc::new(|point| Vec3::new(1.0, 0.0, 0.0));
+    object1.fragment_shader = Arc::new(|_ctx| Vec3::new(1.0, 0.0, 0.0));
 
     let mut object2 = Object::new(
         Vec3::new(1.0, 0.0, -4.0),
@@ -73,17 +54,16 @@ fn main() {
         Shape::Cube,
     );
     object2.set_inflate(0.1);
-    object2.fragment_shader = Rc::new(|point| Vec3::new(0.0, 1.0, 0.0));
+    object2.fragment_shader = Arc::new(|_ctx| Vec3::new(0.0, 1.0, 0.0));
 
-    scene.scene = TreeNode::Node(ObjectTree {
-        operation: scene::Operation::SmoothUnion(0.5),
-        left: Box::new(TreeNode::Leaf(object1)),
-        right: Box::new(TreeNode::Leaf(object2)),
-    });
+    scene.scene = TreeNode::Node(ObjectTree::new(
+        scene::Operation::SmoothUnion(0.5),
+        vec![TreeNode::Leaf(object1), TreeNode::Leaf(object2)],
+    ));
 
     scene.camera.set_aspect_ratio(WIDTH, HEIGHT);
     scene.camera.position = Vec3::new(0.0, 0.0, 1.0);
-    scene.camera.rotate(0.0, 0.0);
+    scene.camera.look_at(Vec3::new(0.0, 0.0, -4.0));
     let render = scene.render(WIDTH, HEIGHT);
     render.to_png(WIDTH, HEIGHT, "renders/output2");
 
@@ -96,23 +76,22 @@ fn main() {
         Vec3::new(1.0, 1.0, 1.0),
         Shape::Sphere,
     );
-    object1.fragment_shader = Rc::new(|point| Vec3::new(1.0, 0.0, 0.0));
+    object1.fragment_shader = Arc::new(|_ctx| Vec3::new(1.0, 0.0, 0.0));
     let mut object2 = Object::new(
         Vec3::new(1.0, 0.0, -4.0),
         Quat::rot_y(0.5),
         Vec3::new(1.0, 1.0, 1.0),
         Shape::Sphere,
     );
-    object2.fragment_shader = Rc::new(|point| Vec3::new(0.0, 0.0, 1.0));
-    scene.scene = TreeNode::Node(ObjectTree {
-        operation: scene::Operation::SmoothUnion(2.0),
-        left: Box::new(TreeNode::Leaf(object1)),
-        right: Box::new(TreeNode::Leaf(object2)),
-    });
+    object2.fragment_shader = Arc::new(|_ctx| Vec3::new(0.0, 0.0, 1.0));
+    scene.scene = TreeNode::Node(ObjectTree::new(
+        scene::Operation::SmoothUnion(2.0),
+        vec![TreeNode::Leaf(object1), TreeNode::Leaf(object2)],
+    ));
 
     scene.camera.set_aspect_ratio(WIDTH, HEIGHT);
     scene.camera.position = Vec3::new(0.0, 0.0, 1.0);
-    scene.camera.rotate(0.0, 0.0);
+    scene.camera.look_at(Vec3::new(0.0, 0.0, -4.0));
     let render = scene.render(WIDTH, HEIGHT);
     render.to_png(WIDTH, HEIGHT, "renders/output3");
 
@@ -123,7 +102,7 @@ fn main() {
         Vec3::new(1.0, 1.0, 1.0),
         Shape::Sphere,
     );
-    object1.fragment_shader = Rc::new(|point| Vec3::new(1.0, 0.0, 0.0));
+    object1.fragment_shader = Arc::new(|_ctx| Vec3::new(1.0, 0.0, 0.0));
     scene.set_first_object(object1);
 
     let mut object2 = Object::new(
@@ -132,12 +111,142 @@ fn main() {
         Vec3::new(1.0, 1.0, 1.0),
         Shape::Sphere,
     );
-    object2.fragment_shader = Rc::new(|point| Vec3::new(0.0, 0.0, 1.0));
+    object2.fragment_shader = Arc::new(|_ctx| Vec3::new(0.0, 0.0, 1.0));
     scene.add_object(object2);
 
     scene.camera.set_aspect_ratio(WIDTH, HEIGHT);
     scene.camera.position = Vec3::new(0.0, 0.0, 1.0);
-    scene.camera.rotate(0.0, 0.0);
+    scene.camera.look_at(Vec3::new(0.0, 0.0, -4.0));
     let render = scene.render(WIDTH, HEIGHT);
     render.to_png(WIDTH, HEIGHT, "renders/output4");
 }
+
+fn first_scene(width: u32, height: u32) -> Scene {
+    let mut scene = Scene::empty();
+    scene.camera.set_aspect_ratio(width, height);
+    scene.camera.position = Vec3::new(0.0, 0.0, 1.0);
+    let mut object1 = Object::new(
+        Vec3::new(-3.0, 0.0, -4.0),
+        Quat::identity(),
+        Vec3::new(1.0, 1.0, 1.0),
+        Shape::Sphere,
+    );
+    object1.fragment_shader = Arc::new(|_ctx| Vec3::new(1.0, 0.0, 0.0));
+    object1.set_name("sphere");
+    scene.set_first_object(object1);
+
+    let mut object2 = Object::new(
+        Vec3::new(3.0, 0.0, -4.0),
+        Quat::rot_y(0.5),
+        Vec3::new(1.0, 2.0, 1.0),
+        Shape::Cube,
+    );
+    object2.set_inflate(0.1);
+    object2.fragment_shader = Arc::new(|_ctx| Vec3::new(0.0, 1.0, 0.0));
+    object2.set_name("pillar");
+    scene.add_object(object2);
+
+    let mut object3 = Object::new(
+        Vec3::new(0.0, 0.0, -4.0),
+        Quat::rot_x(0.5),
+        Vec3::new(1.0, 1.0, 1.0),
+        Shape::Mandelbulb {
+            iterations: 10,
+            power: 8.0,
+        },
+    );
+    object3.fragment_shader =
+        Arc::new(|ctx| Vec3::new(0.4 * (5.0 * ctx.local.x).sin().clamp(0.0, 1.0), 0.0, 1.0));
+    object3.set_inflate(0.001);
+    object3.set_name("mandelbulb");
+    scene.add_object(object3);
+
+    scene.camera.look_at(Vec3::new(0.0, 0.0, -4.0));
+    scene.settings.anti_aliasing = AntiAliasing::new(SamplePattern::RotatedGrid, 4, Filter::Tent);
+    scene.settings.anti_aliasing.set_adaptive(0.05);
+    scene
+}
+
+/// Re-renders some tiles of the first scene and writes them over a render of the whole
+/// frame, so that a change to one part of the image doesn't need the entire frame again.
+fn rerender_tiles(width: u32, height: u32, size: u32, indices: &[usize], dir_name: &str) {
+    let scene = first_scene(width, height);
+    let tiles = Region::tiles(width, height, size);
+    for &index in indices {
+        let Some(&tile) = tiles.get(index) else {
+            println!("No tile {index}, there are {} of {size}px", tiles.len());
+            continue;
+        };
+        let render = scene.render_cropped(width, height, tile);
+        if let Err(error) = render.patch_png(tile.x, tile.y, dir_name) {
+            println!("Couldn't update {dir_name} with tile {index}: {error}");
+        }
+    }
+}
+
+/// Renders a short turntable of the first scene: the Mandelbulb spins and breathes
+/// while the camera slowly pulls back.
+fn render_turntable(dir_name: &str) {
+    const WIDTH: u32 = 640;
+    const HEIGHT: u32 = 320;
+    let mut scene = first_scene(WIDTH, HEIGHT);
+    // stripes sliding across the Mandelbulb over time
+    let mandelbulb = scene.object_mut("mandelbulb").unwrap();
+    mandelbulb.fragment_shader = Arc::new(|ctx| {
+        let stripes = (5.0 * ctx.local.x + 3.0 * ctx.time).sin();
+        Vec3::new(0.4 * stripes.clamp(0.0, 1.0), 0.0, 1.0)
+    });
+
+    // a lumpy sphere with a marbled surface
+    let noise = Noise::new(7);
+    let sphere = scene.object_mut("sphere").unwrap();
+    sphere.set_displacement(Displacement::new(
+        noise.clone(),
+        Fractal::new(Basis::Perlin, 3),
+        0.08,
+    ));
+    let marble = Fractal::new(Basis::Simplex, 4);
+    sphere.fragment_shader = Arc::new(move |ctx| {
+        let veins = (4.0 * ctx.local.y + 3.0 * marble.fbm(&noise, ctx.local)).sin();
+        Vec3::new(1.0, 0.5 + 0.5 * veins, 0.5 + 0.5 * veins)
+    });
+
+    // a brick pillar that keeps its bricks while spinning
+    let bricks = Gradient::new(vec![
+        (0.0, Vec3::new(0.8, 0.8, 0.75)),
+        (1.0, Vec3::new(0.6, 0.2, 0.1)),
+    ]);
+    scene.object_mut("pillar").unwrap().fragment_shader =
+        Arc::new(move |ctx| bricks.sample(brick(ctx.local, Vec3::new(0.5, 0.25, 0.5), 0.05)));
+
+    let mut timeline = Timeline::new();
+    let mandelbulb = timeline.object(2);
+    for i in 0..=4 {
+        let angle = i as f64 * std::f64::consts::FRAC_PI_2;
+        mandelbulb.rotation.insert(
+            i as f64,
+            Quat::rot_y(angle) * Quat::rot_x(0.5),
+            Interpolation::Linear,
+        );
+    }
+    mandelbulb.power.insert(0.0, 8.0, Interpolation::EaseInOut);
+    mandelbulb.power.insert(2.0, 4.0, Interpolation::EaseInOut);
+    mandelbulb.power.insert(4.0, 8.0, Interpolation::EaseInOut);
+    timeline
+        .camera
+        .position
+        .insert(0.0, Vec3::new(0.0, 0.0, 1.0), Interpolation::EaseInOut);
+    timeline
+        .camera
+        .position
+        .insert(4.0, Vec3::new(0.0, 1.0, 3.0), Interpolation::EaseInOut);
+    timeline
+        .camera
+        .target
+        .insert(0.0, Vec3::new(0.0, 0.0, -4.0), Interpolation::Step);
+
+    let sequence = Sequence::new(0.0, timeline.duration(), 12.0);
+    // 180 degree shutter
+    scene.camera.set_shutter(0.0, 0.5 / sequence.fps);
+    timeline.render_sequence(&scene, &sequence, WIDTH, HEIGHT, dir_name);
+}
@@ -0,0 +1,251 @@
+//! Mattes of objects and materials: which objects cover each pixel and by how much, to
+//! isolate them after rendering.
+//!
+//! Every primary ray records the leaf of the tree it hit, and a pixel keeps the weight of the
+//! samples that hit each one as its [`Coverage`]. It is written out as ID passes and as
+//! cryptomatte layers in EXR, from which compositors pull anti-aliased mattes by name.
+
+use exr::prelude::{AnyChannel, AttributeValue, FlatSamples, Text};
+use std::collections::HashMap;
+
+/// Most objects the coverage of a pixel keeps track of. Cryptomatte layers hold two each.
+pub const RANKS: usize = 4;
+
+/// Leaf of the scene a ray hit.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SurfaceId {
+    /// Index of the object in [`Scene::objects`](crate::scene::Scene::objects).
+    pub object: u32,
+    /// [`Object::material`](crate::shape::Object::material) of the object.
+    pub material: u32,
+}
+
+/// Share of a pixel covered by each object it shows, the largest ones if there are more
+/// than [`RANKS`]. What is left up to 1 is background.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Coverage {
+    entries: [(SurfaceId, f32); RANKS],
+    len: usize,
+}
+
+impl Coverage {
+    /// A whole pixel covered by one object.
+    pub fn single(id: SurfaceId) -> Coverage {
+        let mut coverage = Coverage::default();
+        coverage.add(id, 1.0);
+        coverage
+    }
+
+    /// Adds to the coverage of an object. When all the ranks are taken, the object replaces
+    /// the smallest one if it covers more.
+    pub fn add(&mut self, id: SurfaceId, weight: f32) {
+        if let Some(entry) = self.entries[..self.len]
+            .iter_mut()
+            .find(|(other, _)| *other == id)
+        {
+            entry.1 += weight;
+        } else if self.len < RANKS {
+            self.entries[self.len] = (id, weight);
+            self.len += 1;
+        } else {
+            let smallest = (0..RANKS)
+                .min_by(|&a, &b| self.entries[a].1.total_cmp(&self.entries[b].1))
+                .unwrap_or(0);
+            if weight > self.entries[smallest].1 {
+                self.entries[smallest] = (id, weight);
+            }
+        }
+    }
+
+    pub(crate) fn accumulate(&mut self, other: &Coverage, weight: f64) {
+        for &(id, coverage) in other.entries() {
+            self.add(id, coverage * weight as f32);
+        }
+    }
+
+    /// The coverage times `factor`, within 0 to 1. Objects left with none are dropped.
+    pub(crate) fn scaled(&self, factor: f64) -> Coverage {
+        let mut scaled = Coverage::default();
+        for &(id, coverage) in self.entries() {
+            let coverage = (coverage * factor as f32).min(1.0);
+            if coverage > 0.0 {
+                scaled.add(id, coverage);
+            }
+        }
+        scaled
+    }
+
+    pub fn entries(&self) -> &[(SurfaceId, f32)] {
+        &self.entries[..self.len]
+    }
+
+    /// Objects and their coverage, the largest first.
+    pub fn objects(&self) -> Vec<(u32, f32)> {
+        ranked(
+            self.entries()
+                .iter()
+                .map(|&(id, coverage)| (id.object, coverage)),
+        )
+    }
+
+    /// Materials and the coverage of all their objects together, the largest first.
+    pub fn materials(&self) -> Vec<(u32, f32)> {
+        ranked(
+            self.entries()
+                .iter()
+                .map(|&(id, coverage)| (id.material, coverage)),
+        )
+    }
+
+    /// The object covering most of the pixel, if any.
+    pub fn object(&self) -> Option<u32> {
+        self.objects().first().map(|&(object, _)| object)
+    }
+
+    /// The material covering most of the pixel, if any.
+    pub fn material(&self) -> Option<u32> {
+        self.materials().first().map(|&(material, _)| material)
+    }
+}
+
+/// Sums the coverage of equal ids and sorts them by decreasing coverage.
+fn ranked(entries: impl Iterator<Item = (u32, f32)>) -> Vec<(u32, f32)> {
+    let mut ranked: Vec<(u32, f32)> = Vec::new();
+    for (id, coverage) in entries {
+        match ranked.iter_mut().find(|(other, _)| *other == id) {
+            Some(entry) => entry.1 += coverage,
+            None => ranked.push((id, coverage)),
+        }
+    }
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+    ranked
+}
+
+/// Name of an object in the cryptomatte manifest.
+pub fn object_name(object: u32) -> String {
+    format!("object{}", object)
+}
+
+/// Name of a material in the cryptomatte manifest.
+pub fn material_name(material: u32) -> String {
+    format!("material{}", material)
+}
+
+/// Cryptomatte hash of a name: its MurmurHash3, nudged so that its bits read as a normal
+/// f32 rather than an infinity, NaN or denormal.
+pub fn name_hash(name: &str) -> u32 {
+    let hash = murmur3(name.as_bytes(), 0);
+    let exponent = (hash >> 23) & 0xff;
+    if exponent == 0 || exponent == 0xff {
+        hash ^ (1 << 23)
+    } else {
+        hash
+    }
+}
+
+/// Colour of a name in the ID passes, from the bytes of its hash.
+pub fn name_colour(name: &str) -> image::Rgba<u8> {
+    let [r, g, b, _] = name_hash(name).to_le_bytes();
+    image::Rgba([r, g, b, 255])
+}
+
+/// 32 bit MurmurHash3 for x86, the hash cryptomatte uses for names.
+fn murmur3(key: &[u8], seed: u32) -> u32 {
+    const C1: u32 = 0xcc9e2d51;
+    const C2: u32 = 0x1b873593;
+    let mix = |k: u32| k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+    let mut hash = seed;
+    let mut blocks = key.chunks_exact(4);
+    for block in &mut blocks {
+        hash ^= mix(u32::from_le_bytes([block[0], block[1], block[2], block[3]]));
+        hash = hash
+            .rotate_left(13)
+            .wrapping_mul(5)
+            .wrapping_add(0xe6546b64);
+    }
+    let tail = blocks.remainder();
+    if !tail.is_empty() {
+        let k = tail.iter().rev().fold(0, |k, &byte| (k << 8) | byte as u32);
+        hash ^= mix(k);
+    }
+    hash ^= key.len() as u32;
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x85ebca6b);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0xc2b2ae35);
+    hash ^ (hash >> 16)
+}
+
+/// `text` as a JSON string, quoted and escaped.
+fn json_string(text: &str) -> String {
+    let mut json = String::with_capacity(text.len() + 2);
+    json.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c < ' ' => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// Channels and header attributes of a cryptomatte layer called `layer`, from the ranked
+/// ids of every pixel, row by row, and the names the ids stand for.
+pub(crate) fn cryptomatte_layer(
+    layer: &str,
+    pixels: &[Vec<(u32, f32)>],
+    name: &dyn Fn(u32) -> String,
+) -> (Vec<AnyChannel<FlatSamples>>, HashMap<Text, AttributeValue>) {
+    let mut names = HashMap::new();
+    let mut channels = Vec::new();
+    for level in 0..RANKS.div_ceil(2) {
+        for (i, channel) in ["R", "G", "B", "A"].into_iter().enumerate() {
+            // ids in R and B, their coverage in G and A
+            let rank = 2 * level + i / 2;
+            let samples = pixels
+                .iter()
+                .map(|ranked| match ranked.get(rank) {
+                    Some(&(id, _)) if i % 2 == 0 => {
+                        let hash = *names.entry(id).or_insert_with(|| name_hash(&name(id)));
+                        f32::from_bits(hash)
+                    }
+                    Some(&(_, coverage)) => coverage,
+                    None => 0.0,
+                })
+                .collect();
+            channels.push(AnyChannel::new(
+                format!("{}{:02}.{}", layer, level, channel).as_str(),
+                FlatSamples::F32(samples),
+            ));
+        }
+    }
+
+    let mut manifest: Vec<String> = names
+        .iter()
+        .map(|(&id, hash)| format!("{}:\"{:08x}\"", json_string(&name(id)), hash))
+        .collect();
+    manifest.sort();
+    let key = format!("{:08x}", murmur3(layer.as_bytes(), 0));
+    let prefix = format!("cryptomatte/{}", &key[..7]);
+    let attributes = [
+        ("name", layer.to_string()),
+        ("hash", "MurmurHash3_32".to_string()),
+        ("conversion", "uint32_to_float32".to_string()),
+        ("manifest", format!("{{{}}}", manifest.join(","))),
+    ]
+    .into_iter()
+    .map(|(field, value)| {
+        (
+            Text::from(format!("{}/{}", prefix, field).as_str()),
+            AttributeValue::Text(Text::from(value.as_str())),
+        )
+    })
+    .collect();
+    (channels, attributes)
+}
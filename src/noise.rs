@@ -0,0 +1,369 @@
+//! Procedural 3D noise for shaders and SDF displacement.
+//!
+//! Everything is deterministic for a given seed. Each basis comes with an upper bound of its
+//! Lipschitz constant (how fast it can change per unit of distance), so displaced distance
+//! fields can be scaled back down to stay safe to sphere trace.
+
+use crate::math::Vec3;
+use crate::sampling::Rng;
+use std::fmt::Debug;
+use std::fmt::Formatter;
+
+#[derive(Clone)]
+pub struct Noise {
+    seed: u64,
+    permutation: Vec<u8>,
+}
+
+impl Debug for Noise {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        write!(f, "Noise {{ seed: {} }}", self.seed)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Basis {
+    Perlin,
+    Simplex,
+    Value,
+    /// Distance to the closest feature point.
+    Worley,
+}
+
+impl Basis {
+    /// Upper bound of the Lipschitz constant of the basis at frequency 1.
+    /// The Perlin and simplex bounds were measured numerically, with some margin.
+    pub fn lipschitz(&self) -> f64 {
+        match self {
+            Basis::Perlin => 4.0,
+            Basis::Simplex => 8.0,
+            Basis::Value => 6.5,
+            Basis::Worley => 1.0,
+        }
+    }
+}
+
+/// Closest and second closest feature point distances of Worley noise.
+#[derive(Clone, Copy, Debug)]
+pub struct Cellular {
+    pub f1: f64,
+    pub f2: f64,
+}
+
+impl Noise {
+    pub fn new(seed: u64) -> Noise {
+        let mut rng = Rng::new(seed);
+        let mut permutation: Vec<u8> = (0..=255).collect();
+        for i in (1..256).rev() {
+            let j = (rng.next_u64() % (i as u64 + 1)) as usize;
+            permutation.swap(i, j);
+        }
+        // doubled so lookups never need to wrap
+        permutation.extend_from_within(..);
+        Noise { seed, permutation }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    fn hash(&self, x: i64, y: i64, z: i64) -> usize {
+        let p = &self.permutation;
+        let x = (x & 255) as usize;
+        let y = (y & 255) as usize;
+        let z = (z & 255) as usize;
+        p[p[p[x] as usize + y] as usize + z] as usize
+    }
+
+    pub fn sample(&self, basis: Basis, p: Vec3) -> f64 {
+        match basis {
+            Basis::Perlin => self.perlin(p),
+            Basis::Simplex => self.simplex(p),
+            Basis::Value => self.value(p),
+            Basis::Worley => self.worley(p).f1,
+        }
+    }
+
+    /// Improved Perlin gradient noise, roughly in [-1, 1].
+    pub fn perlin(&self, p: Vec3) -> f64 {
+        let (xi, yi, zi) = (p.x.floor(), p.y.floor(), p.z.floor());
+        let (x, y, z) = (p.x - xi, p.y - yi, p.z - zi);
+        let (xi, yi, zi) = (xi as i64, yi as i64, zi as i64);
+        let (u, v, w) = (fade(x), fade(y), fade(z));
+
+        let corner = |dx: i64, dy: i64, dz: i64| {
+            gradient(
+                self.hash(xi + dx, yi + dy, zi + dz),
+                x - dx as f64,
+                y - dy as f64,
+                z - dz as f64,
+            )
+        };
+        lerp(
+            w,
+            lerp(
+                v,
+                lerp(u, corner(0, 0, 0), corner(1, 0, 0)),
+                lerp(u, corner(0, 1, 0), corner(1, 1, 0)),
+            ),
+            lerp(
+                v,
+                lerp(u, corner(0, 0, 1), corner(1, 0, 1)),
+                lerp(u, corner(0, 1, 1), corner(1, 1, 1)),
+            ),
+        )
+    }
+
+    /// Simplex noise, roughly in [-1, 1].
+    pub fn simplex(&self, p: Vec3) -> f64 {
+        const F3: f64 = 1.0 / 3.0;
+        const G3: f64 = 1.0 / 6.0;
+
+        // skew into the simplex grid to find the containing cell
+        let s = (p.x + p.y + p.z) * F3;
+        let (i, j, k) = ((p.x + s).floor(), (p.y + s).floor(), (p.z + s).floor());
+        let t = (i + j + k) * G3;
+        let x0 = p.x - (i - t);
+        let y0 = p.y - (j - t);
+        let z0 = p.z - (k - t);
+
+        // which of the six tetrahedra of the cube we are in
+        let (i1, j1, k1, i2, j2, k2) = if x0 >= y0 {
+            if y0 >= z0 {
+                (1, 0, 0, 1, 1, 0)
+            } else if x0 >= z0 {
+                (1, 0, 0, 1, 0, 1)
+            } else {
+                (0, 0, 1, 1, 0, 1)
+            }
+        } else if y0 < z0 {
+            (0, 0, 1, 0, 1, 1)
+        } else if x0 < z0 {
+            (0, 1, 0, 0, 1, 1)
+        } else {
+            (0, 1, 0, 1, 1, 0)
+        };
+
+        let (i, j, k) = (i as i64, j as i64, k as i64);
+        let corners = [
+            (0, 0, 0, 0.0),
+            (i1, j1, k1, G3),
+            (i2, j2, k2, 2.0 * G3),
+            (1, 1, 1, 3.0 * G3),
+        ];
+        let mut total = 0.0;
+        for (di, dj, dk, offset) in corners {
+            let x = x0 - di as f64 + offset;
+            let y = y0 - dj as f64 + offset;
+            let z = z0 - dk as f64 + offset;
+            // a radius of 0.5 keeps each kernel inside the neighbouring simplices, so the
+            // noise stays continuous
+            let falloff = 0.5 - x * x - y * y - z * z;
+            if falloff > 0.0 {
+                let falloff = falloff * falloff;
+                total += falloff * falloff * gradient(self.hash(i + di, j + dj, k + dk), x, y, z);
+            }
+        }
+        SIMPLEX_SCALE * total
+    }
+
+    /// Smoothly interpolated random lattice values, in [-1, 1].
+    pub fn value(&self, p: Vec3) -> f64 {
+        let (xi, yi, zi) = (p.x.floor(), p.y.floor(), p.z.floor());
+        let (u, v, w) = (fade(p.x - xi), fade(p.y - yi), fade(p.z - zi));
+        let (xi, yi, zi) = (xi as i64, yi as i64, zi as i64);
+
+        let corner =
+            |dx: i64, dy: i64, dz: i64| self.hash(xi + dx, yi + dy, zi + dz) as f64 / 127.5 - 1.0;
+        lerp(
+            w,
+            lerp(
+                v,
+                lerp(u, corner(0, 0, 0), corner(1, 0, 0)),
+                lerp(u, corner(0, 1, 0), corner(1, 1, 0)),
+            ),
+            lerp(
+                v,
+                lerp(u, corner(0, 0, 1), corner(1, 0, 1)),
+                lerp(u, corner(0, 1, 1), corner(1, 1, 1)),
+            ),
+        )
+    }
+
+    /// Worley (cellular) noise with one feature point per unit cell.
+    pub fn worley(&self, p: Vec3) -> Cellular {
+        let (xi, yi, zi) = (p.x.floor() as i64, p.y.floor() as i64, p.z.floor() as i64);
+        let mut f1 = f64::INFINITY;
+        let mut f2 = f64::INFINITY;
+        // the direct neighbours first, then the cells two away that could still be closer
+        for ring in 1..=2i64 {
+            for dz in -ring..=ring {
+                for dy in -ring..=ring {
+                    for dx in -ring..=ring {
+                        if ring == 2 && dx.abs() < 2 && dy.abs() < 2 && dz.abs() < 2 {
+                            continue;
+                        }
+                        let (cx, cy, cz) = (xi + dx, yi + dy, zi + dz);
+                        let cell_min = Vec3::new(cx as f64, cy as f64, cz as f64);
+                        let closest = p.max(cell_min).min(cell_min + 1.0);
+                        if closest.distance(p) >= f2 {
+                            continue;
+                        }
+                        let h = self.hash(cx, cy, cz);
+                        let feature = Vec3::new(
+                            cx as f64 + self.permutation[h] as f64 / 256.0,
+                            cy as f64 + self.permutation[h + 1] as f64 / 256.0,
+                            cz as f64 + self.permutation[h + 2] as f64 / 256.0,
+                        );
+                        let distance = feature.distance(p);
+                        if distance < f1 {
+                            f2 = f1;
+                            f1 = distance;
+                        } else if distance < f2 {
+                            f2 = distance;
+                        }
+                    }
+                }
+            }
+        }
+        Cellular { f1, f2 }
+    }
+
+    /// Divergence-free noise field, the curl of three decorrelated Perlin potentials.
+    pub fn curl(&self, p: Vec3) -> Vec3 {
+        const EPS: f64 = 1e-4;
+        let potential = |p: Vec3| {
+            Vec3::new(
+                self.perlin(p),
+                self.perlin(p + Vec3::new(31.416, -47.853, 12.793)),
+                self.perlin(p + Vec3::new(-73.264, 19.189, 101.937)),
+            )
+        };
+        let dx = (potential(p + Vec3::new(EPS, 0.0, 0.0))
+            - potential(p - Vec3::new(EPS, 0.0, 0.0)))
+            / (2.0 * EPS);
+        let dy = (potential(p + Vec3::new(0.0, EPS, 0.0))
+            - potential(p - Vec3::new(0.0, EPS, 0.0)))
+            / (2.0 * EPS);
+        let dz = (potential(p + Vec3::new(0.0, 0.0, EPS))
+            - potential(p - Vec3::new(0.0, 0.0, EPS)))
+            / (2.0 * EPS);
+        Vec3::new(dy.z - dz.y, dz.x - dx.z, dx.y - dy.x)
+    }
+}
+
+/// Sum of octaves of a basis, each `lacunarity` times higher in frequency and `gain` times
+/// lower in amplitude than the previous one.
+#[derive(Clone, Copy, Debug)]
+pub struct Fractal {
+    pub basis: Basis,
+    pub octaves: u32,
+    pub frequency: f64,
+    pub lacunarity: f64,
+    pub gain: f64,
+}
+
+impl Fractal {
+    pub fn new(basis: Basis, octaves: u32) -> Fractal {
+        Fractal {
+            basis,
+            octaves,
+            frequency: 1.0,
+            lacunarity: 2.0,
+            gain: 0.5,
+        }
+    }
+
+    fn octaves(&self, noise: &Noise, p: Vec3, shape: impl Fn(f64) -> f64) -> f64 {
+        let mut total = 0.0;
+        let mut frequency = self.frequency;
+        let mut amplitude = 1.0;
+        for octave in 0..self.octaves {
+            // offset octaves so their lattices don't line up at the origin
+            let offset = Vec3::new(1.7, 9.2, 5.3) * octave as f64;
+            total += amplitude * shape(noise.sample(self.basis, p * frequency + offset));
+            frequency *= self.lacunarity;
+            amplitude *= self.gain;
+        }
+        total
+    }
+
+    /// Fractional Brownian motion.
+    pub fn fbm(&self, noise: &Noise, p: Vec3) -> f64 {
+        self.octaves(noise, p, |n| n)
+    }
+
+    /// Sharp creases where the basis crosses zero, like mountain ridges.
+    pub fn ridged(&self, noise: &Noise, p: Vec3) -> f64 {
+        self.octaves(noise, p, |n| 1.0 - n.abs())
+    }
+
+    pub fn turbulence(&self, noise: &Noise, p: Vec3) -> f64 {
+        self.octaves(noise, p, f64::abs)
+    }
+
+    /// Bound shared by fbm, ridged and turbulence, since neither `1 - |n|` nor `|n|`
+    /// change faster than `n`.
+    pub fn lipschitz(&self) -> f64 {
+        let mut total = 0.0;
+        let mut frequency = self.frequency;
+        let mut amplitude = 1.0;
+        for _ in 0..self.octaves {
+            total += amplitude * frequency * self.basis.lipschitz();
+            frequency *= self.lacunarity;
+            amplitude *= self.gain;
+        }
+        total
+    }
+}
+
+/// Offsets an object's surface by fractal noise evaluated in its local space.
+#[derive(Clone, Debug)]
+pub struct Displacement {
+    pub noise: Noise,
+    pub fractal: Fractal,
+    pub amplitude: f64,
+}
+
+impl Displacement {
+    pub fn new(noise: Noise, fractal: Fractal, amplitude: f64) -> Displacement {
+        Displacement {
+            noise,
+            fractal,
+            amplitude,
+        }
+    }
+
+    pub fn offset(&self, local: Vec3) -> f64 {
+        self.amplitude * self.fractal.fbm(&self.noise, local)
+    }
+
+    pub fn lipschitz(&self) -> f64 {
+        self.amplitude.abs() * self.fractal.lipschitz()
+    }
+}
+
+/// Brings simplex noise back to roughly [-1, 1].
+const SIMPLEX_SCALE: f64 = 72.0;
+
+fn fade(t: f64) -> f64 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(t: f64, a: f64, b: f64) -> f64 {
+    a + t * (b - a)
+}
+
+/// Dot product with one of the 12 cube edge directions picked by the hash.
+fn gradient(hash: usize, x: f64, y: f64, z: f64) -> f64 {
+    let h = hash & 15;
+    let u = if h < 8 { x } else { y };
+    let v = if h < 4 {
+        y
+    } else if h == 12 || h == 14 {
+        x
+    } else {
+        z
+    };
+    (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+}
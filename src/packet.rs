@@ -0,0 +1,218 @@
+//! Several values processed at once, for evaluating distances along packets of rays.
+//!
+//! Lanes are plain arrays of f64 or f32 that the compiler vectorises. Every operation is
+//! done lane by lane in the same order as its scalar counterpart in [`crate::math`] or
+//! [`crate::single`], so each lane gives exactly the scalar result.
+
+use crate::float::{Float, Vector};
+use crate::math::{Quat, Vec3};
+use crate::single::Vec3f;
+
+/// Number of values in a packet, one 2x2 block of pixels.
+pub const LANES: usize = 4;
+
+/// Which lanes of a packet are still in use.
+pub type Mask = [bool; LANES];
+
+/// One value per lane, f64 or f32.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Lanes<F>(pub [F; LANES]);
+
+pub type F64x4 = Lanes<f64>;
+pub type F32x4 = Lanes<f32>;
+
+impl<F: Float> Lanes<F> {
+    pub fn splat(value: F) -> Lanes<F> {
+        Lanes([value; LANES])
+    }
+
+    pub fn map(self, f: impl Fn(F) -> F) -> Lanes<F> {
+        Lanes(self.0.map(f))
+    }
+
+    fn zip(self, other: Lanes<F>, f: impl Fn(F, F) -> F) -> Lanes<F> {
+        let mut lanes = self.0;
+        for (lane, other) in lanes.iter_mut().zip(other.0) {
+            *lane = f(*lane, other);
+        }
+        Lanes(lanes)
+    }
+
+    pub fn min(self, other: Lanes<F>) -> Lanes<F> {
+        self.zip(other, F::min)
+    }
+
+    pub fn max(self, other: Lanes<F>) -> Lanes<F> {
+        self.zip(other, F::max)
+    }
+
+    pub fn abs(self) -> Lanes<F> {
+        self.map(F::abs)
+    }
+
+    pub fn sqrt(self) -> Lanes<F> {
+        self.map(F::sqrt)
+    }
+}
+
+impl<F: Float> std::ops::Add for Lanes<F> {
+    type Output = Lanes<F>;
+
+    fn add(self, other: Lanes<F>) -> Lanes<F> {
+        self.zip(other, |a, b| a + b)
+    }
+}
+
+impl<F: Float> std::ops::Sub for Lanes<F> {
+    type Output = Lanes<F>;
+
+    fn sub(self, other: Lanes<F>) -> Lanes<F> {
+        self.zip(other, |a, b| a - b)
+    }
+}
+
+impl<F: Float> std::ops::Mul for Lanes<F> {
+    type Output = Lanes<F>;
+
+    fn mul(self, other: Lanes<F>) -> Lanes<F> {
+        self.zip(other, |a, b| a * b)
+    }
+}
+
+impl<F: Float> std::ops::Div for Lanes<F> {
+    type Output = Lanes<F>;
+
+    fn div(self, other: Lanes<F>) -> Lanes<F> {
+        self.zip(other, |a, b| a / b)
+    }
+}
+
+/// One vector per lane, stored as a structure of arrays.
+#[derive(Clone, Copy, Debug)]
+pub struct Vec3x4<V: Vector = Vec3> {
+    pub x: Lanes<V::Float>,
+    pub y: Lanes<V::Float>,
+    pub z: Lanes<V::Float>,
+}
+
+pub type Vec3fx4 = Vec3x4<Vec3f>;
+
+impl<V: Vector> Vec3x4<V> {
+    pub fn splat(v: V) -> Vec3x4<V> {
+        Vec3x4 {
+            x: Lanes::splat(v.x()),
+            y: Lanes::splat(v.y()),
+            z: Lanes::splat(v.z()),
+        }
+    }
+
+    pub fn from_lanes(lanes: [V; LANES]) -> Vec3x4<V> {
+        Vec3x4 {
+            x: Lanes(lanes.map(|v| v.x())),
+            y: Lanes(lanes.map(|v| v.y())),
+            z: Lanes(lanes.map(|v| v.z())),
+        }
+    }
+
+    pub fn lane(&self, i: usize) -> V {
+        V::new(self.x.0[i], self.y.0[i], self.z.0[i])
+    }
+
+    pub fn cross(&self, other: Vec3x4<V>) -> Vec3x4<V> {
+        Vec3x4 {
+            x: self.y * other.z - self.z * other.y,
+            y: self.z * other.x - self.x * other.z,
+            z: self.x * other.y - self.y * other.x,
+        }
+    }
+
+    pub fn length(&self) -> Lanes<V::Float> {
+        (self.x * self.x + self.y * self.y + self.z * self.z).sqrt()
+    }
+
+    pub fn abs(&self) -> Vec3x4<V> {
+        Vec3x4 {
+            x: self.x.abs(),
+            y: self.y.abs(),
+            z: self.z.abs(),
+        }
+    }
+
+    pub fn max(&self, other: Vec3x4<V>) -> Vec3x4<V> {
+        Vec3x4 {
+            x: self.x.max(other.x),
+            y: self.y.max(other.y),
+            z: self.z.max(other.z),
+        }
+    }
+
+    pub fn min(&self, other: Vec3x4<V>) -> Vec3x4<V> {
+        Vec3x4 {
+            x: self.x.min(other.x),
+            y: self.y.min(other.y),
+            z: self.z.min(other.z),
+        }
+    }
+
+    pub fn max_element(&self) -> Lanes<V::Float> {
+        self.x.max(self.y).max(self.z)
+    }
+
+    /// Rotates every lane by the same quaternion, like [`Quat::rotate`] in the precision of
+    /// the lanes.
+    pub fn rotate(&self, q: Quat) -> Vec3x4<V> {
+        let float = |value| Lanes::splat(V::Float::from_f64(value));
+        let qv = Vec3x4::splat(V::from_f64(Vec3::new(q.x, q.y, q.z)));
+        let uv = qv.cross(*self);
+        let uuv = qv.cross(uv);
+        uv * float(q.w) * float(2.0) + uuv * float(2.0) + *self
+    }
+}
+
+impl<V: Vector> std::ops::Add for Vec3x4<V> {
+    type Output = Vec3x4<V>;
+
+    fn add(self, other: Vec3x4<V>) -> Vec3x4<V> {
+        Vec3x4 {
+            x: self.x + other.x,
+            y: self.y + other.y,
+            z: self.z + other.z,
+        }
+    }
+}
+
+impl<V: Vector> std::ops::Sub for Vec3x4<V> {
+    type Output = Vec3x4<V>;
+
+    fn sub(self, other: Vec3x4<V>) -> Vec3x4<V> {
+        Vec3x4 {
+            x: self.x - other.x,
+            y: self.y - other.y,
+            z: self.z - other.z,
+        }
+    }
+}
+
+impl<V: Vector> std::ops::Mul<Lanes<V::Float>> for Vec3x4<V> {
+    type Output = Vec3x4<V>;
+
+    fn mul(self, scalar: Lanes<V::Float>) -> Vec3x4<V> {
+        Vec3x4 {
+            x: self.x * scalar,
+            y: self.y * scalar,
+            z: self.z * scalar,
+        }
+    }
+}
+
+impl<V: Vector> std::ops::Div for Vec3x4<V> {
+    type Output = Vec3x4<V>;
+
+    fn div(self, other: Vec3x4<V>) -> Vec3x4<V> {
+        Vec3x4 {
+            x: self.x / other.x,
+            y: self.y / other.y,
+            z: self.z / other.z,
+        }
+    }
+}
@@ -0,0 +1,614 @@
+//! The object tree compiled to a flat list of instructions, so that distance queries run
+//! through a single loop instead of recursing through boxed nodes.
+//!
+//! Instances call the program of their shared subtree, compiled once for all of them. The
+//! children of a union that have [`Bounds`] are compiled on their own and put in a
+//! hierarchy of boxes, which is looked through nearest box first: a box that can't be
+//! nearer than the distance found so far is skipped with everything in it, so that large
+//! unions don't cost one evaluation per child.
+
+use crate::bounds::Bounds;
+use crate::float::{Float, Vector};
+use crate::interval::Aabb;
+use crate::math::{Quat, Vec3};
+use crate::packet::{F32x4, F64x4, Lanes, Vec3fx4, Vec3x4, LANES};
+use crate::scene::{ObjectTree, Operation, TreeNode};
+use crate::shape::Object;
+use crate::single::Vec3f;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fmt::Formatter;
+use std::sync::Arc;
+
+/// Stack depth evaluated without allocating.
+const INLINE_STACK: usize = 32;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Instruction {
+    /// Runs the vertex shader of an object on the query point.
+    Deform { object: usize },
+    /// Brings the (possibly deformed) query point into an object's space.
+    Transform {
+        position: Vec3,
+        inverse_rotation: Quat,
+        scale: Vec3,
+    },
+    /// Pushes the distance to an object's shape from the transformed point.
+    Primitive { object: usize },
+    /// Pops two distances and pushes them combined.
+    Combine(Operation),
+    /// Pushes the distance to a node without children, which is infinitely far.
+    Empty,
+    /// Pushes the distance given by another program.
+    Call(Call),
+    /// Combines the children in the hierarchy below `root` into the union on top of the
+    /// stack.
+    Nearest { root: usize },
+}
+
+/// Program run for a distance, on the free end of the stack of the caller.
+#[derive(Clone, Copy, Debug)]
+pub enum Call {
+    /// A child of a union compiled on its own.
+    Program(usize),
+    /// The shared subtree of an instance, on the query point brought into its space.
+    Instance {
+        program: usize,
+        position: Vec3,
+        inverse_rotation: Quat,
+        scale: f64,
+    },
+}
+
+/// Box of a hierarchy, around a single child of a union or two smaller boxes.
+#[derive(Clone, Copy, Debug)]
+pub struct HierarchyNode {
+    pub bounds: Bounds,
+    pub branch: Branch,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Branch {
+    Leaf(Call),
+    Split(usize, usize),
+}
+
+/// Programs of shared subtrees compiled so far, by address.
+type Shared = HashMap<*const TreeNode, Arc<Program>>;
+
+#[derive(Clone)]
+pub struct Program {
+    instructions: Vec<Instruction>,
+    objects: Vec<Object>,
+    /// Programs called, instances of the same subtree sharing one.
+    programs: Vec<Arc<Program>>,
+    hierarchy: Vec<HierarchyNode>,
+    stack_size: usize,
+    time: f64,
+    frame: u32,
+}
+
+impl Debug for Program {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        write!(
+            f,
+            "Program {{ instructions: {}, programs: {}, stack_size: {} }}",
+            self.instructions.len(),
+            self.programs.len(),
+            self.stack_size
+        )
+    }
+}
+
+impl Program {
+    /// Compiles a tree, with vertex shaders evaluated at `time` and `frame`.
+    pub fn compile(tree: &TreeNode, time: f64, frame: u32) -> Program {
+        Program::compile_shared(tree, time, frame, &mut HashMap::new())
+    }
+
+    fn compile_shared(tree: &TreeNode, time: f64, frame: u32, shared: &mut Shared) -> Program {
+        let mut program = Program {
+            instructions: Vec::new(),
+            objects: Vec::new(),
+            programs: Vec::new(),
+            hierarchy: Vec::new(),
+            stack_size: 0,
+            time,
+            frame,
+        };
+        program.stack_size = program.emit(tree, shared);
+        program
+    }
+
+    /// Appends the instructions of a subtree, returning the stack depth it needs.
+    fn emit(&mut self, node: &TreeNode, shared: &mut Shared) -> usize {
+        match node {
+            TreeNode::Leaf(object) => {
+                let index = self.objects.len();
+                if object.vertex_shader.is_some() {
+                    self.instructions
+                        .push(Instruction::Deform { object: index });
+                }
+                self.instructions.push(Instruction::Transform {
+                    position: object.position,
+                    inverse_rotation: object.rotation.conjugate(),
+                    scale: object.scale,
+                });
+                self.instructions
+                    .push(Instruction::Primitive { object: index });
+                self.objects.push(object.clone());
+                1
+            }
+            TreeNode::Node(tree) => {
+                let (mut children, bounded) = split_bounded(tree);
+                // every operation is symmetric, so the deeper of the first two children goes
+                // first to keep the stack shallow, and the others are combined in order
+                if children.len() >= 2 && stack_size(children[1]) > stack_size(children[0]) {
+                    children.swap(0, 1);
+                }
+                let mut depth = match children.split_first() {
+                    Some((first, rest)) => {
+                        let mut depth = self.emit(first, shared);
+                        for child in rest {
+                            depth = depth.max(self.emit(child, shared) + 1);
+                            self.instructions.push(Instruction::Combine(tree.operation));
+                        }
+                        depth
+                    }
+                    None => {
+                        self.instructions.push(Instruction::Empty);
+                        1
+                    }
+                };
+                if !bounded.is_empty() {
+                    let (root, calls) = self.hierarchy(bounded, shared);
+                    self.instructions.push(Instruction::Nearest { root });
+                    depth = depth.max(calls + 1);
+                }
+                depth
+            }
+            TreeNode::Instance(_) => {
+                let call = self.call(node, shared);
+                self.instructions.push(Instruction::Call(call));
+                1
+            }
+        }
+    }
+
+    /// Compiles a subtree to a program of its own, that of an instance only once for all
+    /// the instances sharing it.
+    fn call(&mut self, node: &TreeNode, shared: &mut Shared) -> Call {
+        let TreeNode::Instance(instance) = node else {
+            let program = Program::compile_shared(node, self.time, self.frame, shared);
+            self.programs.push(Arc::new(program));
+            return Call::Program(self.programs.len() - 1);
+        };
+        let key = Arc::as_ptr(&instance.tree);
+        let program = match shared.get(&key) {
+            Some(program) => program.clone(),
+            None => {
+                let program =
+                    Program::compile_shared(&instance.tree, self.time, self.frame, shared);
+                let program = Arc::new(program);
+                shared.insert(key, program.clone());
+                program
+            }
+        };
+        let index = match self
+            .programs
+            .iter()
+            .position(|other| Arc::ptr_eq(other, &program))
+        {
+            Some(index) => index,
+            None => {
+                self.programs.push(program);
+                self.programs.len() - 1
+            }
+        };
+        Call::Instance {
+            program: index,
+            position: instance.position,
+            inverse_rotation: instance.rotation.conjugate(),
+            scale: instance.scale,
+        }
+    }
+
+    /// Puts bounded children in a hierarchy, split in two along the longest side of the box
+    /// around their centres and each half again. Returns its root and the stack depth its
+    /// calls need.
+    fn hierarchy(
+        &mut self,
+        mut children: Vec<(&TreeNode, Bounds)>,
+        shared: &mut Shared,
+    ) -> (usize, usize) {
+        let (branch, bounds, depth) = if let [(child, bounds)] = children[..] {
+            let call = self.call(child, shared);
+            let (Call::Program(program) | Call::Instance { program, .. }) = call;
+            (
+                Branch::Leaf(call),
+                bounds,
+                self.programs[program].stack_size,
+            )
+        } else {
+            let centre = |&(_, bounds): &(&TreeNode, Bounds)| bounds.aabb.centre();
+            let around = children
+                .iter()
+                .map(|child| Aabb::new(centre(child), centre(child)))
+                .reduce(|left, right| left.union(&right))
+                .expect("a hierarchy has children");
+            let size = around.size();
+            let axis: fn(Vec3) -> f64 = if size.x >= size.y && size.x >= size.z {
+                |v| v.x
+            } else if size.y >= size.z {
+                |v| v.y
+            } else {
+                |v| v.z
+            };
+            children.sort_by(|a, b| axis(centre(a)).total_cmp(&axis(centre(b))));
+            let bounds = children
+                .iter()
+                .map(|&(_, bounds)| bounds)
+                .reduce(|left, right| left.union(&right))
+                .expect("a hierarchy has children");
+            let right = children.split_off(children.len() / 2);
+            let (left, left_depth) = self.hierarchy(children, shared);
+            let (right, right_depth) = self.hierarchy(right, shared);
+            (
+                Branch::Split(left, right),
+                bounds,
+                left_depth.max(right_depth),
+            )
+        };
+        self.hierarchy.push(HierarchyNode { bounds, branch });
+        (self.hierarchy.len() - 1, depth)
+    }
+
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    /// Largest number of distances on the stack at once.
+    pub fn stack_size(&self) -> usize {
+        self.stack_size
+    }
+
+    /// Same as `Scene::distance` on the compiled tree, to the bit.
+    pub fn distance(&self, point: Vec3) -> f64 {
+        self.evaluate(point)
+    }
+
+    /// [`Program::distance`] in single precision.
+    pub fn distance_f32(&self, point: Vec3f) -> f32 {
+        self.evaluate(point)
+    }
+
+    /// Distance in the precision of the point. Kept to the crate behind the functions of
+    /// each precision, so that it is compiled here rather than in every caller.
+    pub(crate) fn evaluate<V: Vector>(&self, point: V) -> V::Float {
+        let zero = V::Float::ZERO;
+        if self.stack_size <= INLINE_STACK {
+            self.run(point, &mut [zero; INLINE_STACK])
+        } else {
+            self.run(point, &mut vec![zero; self.stack_size])
+        }
+    }
+
+    fn run<V: Vector>(&self, point: V, stack: &mut [V::Float]) -> V::Float {
+        let mut top = 0;
+        let mut sampled = point;
+        let mut local = point;
+        for instruction in &self.instructions {
+            match *instruction {
+                Instruction::Deform { object } => {
+                    let object = &self.objects[object];
+                    if let Some(vertex_shader) = &object.vertex_shader {
+                        let context = object.shader_context(point.to_f64(), self.time, self.frame);
+                        sampled = V::from_f64(vertex_shader(&context));
+                    }
+                }
+                Instruction::Transform {
+                    position,
+                    inverse_rotation,
+                    scale,
+                } => {
+                    local = (sampled - V::from_f64(position)).rotate(inverse_rotation)
+                        / V::from_f64(scale);
+                    sampled = point;
+                }
+                Instruction::Primitive { object } => {
+                    stack[top] = self.objects[object].local_distance(local);
+                    top += 1;
+                }
+                Instruction::Combine(operation) => {
+                    top -= 1;
+                    stack[top - 1] = operation.apply(stack[top - 1], stack[top]);
+                }
+                Instruction::Empty => {
+                    stack[top] = V::Float::INFINITY;
+                    top += 1;
+                }
+                Instruction::Call(call) => {
+                    stack[top] = self.call_distance(call, point, &mut stack[top..]);
+                    top += 1;
+                }
+                Instruction::Nearest { root } => {
+                    let (nearest, free) = stack[top - 1..].split_at_mut(1);
+                    let bound = self.hierarchy[root].bounds.distance(point.to_f64());
+                    if !culled(bound, nearest[0].to_f64()) {
+                        nearest[0] = self.nearest(root, point, free, nearest[0]);
+                    }
+                }
+            }
+        }
+        stack[0]
+    }
+
+    fn call_distance<V: Vector>(&self, call: Call, point: V, stack: &mut [V::Float]) -> V::Float {
+        let (program, point, scale) = match call {
+            Call::Program(program) => (program, point, 1.0),
+            Call::Instance {
+                program,
+                position,
+                inverse_rotation,
+                scale,
+            } => (
+                program,
+                (point - V::from_f64(position)).rotate(inverse_rotation)
+                    / V::Float::from_f64(scale),
+                scale,
+            ),
+        };
+        let program = &self.programs[program];
+        let distance = if program.stack_size <= stack.len() {
+            program.run(point, stack)
+        } else {
+            program.evaluate(point)
+        };
+        distance * V::Float::from_f64(scale)
+    }
+
+    /// The union of `nearest` and the children in the hierarchy below `node`, whose box
+    /// is already known not to be culled. Boxes are measured in f64 whatever the precision
+    /// of the point.
+    fn nearest<V: Vector>(
+        &self,
+        node: usize,
+        point: V,
+        stack: &mut [V::Float],
+        nearest: V::Float,
+    ) -> V::Float {
+        match self.hierarchy[node].branch {
+            Branch::Leaf(call) => {
+                Operation::Union.apply(nearest, self.call_distance(call, point, stack))
+            }
+            Branch::Split(left, right) => {
+                let bound = |node: usize| self.hierarchy[node].bounds.distance(point.to_f64());
+                let (left_bound, right_bound) = (bound(left), bound(right));
+                let order = if right_bound < left_bound {
+                    [(right, right_bound), (left, left_bound)]
+                } else {
+                    [(left, left_bound), (right, right_bound)]
+                };
+                order.into_iter().fold(nearest, |nearest, (node, bound)| {
+                    if culled(bound, nearest.to_f64()) {
+                        nearest
+                    } else {
+                        self.nearest(node, point, stack, nearest)
+                    }
+                })
+            }
+        }
+    }
+
+    /// [`Program::distance`] of every lane of a packet of points.
+    pub fn distance_packet(&self, points: Vec3x4) -> F64x4 {
+        self.evaluate_packet(points)
+    }
+
+    /// [`Program::distance_packet`] in single precision.
+    pub fn distance_packet_f32(&self, points: Vec3fx4) -> F32x4 {
+        self.evaluate_packet(points)
+    }
+
+    /// [`Program::evaluate`] of every lane of a packet of points.
+    pub(crate) fn evaluate_packet<V: Vector>(&self, points: Vec3x4<V>) -> Lanes<V::Float> {
+        let zero = Lanes::splat(V::Float::ZERO);
+        if self.stack_size <= INLINE_STACK {
+            self.run_packet(points, &mut [zero; INLINE_STACK])
+        } else {
+            self.run_packet(points, &mut vec![zero; self.stack_size])
+        }
+    }
+
+    fn run_packet<V: Vector>(
+        &self,
+        points: Vec3x4<V>,
+        stack: &mut [Lanes<V::Float>],
+    ) -> Lanes<V::Float> {
+        let mut top = 0;
+        let mut sampled = points;
+        let mut local = points;
+        for instruction in &self.instructions {
+            match *instruction {
+                Instruction::Deform { object } => {
+                    let object = &self.objects[object];
+                    if let Some(vertex_shader) = &object.vertex_shader {
+                        sampled = Vec3x4::from_lanes(std::array::from_fn(|i| {
+                            V::from_f64(vertex_shader(&object.shader_context(
+                                points.lane(i).to_f64(),
+                                self.time,
+                                self.frame,
+                            )))
+                        }));
+                    }
+                }
+                Instruction::Transform {
+                    position,
+                    inverse_rotation,
+                    scale,
+                } => {
+                    local = (sampled - Vec3x4::splat(V::from_f64(position)))
+                        .rotate(inverse_rotation)
+                        / Vec3x4::splat(V::from_f64(scale));
+                    sampled = points;
+                }
+                Instruction::Primitive { object } => {
+                    stack[top] = self.objects[object].local_distance_packet(local);
+                    top += 1;
+                }
+                Instruction::Combine(operation) => {
+                    top -= 1;
+                    stack[top - 1] = combine_packet(operation, stack[top - 1], stack[top]);
+                }
+                Instruction::Empty => {
+                    stack[top] = Lanes::splat(V::Float::INFINITY);
+                    top += 1;
+                }
+                Instruction::Call(call) => {
+                    stack[top] = self.call_distance_packet(call, points, &mut stack[top..]);
+                    top += 1;
+                }
+                Instruction::Nearest { root } => {
+                    let (nearest, free) = stack[top - 1..].split_at_mut(1);
+                    if !culled_packet(self.bound_packet(root, points), nearest[0]) {
+                        nearest[0] = self.nearest_packet(root, points, free, nearest[0]);
+                    }
+                }
+            }
+        }
+        stack[0]
+    }
+
+    fn call_distance_packet<V: Vector>(
+        &self,
+        call: Call,
+        points: Vec3x4<V>,
+        stack: &mut [Lanes<V::Float>],
+    ) -> Lanes<V::Float> {
+        let (program, points, scale) = match call {
+            Call::Program(program) => (program, points, 1.0),
+            Call::Instance {
+                program,
+                position,
+                inverse_rotation,
+                scale,
+            } => {
+                let local = (points - Vec3x4::splat(V::from_f64(position)))
+                    .rotate(inverse_rotation)
+                    / Vec3x4::splat(V::from_f64(Vec3::new(scale, scale, scale)));
+                (program, local, scale)
+            }
+        };
+        let program = &self.programs[program];
+        let distance = if program.stack_size <= stack.len() {
+            program.run_packet(points, stack)
+        } else {
+            program.evaluate_packet(points)
+        };
+        distance * Lanes::splat(V::Float::from_f64(scale))
+    }
+
+    fn bound_packet<V: Vector>(&self, node: usize, points: Vec3x4<V>) -> F64x4 {
+        let bounds = self.hierarchy[node].bounds;
+        Lanes(std::array::from_fn(|i| {
+            bounds.distance(points.lane(i).to_f64())
+        }))
+    }
+
+    /// [`Program::nearest`] for a packet: boxes are culled only when no lane needs them, and
+    /// taken nearest first on average.
+    fn nearest_packet<V: Vector>(
+        &self,
+        node: usize,
+        points: Vec3x4<V>,
+        stack: &mut [Lanes<V::Float>],
+        nearest: Lanes<V::Float>,
+    ) -> Lanes<V::Float> {
+        match self.hierarchy[node].branch {
+            Branch::Leaf(call) => combine_packet(
+                Operation::Union,
+                nearest,
+                self.call_distance_packet(call, points, stack),
+            ),
+            Branch::Split(left, right) => {
+                let (left_bound, right_bound) = (
+                    self.bound_packet(left, points),
+                    self.bound_packet(right, points),
+                );
+                let sum = |bound: F64x4| bound.0.iter().sum::<f64>();
+                let order = if sum(right_bound) < sum(left_bound) {
+                    [(right, right_bound), (left, left_bound)]
+                } else {
+                    [(left, left_bound), (right, right_bound)]
+                };
+                order.into_iter().fold(nearest, |nearest, (node, bound)| {
+                    if culled_packet(bound, nearest) {
+                        nearest
+                    } else {
+                        self.nearest_packet(node, points, stack, nearest)
+                    }
+                })
+            }
+        }
+    }
+}
+
+fn combine_packet<F: Float>(operation: Operation, left: Lanes<F>, right: Lanes<F>) -> Lanes<F> {
+    Lanes(std::array::from_fn::<_, LANES, _>(|i| {
+        operation.apply(left.0[i], right.0[i])
+    }))
+}
+
+/// Whether a box at distance `bound` can be left out of a union whose nearest distance so
+/// far is `nearest`: nothing in it can be nearer, and the point isn't inside it.
+fn culled(bound: f64, nearest: f64) -> bool {
+    bound > 0.0 && bound >= nearest
+}
+
+fn culled_packet<F: Float>(bound: F64x4, nearest: Lanes<F>) -> bool {
+    (0..LANES).all(|i| culled(bound.0[i], nearest.0[i].to_f64()))
+}
+
+/// The children of a node compiled in place, and those put in a hierarchy with their
+/// bounds: the bounded children of a union, when there are at least two.
+fn split_bounded(tree: &ObjectTree) -> (Vec<&TreeNode>, Vec<(&TreeNode, Bounds)>) {
+    let mut children = Vec::new();
+    let mut bounded = Vec::new();
+    for child in &tree.children {
+        match child.bounds() {
+            Some(bounds) if tree.operation == Operation::Union => bounded.push((child, bounds)),
+            _ => children.push(child),
+        }
+    }
+    if bounded.len() < 2 {
+        return (tree.children.iter().collect(), Vec::new());
+    }
+    (children, bounded)
+}
+
+/// Stack depth needed to evaluate a subtree, the deeper of the first two children first.
+fn stack_size(node: &TreeNode) -> usize {
+    match node {
+        TreeNode::Leaf(_) | TreeNode::Instance(_) => 1,
+        TreeNode::Node(tree) => {
+            let (children, bounded) = split_bounded(tree);
+            let sizes: Vec<usize> = children.into_iter().map(stack_size).collect();
+            let depth = match sizes[..] {
+                [] => 1,
+                [only] => only,
+                [first, second, ref rest @ ..] => rest.iter().fold(
+                    first.max(second).max(first.min(second) + 1),
+                    |depth, &size| depth.max(size + 1),
+                ),
+            };
+            // the calls of a hierarchy run above the union they are combined into
+            bounded
+                .into_iter()
+                .map(|(child, _)| match child {
+                    TreeNode::Instance(instance) => stack_size(&instance.tree),
+                    child => stack_size(child),
+                })
+                .fold(depth, |depth, size| depth.max(size + 1))
+        }
+    }
+}
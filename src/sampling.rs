@@ -0,0 +1,275 @@
+//! Pixel sampling patterns and reconstruction filters used for anti-aliasing.
+
+/// Small deterministic random number generator (SplitMix64), so renders are reproducible.
+#[derive(Clone, Copy, Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng { state: seed }
+    }
+
+    /// Seed a generator for a given pixel, so every pixel gets its own independent stream.
+    pub fn for_pixel(px: u32, py: u32, seed: u64) -> Rng {
+        let mut rng = Rng::new(seed ^ ((px as u64) << 32 | py as u64));
+        rng.next_u64();
+        rng
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform float in [0, 1).
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SamplePattern {
+    /// Regular n x n grid of samples.
+    Grid,
+    /// n x n grid rotated so that no two samples share a row or a column.
+    RotatedGrid,
+    /// n x n grid with each sample jittered inside its cell.
+    Stratified,
+    /// Best-candidate (Poisson-disk like) point set, randomly shifted per pixel.
+    BlueNoise,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+    Box,
+    Tent,
+    Gaussian,
+    Mitchell,
+}
+
+impl Filter {
+    /// Half-width of the filter support, in pixels.
+    pub fn radius(&self) -> f64 {
+        match self {
+            Filter::Box => 0.5,
+            Filter::Tent => 1.0,
+            Filter::Gaussian => 1.5,
+            Filter::Mitchell => 2.0,
+        }
+    }
+
+    /// Separable filter weight for a sample at offset (dx, dy) from the pixel centre.
+    pub fn weight(&self, dx: f64, dy: f64) -> f64 {
+        self.weight_1d(dx) * self.weight_1d(dy)
+    }
+
+    fn weight_1d(&self, x: f64) -> f64 {
+        let x = x.abs();
+        let radius = self.radius();
+        if x > radius {
+            return 0.0;
+        }
+        match self {
+            Filter::Box => 1.0,
+            Filter::Tent => 1.0 - x / radius,
+            Filter::Gaussian => {
+                const ALPHA: f64 = 2.0;
+                ((-ALPHA * x * x).exp() - (-ALPHA * radius * radius).exp()).max(0.0)
+            }
+            Filter::Mitchell => {
+                // Mitchell-Netravali with B = C = 1/3
+                const B: f64 = 1.0 / 3.0;
+                const C: f64 = 1.0 / 3.0;
+                if x < 1.0 {
+                    ((12.0 - 9.0 * B - 6.0 * C) * x * x * x
+                        + (-18.0 + 12.0 * B + 6.0 * C) * x * x
+                        + (6.0 - 2.0 * B))
+                        / 6.0
+                } else {
+                    ((-B - 6.0 * C) * x * x * x
+                        + (6.0 * B + 30.0 * C) * x * x
+                        + (-12.0 * B - 48.0 * C) * x
+                        + (8.0 * B + 24.0 * C))
+                        / 6.0
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct AntiAliasing {
+    pub pattern: SamplePattern,
+    /// Samples per pixel. Grid based patterns round this down to a square number.
+    pub samples: u32,
+    pub filter: Filter,
+    /// When set, pixels are first rendered with a single sample and only supersampled
+    /// if their colour differs from a neighbour's by more than this threshold.
+    pub adaptive_threshold: Option<f64>,
+    pub seed: u64,
+}
+
+impl Default for AntiAliasing {
+    fn default() -> AntiAliasing {
+        AntiAliasing {
+            pattern: SamplePattern::Grid,
+            samples: 1,
+            filter: Filter::Box,
+            adaptive_threshold: None,
+            seed: 0,
+        }
+    }
+}
+
+impl AntiAliasing {
+    pub fn new(pattern: SamplePattern, samples: u32, filter: Filter) -> AntiAliasing {
+        AntiAliasing {
+            pattern,
+            samples,
+            filter,
+            ..AntiAliasing::default()
+        }
+    }
+
+    pub fn set_adaptive(&mut self, threshold: f64) {
+        self.adaptive_threshold = Some(threshold);
+    }
+
+    /// The same sampling with another seed, to take more samples of the same pixels.
+    /// Regular grids become stratified, since they would sample the same spots again.
+    pub fn reseeded(&self, pass: u32) -> AntiAliasing {
+        let pattern = match self.pattern {
+            SamplePattern::Grid | SamplePattern::RotatedGrid => SamplePattern::Stratified,
+            pattern => pattern,
+        };
+        AntiAliasing {
+            pattern,
+            seed: self.seed.wrapping_add(pass as u64),
+            ..*self
+        }
+    }
+
+    /// Precomputes the parts of the pattern that are shared by every pixel.
+    pub fn sampler(&self) -> PixelSampler {
+        let base = match self.pattern {
+            SamplePattern::BlueNoise => best_candidate(self.samples.max(1), self.seed),
+            _ => Vec::new(),
+        };
+        PixelSampler {
+            settings: *self,
+            base,
+        }
+    }
+}
+
+pub struct PixelSampler {
+    settings: AntiAliasing,
+    base: Vec<(f64, f64)>,
+}
+
+impl PixelSampler {
+    /// Sample offsets from the pixel centre, in pixels, spread over the filter support.
+    pub fn offsets(&self, px: u32, py: u32) -> Vec<(f64, f64)> {
+        let settings = &self.settings;
+        let mut rng = Rng::for_pixel(px, py, settings.seed);
+        let n = (settings.samples.max(1) as f64).sqrt().floor() as u32;
+        let unit: Vec<(f64, f64)> = match settings.pattern {
+            SamplePattern::Grid => grid(n, |_| (0.5, 0.5)),
+            SamplePattern::Stratified => grid(n, |_| (rng.next_f64(), rng.next_f64())),
+            SamplePattern::RotatedGrid => {
+                // atan(1/2) is the classic rotated grid angle
+                let (sin, cos) = 0.5_f64.atan().sin_cos();
+                grid(n, |_| (0.5, 0.5))
+                    .into_iter()
+                    .map(|(x, y)| {
+                        let (x, y) = (x - 0.5, y - 0.5);
+                        let rx = x * cos - y * sin;
+                        let ry = x * sin + y * cos;
+                        ((rx + 0.5).rem_euclid(1.0), (ry + 0.5).rem_euclid(1.0))
+                    })
+                    .collect()
+            }
+            SamplePattern::BlueNoise => {
+                // toroidal shift keeps the blue noise property while decorrelating pixels
+                let (sx, sy) = (rng.next_f64(), rng.next_f64());
+                self.base
+                    .iter()
+                    .map(|(x, y)| ((x + sx).fract(), (y + sy).fract()))
+                    .collect()
+            }
+        };
+
+        let radius = settings.filter.radius();
+        unit.into_iter()
+            .map(|(x, y)| ((x - 0.5) * 2.0 * radius, (y - 0.5) * 2.0 * radius))
+            .collect()
+    }
+
+    /// Stratified samples of the unit square used to pick points on the camera lens.
+    /// They are shuffled so they are not correlated with the pixel offsets.
+    pub fn lens_samples(&self, px: u32, py: u32, count: usize) -> Vec<(f64, f64)> {
+        let mut rng = Rng::for_pixel(px, py, !self.settings.seed);
+        let n = (count as f64).sqrt().ceil() as u32;
+        let mut cells = grid(n, |_| (rng.next_f64(), rng.next_f64()));
+        for i in (1..cells.len()).rev() {
+            let j = (rng.next_u64() % (i as u64 + 1)) as usize;
+            cells.swap(i, j);
+        }
+        cells.truncate(count);
+        cells
+    }
+
+    pub fn filter(&self) -> Filter {
+        self.settings.filter
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.settings.seed
+    }
+}
+
+fn grid(n: u32, mut jitter: impl FnMut(u32) -> (f64, f64)) -> Vec<(f64, f64)> {
+    let mut points = Vec::with_capacity((n * n) as usize);
+    for j in 0..n {
+        for i in 0..n {
+            let (jx, jy) = jitter(j * n + i);
+            points.push(((i as f64 + jx) / n as f64, (j as f64 + jy) / n as f64));
+        }
+    }
+    points
+}
+
+/// Mitchell's best-candidate algorithm on the unit torus.
+fn best_candidate(count: u32, seed: u64) -> Vec<(f64, f64)> {
+    const CANDIDATES: u32 = 16;
+    let mut rng = Rng::new(seed);
+    let mut points: Vec<(f64, f64)> = Vec::with_capacity(count as usize);
+    for i in 0..count {
+        let mut best = (rng.next_f64(), rng.next_f64());
+        let mut best_distance = 0.0;
+        for _ in 0..CANDIDATES * i {
+            let candidate = (rng.next_f64(), rng.next_f64());
+            let distance = points
+                .iter()
+                .map(|p| {
+                    let dx = (p.0 - candidate.0).abs();
+                    let dy = (p.1 - candidate.1).abs();
+                    let dx = dx.min(1.0 - dx);
+                    let dy = dy.min(1.0 - dy);
+                    dx * dx + dy * dy
+                })
+                .fold(f64::INFINITY, f64::min);
+            if distance > best_distance {
+                best_distance = distance;
+                best = candidate;
+            }
+        }
+        points.push(best);
+    }
+    points
+}
@@ -1,10 +1,24 @@
 //! A scene is a collection of shapes and a camera.
 
 use crate::camera::{Camera, Ray};
-use crate::math::{Quat, Vec3};
-use crate::shape::{Object, Shape};
+use crate::dual::Dual;
+use crate::environment::Environment;
+use crate::float::{Float, Vector};
+use crate::interval::{Aabb, Interval, IntervalVec3};
+use crate::math::{Mat4, Quat, Vec3};
+use crate::matte::{self, Coverage, SurfaceId};
+use crate::packet::{F64x4, Lanes, Mask, Vec3x4, LANES};
+use crate::program::Program;
+use crate::sampling::{AntiAliasing, PixelSampler, Rng};
+use crate::shape::{FragmentShader, Object, ShaderContext, Shape};
+use crate::single::Vec3f;
+use crate::stats::{Counters, RenderStats};
+use crate::volume::Atmosphere;
 
 use image::RgbaImage;
+use std::fmt::{Debug, Formatter};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 /*pub struct Scene {
     pub camera: Camera,
@@ -101,7 +115,84 @@ impl Scene {
 impl Render {
     pub fn to_png(&self, width: u32, height: u32, dir_name: &str) {
         std::fs::create_dir_all(dir_name).unwrap();
+        for (name, image) in self.images(width, height) {
+            image.save(format!("{}/{}.png", dir_name, name)).unwrap();
+        }
+    }
+
+    /// Writes a cropped render over the images of a whole frame already saved in `dir_name`
+    /// by [`Render::to_png`], its top left corner at (`x`, `y`).
+    pub fn patch_png(&self, x: u32, y: u32, dir_name: &str) -> image::ImageResult<()> {
+        for (name, tile) in self.images(self.width(), self.height()) {
+            let path = format!("{}/{}.png", dir_name, name);
+            let mut image = image::open(&path)?.into_rgba8();
+            image::imageops::replace(&mut image, &tile, x as i64, y as i64);
+            image.save(&path)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the final image to an EXR file, along with cryptomatte layers of the objects
+    /// and materials covering each pixel, for compositors to pull mattes from.
+    pub fn to_exr(&self, path: &str) -> exr::error::UnitResult {
+        use exr::prelude::{
+            AnyChannel, AnyChannels, Encoding, FlatSamples, Image, Layer, WritableImage,
+        };
+
+        let (width, height) = (self.width(), self.height());
+        let image = self.final_image(width, height);
+        let mut channels: Vec<AnyChannel<FlatSamples>> = ["R", "G", "B", "A"]
+            .into_iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let samples = image.pixels().map(|pixel| pixel.0[i] as f32 / 255.0);
+                AnyChannel::new(name, FlatSamples::F32(samples.collect()))
+            })
+            .collect();
+        let mut attributes = exr::prelude::LayerAttributes::default();
+        let coverage = || self.coverage.iter().flatten();
+        let objects: Vec<Vec<(u32, f32)>> = coverage().map(Coverage::objects).collect();
+        let materials: Vec<Vec<(u32, f32)>> = coverage().map(Coverage::materials).collect();
+        let object_name = |id| self.object_name(id);
+        let layers: [(&str, _, &dyn Fn(u32) -> String); 2] = [
+            ("CryptoObject", objects, &object_name),
+            ("CryptoMaterial", materials, &matte::material_name),
+        ];
+        for (layer, pixels, name) in layers {
+            let (layer_channels, layer_attributes) = matte::cryptomatte_layer(layer, &pixels, name);
+            channels.extend(layer_channels);
+            attributes.other.extend(layer_attributes);
+        }
+
+        let layer = Layer::new(
+            (width as usize, height as usize),
+            attributes,
+            Encoding::FAST_LOSSLESS,
+            AnyChannels::sort(channels.into()),
+        );
+        Image::from_layer(layer).write().to_file(path)
+    }
+
+    /// Name of an object in the ID pass and the cryptomatte manifest.
+    pub fn object_name(&self, object: u32) -> String {
+        self.object_names
+            .get(object as usize)
+            .cloned()
+            .unwrap_or_else(|| matte::object_name(object))
+    }
 
+    /// The object covering most of a pixel, numbered as in [`Scene::objects`].
+    pub fn object_id(&self, x: u32, y: u32) -> Option<u32> {
+        self.coverage[y as usize][x as usize].object()
+    }
+
+    /// The material covering most of a pixel.
+    pub fn material_id(&self, x: u32, y: u32) -> Option<u32> {
+        self.coverage[y as usize][x as usize].material()
+    }
+
+    /// The final image and the buffers, as named images.
+    fn images(&self, width: u32, height: u32) -> [(&'static str, RgbaImage); 8] {
         let mut image = RgbaImage::new(width, height);
         let mut colours = RgbaImage::new(width, height);
         let mut ambient = RgbaImage::new(width, height);
@@ -121,7 +212,6 @@ impl Render {
                 let b = (ob * 255.0).round() as u8;
                 let occ = (occl * 255.0).round() as u8;
                 let depthu: u8 = 255 - (depth / 10.0 * 255.0).round() as u8;
-                let mind_powed = (1.0 - mind).powf(25.0);
                 //println!("{}", mind);
                 let mind = 255 - (mind * 255.0).min(255.0).round() as u8;
 
@@ -135,46 +225,82 @@ impl Render {
                 //println!("{}", mind);
 
                 m.put_pixel(x, y, image::Rgba([mind, mind, mind, 255]));
-                colours.put_pixel(
-                    x,
-                    y,
-                    image::Rgba([
-                        r,
-                        g,
-                        b,
-                        (self.colour[y as usize][x as usize].3 * 255.0) as u8,
-                    ]),
-                );
+                colours.put_pixel(x, y, image::Rgba([r, g, b, (oa * 255.0) as u8]));
                 ambient.put_pixel(x, y, image::Rgba([occ, occ, occ, 255]));
                 depthi.put_pixel(x, y, image::Rgba([depthu, depthu, depthu, 255]));
 
-                let occl = occl.powf(3.0);
-                let r = ((r as f64 / 255.0) * occl).min(1.0);
-                let g = ((g as f64 / 255.0) * occl).min(1.0);
-                let b = ((b as f64 / 255.0) * occl).min(1.0);
-
-                let depth = 1.0 - (depth / 30.0).min(1.0);
-                let r = r * depth;
-                let g = g * depth;
-                let b = b * depth;
-                let mut r = (r * 255.0).round() as u8;
-                let mut g = (g * 255.0).round() as u8;
-                let mut b = (b * 255.0).round() as u8;
-                if or == 0.0 && og == 0.0 && ob == 0.0 {
-                    r = (mind_powed * 255.0).round() as u8;
-                    g = (mind_powed * 255.0).round() as u8;
-                    b = (mind_powed * 255.0).round() as u8;
-                }
-                image.put_pixel(x, y, image::Rgba([r, g, b, 255]));
+                image.put_pixel(x, y, self.final_pixel(x, y));
             }
         }
 
-        image.save(format!("{}/final.png", dir_name)).unwrap();
-        colours.save(format!("{}/colours.png", dir_name)).unwrap();
-        ambient.save(format!("{}/steps.png", dir_name)).unwrap();
-        depthi.save(format!("{}/depth.png", dir_name)).unwrap();
-        m.save(format!("{}/min_distance.png", dir_name)).unwrap();
-        normals.save(format!("{}/normals.png", dir_name)).unwrap();
+        // a flat colour per id, black where nothing was hit
+        let id_pass = |id: fn(&Render, u32, u32) -> Option<u32>, name: &dyn Fn(u32) -> String| {
+            RgbaImage::from_fn(width, height, |x, y| {
+                id(self, x, y).map_or(image::Rgba([0, 0, 0, 255]), |id| {
+                    matte::name_colour(&name(id))
+                })
+            })
+        };
+
+        [
+            ("final", image),
+            ("colours", colours),
+            ("steps", ambient),
+            ("depth", depthi),
+            ("min_distance", m),
+            ("normals", normals),
+            (
+                "object_id",
+                id_pass(Render::object_id, &|id| self.object_name(id)),
+            ),
+            (
+                "material_id",
+                id_pass(Render::material_id, &matte::material_name),
+            ),
+        ]
+    }
+
+    /// Shaded colour of a pixel: the surface colour darkened by marching steps and depth,
+    /// the environment for rays that missed, or a glow around silhouettes without one.
+    /// Colours seen through an atmosphere are already faded, and shown as they are.
+    pub fn final_pixel(&self, x: u32, y: u32) -> image::Rgba<u8> {
+        let (or, og, ob, oa) = self.colour[y as usize][x as usize];
+        // background seen by rays that escaped, shown as is
+        if self.atmosphere || (oa == 0.0 && (or > 0.0 || og > 0.0 || ob > 0.0)) {
+            let to_byte = |v: f64| (v.min(1.0) * 255.0).round() as u8;
+            return image::Rgba([to_byte(or), to_byte(og), to_byte(ob), 255]);
+        }
+        let occl = self.steps[y as usize][x as usize];
+        let depth = self.depth[y as usize][x as usize];
+        let mind = self.min_distance[y as usize][x as usize];
+
+        let r = (or * 255.0).round() as u8;
+        let g = (og * 255.0).round() as u8;
+        let b = (ob * 255.0).round() as u8;
+        let mind_powed = (1.0 - mind).powf(25.0);
+
+        let occl = occl.powf(3.0);
+        let r = ((r as f64 / 255.0) * occl).min(1.0);
+        let g = ((g as f64 / 255.0) * occl).min(1.0);
+        let b = ((b as f64 / 255.0) * occl).min(1.0);
+
+        let depth = 1.0 - (depth / 30.0).min(1.0);
+        let r = r * depth;
+        let g = g * depth;
+        let b = b * depth;
+        let mut r = (r * 255.0).round() as u8;
+        let mut g = (g * 255.0).round() as u8;
+        let mut b = (b * 255.0).round() as u8;
+        if or == 0.0 && og == 0.0 && ob == 0.0 {
+            r = (mind_powed * 255.0).round() as u8;
+            g = (mind_powed * 255.0).round() as u8;
+            b = (mind_powed * 255.0).round() as u8;
+        }
+        image::Rgba([r, g, b, 255])
+    }
+
+    pub fn final_image(&self, width: u32, height: u32) -> RgbaImage {
+        RgbaImage::from_fn(width, height, |x, y| self.final_pixel(x, y))
     }
 }
 
@@ -219,24 +345,170 @@ impl Render {
     }
 }*/
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Operation {
     Union,
     SmoothUnion(f64),
     Intersection,
 }
 
+impl Operation {
+    /// Combines the distances to two shapes. Nodes with more children combine them from left
+    /// to right.
+    pub fn apply<F: Float>(&self, left: F, right: F) -> F {
+        match self {
+            Operation::Union => left.min(right),
+            Operation::SmoothUnion(k) => {
+                let k = F::from_f64(*k);
+                let h = (k - (left - right).abs()).max(F::ZERO) / k;
+                left.min(right) - h * h * k * F::from_f64(1.0 / 5.0)
+            }
+            Operation::Intersection => left.max(right),
+        }
+    }
+
+    /// [`Operation::apply`] over ranges of distances.
+    pub fn apply_interval(&self, left: Interval, right: Interval) -> Interval {
+        match self {
+            Operation::Union => left.min(right),
+            Operation::SmoothUnion(k) => {
+                let h = (Interval::point(*k) - (left - right).abs()).max(Interval::point(0.0)) / *k;
+                left.min(right) - h.square() * (k * (1.0 / 5.0))
+            }
+            Operation::Intersection => left.max(right),
+        }
+    }
+
+    /// [`Operation::apply`] carrying the gradients along.
+    pub fn apply_dual(&self, left: Dual, right: Dual) -> Dual {
+        match self {
+            Operation::Union => left.min(right),
+            Operation::SmoothUnion(k) => {
+                let h = (-(left - right).abs() + *k).max(Dual::constant(0.0)) / *k;
+                left.min(right) - h * h * (k * (1.0 / 5.0))
+            }
+            Operation::Intersection => left.max(right),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum TreeNode {
     Leaf(Object),
     Node(ObjectTree),
+    Instance(Instance),
 }
 
+/// Node combining any number of children with an operation, from left to right. A node
+/// without children is empty space.
 #[derive(Clone, Debug)]
 pub struct ObjectTree {
     pub operation: Operation,
-    pub left: Box<TreeNode>,
-    pub right: Box<TreeNode>,
+    pub children: Vec<TreeNode>,
+    /// Name to find the subtree by, and to group the names of the nodes below.
+    pub name: Option<String>,
+    pub tags: Vec<String>,
+}
+
+impl ObjectTree {
+    /// Node over `children`, flattened like [`ObjectTree::push`] does.
+    pub fn new(operation: Operation, children: Vec<TreeNode>) -> ObjectTree {
+        let mut tree = ObjectTree {
+            operation,
+            children: Vec::with_capacity(children.len()),
+            name: None,
+            tags: Vec::new(),
+        };
+        for child in children {
+            tree.push(child);
+        }
+        tree
+    }
+
+    /// Adds a child after the others. A child that is itself an anonymous node doing the
+    /// same thing has its children spliced in instead, when that gives the same distances:
+    /// always for unions and intersections, and for smooth unions only in first place, as
+    /// they blend in order.
+    pub fn push(&mut self, child: TreeNode) {
+        match child {
+            TreeNode::Node(tree)
+                if tree.name.is_none()
+                    && tree.tags.is_empty()
+                    && tree.operation == self.operation
+                    && (self.children.is_empty()
+                        || !matches!(self.operation, Operation::SmoothUnion(_))) =>
+            {
+                self.children.extend(tree.children);
+            }
+            child => self.children.push(child),
+        }
+    }
+}
+
+/// A shared subtree placed somewhere of its own. However many instances there are, the
+/// subtree is kept once. It is scaled the same along every axis, so that its distance field
+/// stays a true distance once multiplied back by `scale`.
+#[derive(Clone)]
+pub struct Instance {
+    /// Subtree shared with the other instances, in an `Arc` so that scenes can be sent to
+    /// other threads.
+    pub tree: Arc<TreeNode>,
+    pub position: Vec3,
+    pub rotation: Quat,
+    pub scale: f64,
+    /// Material id of all the objects of the instance, instead of their own.
+    pub material: Option<u32>,
+    /// Shader colouring all the objects of the instance instead of their own. Its local
+    /// positions and normals are in the instance's space.
+    pub fragment_shader: Option<FragmentShader>,
+    pub name: Option<String>,
+    pub tags: Vec<String>,
+}
+
+impl Instance {
+    pub fn new(tree: Arc<TreeNode>, position: Vec3, rotation: Quat, scale: f64) -> Instance {
+        Instance {
+            tree,
+            position,
+            rotation,
+            scale,
+            material: None,
+            fragment_shader: None,
+            name: None,
+            tags: Vec::new(),
+        }
+    }
+
+    /// Brings a point into the space of the shared subtree.
+    pub fn local_point(&self, point: Vec3) -> Vec3 {
+        self.rotation.conjugate().rotate(point - self.position) / self.scale
+    }
+
+    /// Transform from the space of the shared subtree to the instance's parent, the inverse
+    /// of [`Instance::local_point`].
+    pub fn matrix(&self) -> Mat4 {
+        let scale = Vec3::new(self.scale, self.scale, self.scale);
+        Mat4::from_placement(self.position, self.rotation, scale)
+    }
+
+    pub fn set_material(&mut self, material: u32) {
+        self.material = Some(material);
+    }
+
+    pub fn set_fragment_shader(&mut self, fragment_shader: FragmentShader) {
+        self.fragment_shader = Some(fragment_shader);
+    }
+}
+
+impl Debug for Instance {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        write!(
+            f,
+            "Instance {{ name: {:?}, position: {:?}, rotation: {:?}, scale: {:?}, tree: {:?} }}",
+            self.name, self.position, self.rotation, self.scale, self.tree
+        )
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -244,6 +516,71 @@ pub struct ObjectTree {
 pub struct Scene {
     pub camera: Camera,
     pub scene: TreeNode,
+    pub settings: RenderSettings,
+    pub environment: Environment,
+    /// Fog, smoke and clouds in front of the surfaces, none by default.
+    pub atmosphere: Option<Atmosphere>,
+    /// Time of the frame in seconds, handed to the shaders.
+    pub time: f64,
+    pub frame: u32,
+    /// Compiled tree, only set on the copies made by [`Scene::compiled`].
+    program: Option<Arc<Program>>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RenderSettings {
+    pub anti_aliasing: AntiAliasing,
+    /// Number of instants the shutter interval is split into for motion blur.
+    pub time_samples: u32,
+    /// Shadow rays towards the environment per hit, to light surfaces with it. Zero keeps
+    /// surfaces at their flat shader colour.
+    pub environment_samples: u32,
+    /// Most distance evaluations along a ray before giving up.
+    pub max_steps: u32,
+    /// Distance after which a ray is considered to escape the scene.
+    pub max_distance: f64,
+    /// Distance to a surface counted as a hit, near the camera. Rays with a footprint
+    /// accept up to half a pixel further away.
+    pub hit_epsilon: f64,
+    /// Factor applied to each step of the sphere tracing, 1 for plain steps. Larger values
+    /// get through open space faster and fall back to plain steps when they overshoot.
+    pub over_relaxation: f64,
+    /// Width in pixels of the tiles of the cone marching pre-pass, which finds how far the
+    /// primary rays of each tile can skip ahead. Zero disables it.
+    pub cone_tile: u32,
+    /// March coherent rays by packets of [`LANES`]: 2x2 pixel blocks in the preview pass and
+    /// the samples of each pixel. The image is the same either way.
+    pub packets: bool,
+    /// Float type distances are evaluated in while marching. Shading stays in f64.
+    pub precision: Precision,
+    /// Only renders these pixels of the frame, all of them if `None`.
+    pub region: Option<Region>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Precision {
+    #[default]
+    Double,
+    /// Marches in f32, which is enough for most scenes away from extreme scales.
+    Single,
+}
+
+impl Default for RenderSettings {
+    fn default() -> RenderSettings {
+        RenderSettings {
+            anti_aliasing: AntiAliasing::default(),
+            time_samples: 8,
+            environment_samples: 0,
+            max_steps: 500,
+            max_distance: 1000.0,
+            hit_epsilon: 0.001,
+            over_relaxation: 1.2,
+            cone_tile: 8,
+            packets: true,
+            precision: Precision::default(),
+            region: None,
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -260,50 +597,197 @@ pub struct Render {
     pub depth: Vec<Vec<f64>>,
     pub min_distance: Vec<Vec<f64>>,
     pub normals: Vec<Vec<Vec3>>,
+    /// Objects seen in each pixel, for the ID passes and mattes.
+    pub coverage: Vec<Vec<Coverage>>,
+    /// Names of the objects by id, for the cryptomatte manifest. Objects without one go by
+    /// [`matte::object_name`].
+    pub object_names: Vec<String>,
+    /// Whether the colours were seen through an atmosphere, which already fades them with
+    /// distance and darkens them by marching steps: they are shown as they are.
+    pub atmosphere: bool,
 }
 
 pub struct Hit {
     pub did_hit: bool,
     pub min_distance: f64,
+    /// Surface colour at the hit point, black if nothing was hit.
     pub colour: Vec3,
+    /// Surface normal at the hit point, zero if nothing was hit.
+    pub normal: Vec3,
+    /// Leaf of the tree that was hit.
+    pub id: Option<SurfaceId>,
     pub iterations: u32,
     pub total_distance: f64,
 }
 
 pub fn ray_march(scene: &Scene, ray: Ray) -> Hit {
-    let mut t = 0.0;
-    let mut distance = 100000.0;
-    let mut colour = Vec3::new(0.0, 0.0, 0.0);
-    let mut total_distance = 0.0;
-    let mut min_distance = 100000.0f64;
-    let mut iterations = 0;
-    for _ in 0..500 {
-        let point = ray.point(t);
-        let (d, col) = scene.distance_and_colour(point);
-        colour = col;
-        distance = d;
-        t += distance;
-        iterations += 1;
-        total_distance += distance;
-        min_distance = min_distance.min(distance);
-        if distance < 0.001 || distance > 1000.0 {
-            break;
-        }
-    }
-
-    let hit = Hit {
-        did_hit: distance < 0.0011,
-        min_distance,
-        colour,
-        iterations,
-        total_distance,
-    };
-    hit
+    let mut march = March::new(&scene.settings, &ray);
+    while !march.done {
+        let distance = scene.march_distance(&ray, march.t);
+        march.step(&scene.settings, &ray, distance);
+    }
+    march.hit(scene, &ray)
+}
+
+/// Marches `ray` for visibility alone: how far along it hits something, without the
+/// shading [`ray_march`] does at the hit.
+pub fn ray_hits(scene: &Scene, ray: Ray) -> Option<f64> {
+    let mut march = March::new(&scene.settings, &ray);
+    while !march.done {
+        let distance = scene.march_distance(&ray, march.t);
+        march.step(&scene.settings, &ray, distance);
+    }
+    march.did_hit().then_some(march.t)
+}
+
+/// Marches a packet of rays together, evaluating the distances of all the lanes still
+/// marching at once, in the precision of the render settings. Gives the same hits as
+/// [`ray_march`] on each ray of a `live` lane, and nothing on the others, which are never
+/// marched.
+pub fn ray_march_packet(scene: &Scene, rays: &[Ray; LANES], live: Mask) -> [Option<Hit>; LANES] {
+    let mut marches = rays.map(|ray| March::new(&scene.settings, &ray));
+    for (march, live) in marches.iter_mut().zip(live) {
+        march.done |= !live;
+    }
+    while let Some(marching) = marches.iter().position(|march| !march.done) {
+        // lanes that are done take the point of one still marching, so that they don't keep
+        // the program from culling what it doesn't need, and their distance is ignored
+        let lanes: [usize; LANES] =
+            std::array::from_fn(|i| if marches[i].done { marching } else { i });
+        let distances = match scene.settings.precision {
+            Precision::Double => scene.uncounted_distance_packet(Vec3x4::from_lanes(
+                lanes.map(|lane| rays[lane].point(marches[lane].t)),
+            )),
+            Precision::Single => {
+                let points = lanes.map(|lane| single_point(&rays[lane], marches[lane].t));
+                Lanes(
+                    scene
+                        .uncounted_distance_packet(Vec3x4::from_lanes(points))
+                        .0
+                        .map(f64::from),
+                )
+            }
+        };
+        for (i, march) in marches.iter_mut().enumerate() {
+            if !march.done {
+                march.step(&scene.settings, &rays[i], distances.0[i]);
+            }
+        }
+    }
+    std::array::from_fn(|i| live[i].then(|| marches[i].hit(scene, &rays[i])))
+}
+
+/// Point at `t` along a ray, in single precision.
+fn single_point(ray: &Ray, t: f64) -> Vec3f {
+    Vec3f::from(ray.origin) + Vec3f::from(ray.direction) * t as f32
+}
+
+/// State of the sphere tracing of one ray. Its steps, one evaluation of the distance each,
+/// are counted once it is over rather than one by one, which would cost a thread local
+/// access per evaluation.
+struct March {
+    t: f64,
+    distance: f64,
+    min_distance: f64,
+    iterations: u32,
+    relaxation: f64,
+    previous_t: f64,
+    previous_distance: f64,
+    epsilon: f64,
+    done: bool,
+}
+
+impl March {
+    fn new(settings: &RenderSettings, ray: &Ray) -> March {
+        March {
+            t: ray.near,
+            distance: 100000.0,
+            min_distance: 100000.0,
+            iterations: 0,
+            relaxation: settings.over_relaxation,
+            previous_t: ray.near,
+            previous_distance: 0.0,
+            epsilon: settings.hit_epsilon,
+            done: settings.max_steps == 0,
+        }
+    }
+
+    /// Moves along the ray given the distance to the scene at the current point.
+    fn step(&mut self, settings: &RenderSettings, ray: &Ray, distance: f64) {
+        self.distance = distance;
+        self.iterations += 1;
+        self.done = self.iterations >= settings.max_steps;
+        // over-relaxed steps can skip past the surface: if the spheres of the last two
+        // points don't overlap, go back and take plain steps from there on
+        if self.relaxation > 1.0
+            && distance.abs() + self.previous_distance < self.t - self.previous_t
+        {
+            self.t = self.previous_t + self.previous_distance;
+            self.relaxation = 1.0;
+            return;
+        }
+        self.min_distance = self.min_distance.min(distance);
+        // a hit only needs to be as precise as the pixel is wide at that distance
+        self.epsilon = settings.hit_epsilon.max(0.5 * ray.footprint * self.t);
+        if distance < self.epsilon || self.t > settings.max_distance {
+            self.done = true;
+            return;
+        }
+        self.previous_t = self.t;
+        self.previous_distance = distance;
+        self.t += distance * self.relaxation;
+    }
+
+    fn did_hit(&self) -> bool {
+        self.distance < self.epsilon * 1.1
+    }
+
+    fn hit(&self, scene: &Scene, ray: &Ray) -> Hit {
+        // shading only happens once, where the ray stopped
+        let did_hit = self.did_hit();
+        let (colour, normal, id) = if did_hit {
+            let point = ray.point(self.t);
+            let normal = scene.normal(point, self.epsilon);
+            let (_, colour, id) = scene.surface(point, normal, ray.direction);
+            (colour, normal, Some(id))
+        } else {
+            (Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 0.0), None)
+        };
+
+        Hit {
+            did_hit,
+            min_distance: self.min_distance,
+            colour,
+            normal,
+            id,
+            iterations: self.iterations,
+            total_distance: self.t,
+        }
+    }
+}
+
+impl Drop for March {
+    fn drop(&mut self) {
+        let steps = self.iterations as u64;
+        Counters::count(|counters| {
+            counters.iterations += steps;
+            counters.evaluations += steps;
+        });
+    }
 }
 
 impl Scene {
     pub fn new(camera: Camera, scene: TreeNode) -> Scene {
-        Scene { camera, scene }
+        Scene {
+            camera,
+            scene,
+            settings: RenderSettings::default(),
+            environment: Environment::default(),
+            atmosphere: None,
+            time: 0.0,
+            frame: 0,
+            program: None,
+        }
     }
 
     pub fn empty() -> Scene {
@@ -321,260 +805,1458 @@ impl Scene {
                 Vec3::new(1.0, 1.0, 1.0),
                 Shape::Sphere,
             )),
+            settings: RenderSettings::default(),
+            environment: Environment::default(),
+            atmosphere: None,
+            time: 0.0,
+            frame: 0,
+            program: None,
         }
     }
 
     pub fn distance(&self, point: Vec3) -> f64 {
-        self.distance_recursive(&self.scene, point)
+        Counters::count(|counters| counters.evaluations += 1);
+        self.uncounted_distance(point)
+    }
+
+    /// [`Scene::distance`] in single precision.
+    pub fn distance_f32(&self, point: Vec3f) -> f32 {
+        Counters::count(|counters| counters.evaluations += 1);
+        self.uncounted_distance(point)
+    }
+
+    /// [`Scene::distance`] for marches, which count their evaluations themselves.
+    fn uncounted_distance<V: Vector>(&self, point: V) -> V::Float {
+        match &self.program {
+            Some(program) => program.evaluate(point),
+            None => self.distance_recursive(&self.scene, point),
+        }
     }
 
-    fn distance_recursive(&self, node: &TreeNode, point: Vec3) -> f64 {
+    /// Distance at `t` along a ray being marched, in the precision of the render settings.
+    /// Left for the [`March`] to count.
+    fn march_distance(&self, ray: &Ray, t: f64) -> f64 {
+        match self.settings.precision {
+            Precision::Double => self.uncounted_distance(ray.point(t)),
+            Precision::Single => self.uncounted_distance(single_point(ray, t)) as f64,
+        }
+    }
+
+    /// [`Scene::distance`] of every lane of a packet of points.
+    pub fn distance_packet(&self, points: Vec3x4) -> F64x4 {
+        Counters::count(|counters| counters.evaluations += LANES as u64);
+        self.uncounted_distance_packet(points)
+    }
+
+    fn uncounted_distance_packet<V: Vector>(&self, points: Vec3x4<V>) -> Lanes<V::Float> {
+        match &self.program {
+            Some(program) => program.evaluate_packet(points),
+            None => Lanes(std::array::from_fn(|i| {
+                self.uncounted_distance(points.lane(i))
+            })),
+        }
+    }
+
+    /// Copy of the scene whose distance queries run through the tree compiled to a
+    /// [`Program`]. Changes made to the copy's objects are not seen by the program.
+    pub fn compiled(&self) -> Scene {
+        Scene {
+            program: Some(Arc::new(Program::compile(
+                &self.scene,
+                self.time,
+                self.frame,
+            ))),
+            ..self.clone()
+        }
+    }
+
+    fn distance_recursive<V: Vector>(&self, node: &TreeNode, point: V) -> V::Float {
         match node {
-            TreeNode::Leaf(object) => object.distance(point),
-            TreeNode::Node(tree) => {
-                let left = self.distance_recursive(&*tree.left, point);
-                let right = self.distance_recursive(&*tree.right, point);
-
-                match tree.operation {
-                    Operation::Union => left.min(right),
-                    Operation::SmoothUnion(k) => {
-                        let h = (k - (left - right).abs()).max(0.0) / k;
-                        let new_min = left.min(right) - h * h * k * (1.0 / 5.0);
-                        new_min
-                    }
-                    Operation::Intersection => left.max(right),
-                }
+            TreeNode::Leaf(object) => object.deformed_distance(point, self.time, self.frame),
+            TreeNode::Node(tree) => tree
+                .children
+                .iter()
+                .map(|child| self.distance_recursive(child, point))
+                .reduce(|left, right| tree.operation.apply(left, right))
+                .unwrap_or(V::Float::INFINITY),
+            TreeNode::Instance(instance) => {
+                let scale = V::Float::from_f64(instance.scale);
+                let local = (point - V::from_f64(instance.position))
+                    .rotate(instance.rotation.conjugate())
+                    / scale;
+                self.distance_recursive(&instance.tree, local) * scale
             }
         }
     }
 
-    pub fn distance_and_colour(&self, point: Vec3) -> (f64, Vec3) {
-        self.distance_and_colour_recursive(&self.scene, point)
+    /// Distance and colour at a surface point, the fragment shaders seeing the given normal
+    /// and view direction.
+    pub fn distance_and_colour(
+        &self,
+        point: Vec3,
+        normal: Vec3,
+        view_direction: Vec3,
+    ) -> (f64, Vec3) {
+        let (distance, colour, _) = self.surface(point, normal, view_direction);
+        (distance, colour)
+    }
+
+    /// [`Scene::distance_and_colour`], along with the leaf that wins the comparisons of
+    /// the tree at that point.
+    pub fn surface(
+        &self,
+        point: Vec3,
+        normal: Vec3,
+        view_direction: Vec3,
+    ) -> (f64, Vec3, SurfaceId) {
+        Counters::count(|counters| counters.evaluations += 1);
+        let mut leaves = 0;
+        self.distance_and_colour_recursive(&self.scene, point, normal, view_direction, &mut leaves)
     }
 
-    fn distance_and_colour_recursive(&self, node: &TreeNode, point: Vec3) -> (f64, Vec3) {
+    /// `leaves` counts the leaves visited so far, which numbers them in the order of
+    /// [`Scene::objects`].
+    fn distance_and_colour_recursive(
+        &self,
+        node: &TreeNode,
+        point: Vec3,
+        normal: Vec3,
+        view_direction: Vec3,
+        leaves: &mut u32,
+    ) -> (f64, Vec3, SurfaceId) {
         match node {
             TreeNode::Leaf(object) => {
-                let dist = object.distance((object.vertex_shader)(point));
-                let col = (object.fragment_shader)(point);
-                (dist, col)
+                let dist = object.deformed_distance(point, self.time, self.frame);
+                let ctx = object
+                    .shader_context(point, self.time, self.frame)
+                    .with_surface(object, normal, view_direction);
+                let col = (object.fragment_shader)(&ctx);
+                let id = SurfaceId {
+                    object: *leaves,
+                    material: object.material,
+                };
+                *leaves += 1;
+                (dist, col, id)
             }
             TreeNode::Node(tree) => {
-                let (left_dist, left_col) = self.distance_and_colour_recursive(&*tree.left, point);
-                let (right_dist, right_col) =
-                    self.distance_and_colour_recursive(&*tree.right, point);
-
-                match tree.operation {
-                    Operation::Union => {
-                        if left_dist < right_dist {
-                            (left_dist, left_col)
-                        } else {
-                            (right_dist, right_col)
-                        }
-                    }
-                    /*Operation::SmoothUnion(k) => {
-                        let h = (k - (left_dist - right_dist).abs()).max(0.0) / k;
-                        let new_min = left_dist.min(right_dist) - h * h * k * (1.0 / 5.0);
-                        //mix between the two colours
-                        let how_close_to_left =
-                            (left_dist - new_min) / (left_dist - right_dist).abs();
-                        //println!("{}", how_close_to_left);
-                        let how_close_to_right = 1.0 - how_close_to_left;
-                        let col = left_col * how_close_to_left + right_col * how_close_to_right;
-
-                        (new_min, col)
-                    }*/
-                    Operation::SmoothUnion(k) => {
-                        let h = (k - (left_dist - right_dist).abs()).max(0.0) / k;
-                        let new_min = left_dist.min(right_dist) - h * h * k * (1.0 / 5.0);
-                        //mix between the two colours
-
-                        /*let mean_col = (left_col + right_col) / 2.0;
-
-                        let how_close_to_left =
-                            ((left_dist - new_min) / (left_dist - right_dist).abs()).min(1.0);
-                        //println!("{}", how_close_to_left);
-                        let how_close_to_right = 1.0 - how_close_to_left;
-
-                        if (left_dist - right_dist).abs() < 0.01 {
-                            println!("dist : {} {}", left_dist, right_dist);
-                            println!("close : {} {}", how_close_to_left, how_close_to_right);
-                            return (new_min, mean_col);
-                        }
-
-                        // mix between the two colours with k as the factor
-                        let col = left_col * how_close_to_right + right_col * how_close_to_left;
-                        let col = mean_col * (h) + col * (1.0 - h);
-
-                        (new_min, col)*/
-
-                        let diff = left_dist - right_dist;
-                        if diff.abs() < 0.001 {
-                            let mean_col = (left_col + right_col) / 2.0;
-                            return (new_min, mean_col);
-                        } else {
-                            let how_close_to_left = (diff / diff.abs()).min(1.0);
-                            let how_close_to_right = 1.0 - how_close_to_left;
-                            let col = left_col * how_close_to_right + right_col * how_close_to_left;
-                            let mean_col = (left_col + right_col) / 2.0;
-                            let col = mean_col * (h) + col * (1.0 - h);
-                            (new_min, col)
-                        }
-                    }
-                    Operation::Intersection => {
-                        if left_dist > right_dist {
-                            (left_dist, left_col)
-                        } else {
-                            (right_dist, right_col)
-                        }
-                    }
-                }
+                let mut surfaces = tree.children.iter().map(|child| {
+                    self.distance_and_colour_recursive(child, point, normal, view_direction, leaves)
+                });
+                let Some(first) = surfaces.next() else {
+                    return (
+                        f64::INFINITY,
+                        Vec3::new(0.0, 0.0, 0.0),
+                        SurfaceId::default(),
+                    );
+                };
+                surfaces.fold(first, |left, right| {
+                    combine_surfaces(tree.operation, left, right)
+                })
+            }
+            TreeNode::Instance(instance) => {
+                let inverse = instance.rotation.conjugate();
+                let local = instance.local_point(point);
+                let local_normal = inverse.rotate(normal);
+                let (dist, colour, id) = self.distance_and_colour_recursive(
+                    &instance.tree,
+                    local,
+                    local_normal,
+                    inverse.rotate(view_direction),
+                    leaves,
+                );
+                let colour = match &instance.fragment_shader {
+                    Some(fragment_shader) => fragment_shader(&ShaderContext {
+                        position: point,
+                        local,
+                        normal,
+                        local_normal,
+                        view_direction,
+                        time: self.time,
+                        frame: self.frame,
+                    }),
+                    None => colour,
+                };
+                let id = SurfaceId {
+                    material: instance.material.unwrap_or(id.material),
+                    ..id
+                };
+                (dist * instance.scale, colour, id)
             }
         }
     }
 
+    /// Adds an object to the union at the root of the tree, or puts the tree and the object
+    /// together in a new union if the root is something else or has a name.
     pub fn add_object(&mut self, object: Object) {
-        self.scene = TreeNode::Node(ObjectTree {
-            operation: Operation::Union,
-            left: Box::new(self.scene.clone()),
-            right: Box::new(TreeNode::Leaf(object)),
-        });
+        let object = TreeNode::Leaf(object);
+        match &mut self.scene {
+            TreeNode::Node(tree)
+                if tree.operation == Operation::Union
+                    && tree.name.is_none()
+                    && tree.tags.is_empty() =>
+            {
+                tree.push(object)
+            }
+            root => {
+                let empty = TreeNode::Node(ObjectTree::new(Operation::Union, Vec::new()));
+                let root_node = std::mem::replace(root, empty);
+                *root = TreeNode::Node(ObjectTree::new(Operation::Union, vec![root_node, object]));
+            }
+        }
     }
 
     pub fn set_first_object(&mut self, object: Object) {
         self.scene = TreeNode::Leaf(object);
     }
 
+    /// All the leaves of the tree, depth first, left to right. The objects of a shared
+    /// subtree come up once for every instance of it.
+    pub fn objects(&self) -> Vec<&Object> {
+        fn collect<'a>(node: &'a TreeNode, objects: &mut Vec<&'a Object>) {
+            match node {
+                TreeNode::Leaf(object) => objects.push(object),
+                TreeNode::Node(tree) => {
+                    for child in &tree.children {
+                        collect(child, objects);
+                    }
+                }
+                TreeNode::Instance(instance) => collect(&instance.tree, objects),
+            }
+        }
+        let mut objects = Vec::new();
+        collect(&self.scene, &mut objects);
+        objects
+    }
+
+    /// [`Scene::objects`], at the same indices. The objects inside instances are `None`:
+    /// they are shared, and only change through [`Instance::tree`].
+    pub fn objects_mut(&mut self) -> Vec<Option<&mut Object>> {
+        fn collect<'a>(node: &'a mut TreeNode, objects: &mut Vec<Option<&'a mut Object>>) {
+            match node {
+                TreeNode::Leaf(object) => objects.push(Some(object)),
+                TreeNode::Node(tree) => {
+                    for child in &mut tree.children {
+                        collect(child, objects);
+                    }
+                }
+                TreeNode::Instance(instance) => {
+                    objects.extend(std::iter::repeat_with(|| None).take(count(&instance.tree)))
+                }
+            }
+        }
+        fn count(node: &TreeNode) -> usize {
+            match node {
+                TreeNode::Leaf(_) => 1,
+                TreeNode::Node(tree) => tree.children.iter().map(count).sum(),
+                TreeNode::Instance(instance) => count(&instance.tree),
+            }
+        }
+        let mut objects = Vec::new();
+        collect(&mut self.scene, &mut objects);
+        objects
+    }
+
     pub fn get_normals(&self, point: Vec3) -> Vec3 {
         const EPS: f64 = 0.001;
-        let x = self.distance(Vec3::new(point.x + EPS, point.y, point.z))
-            - self.distance(Vec3::new(point.x - EPS, point.y, point.z));
-        let y = self.distance(Vec3::new(point.x, point.y + EPS, point.z))
-            - self.distance(Vec3::new(point.x, point.y - EPS, point.z));
-        let z = self.distance(Vec3::new(point.x, point.y, point.z + EPS))
-            - self.distance(Vec3::new(point.x, point.y, point.z - EPS));
-        Vec3::new(x, y, z).normalize()
+        self.normal(point, EPS)
     }
 
+    /// Surface normal at `point`, `epsilon` being the finite difference step for objects
+    /// that can't be differentiated.
+    pub fn normal(&self, point: Vec3, epsilon: f64) -> Vec3 {
+        self.distance_and_gradient(point, epsilon)
+            .gradient
+            .normalize()
+    }
+
+    /// Guaranteed range of [`Scene::distance`] anywhere inside `bounds`.
+    pub fn distance_interval(&self, bounds: &Aabb) -> Interval {
+        self.interval_recursive(&self.scene, bounds)
+    }
+
+    fn interval_recursive(&self, node: &TreeNode, bounds: &Aabb) -> Interval {
+        match node {
+            TreeNode::Leaf(object) => object.distance_interval(bounds),
+            TreeNode::Node(tree) => tree
+                .children
+                .iter()
+                .map(|child| self.interval_recursive(child, bounds))
+                .reduce(|left, right| tree.operation.apply_interval(left, right))
+                .unwrap_or(Interval::point(f64::INFINITY)),
+            TreeNode::Instance(instance) => {
+                let scale = Vec3::new(instance.scale, instance.scale, instance.scale);
+                let local = (bounds.intervals() - IntervalVec3::constant(instance.position))
+                    .rotate(instance.rotation.conjugate())
+                    / scale;
+                self.interval_recursive(&instance.tree, &local.bounds()) * instance.scale
+            }
+        }
+    }
+
+    /// Octree subdivision of `bounds`, `depth` levels deep, keeping only the cells that may
+    /// contain the surface. Every discarded region is certain to be entirely inside or
+    /// entirely outside the scene.
+    pub fn surface_cells(&self, bounds: Aabb, depth: u32) -> Vec<Aabb> {
+        let mut cells = Vec::new();
+        let mut pending = vec![(bounds, depth)];
+        while let Some((cell, depth)) = pending.pop() {
+            if !self.distance_interval(&cell).contains(0.0) {
+                continue;
+            }
+            if depth == 0 {
+                cells.push(cell);
+            } else {
+                pending.extend(cell.octants().into_iter().map(|octant| (octant, depth - 1)));
+            }
+        }
+        cells
+    }
+
+    /// Distance and its gradient in a single pass over the tree.
+    pub fn distance_and_gradient(&self, point: Vec3, epsilon: f64) -> Dual {
+        Counters::count(|counters| counters.evaluations += 1);
+        self.gradient_recursive(&self.scene, point, epsilon)
+    }
+
+    fn gradient_recursive(&self, node: &TreeNode, point: Vec3, epsilon: f64) -> Dual {
+        match node {
+            TreeNode::Leaf(object) => {
+                object.distance_and_gradient(point, self.time, self.frame, epsilon)
+            }
+            TreeNode::Node(tree) => tree
+                .children
+                .iter()
+                .map(|child| self.gradient_recursive(child, point, epsilon))
+                .reduce(|left, right| tree.operation.apply_dual(left, right))
+                .unwrap_or(Dual::constant(f64::INFINITY)),
+            TreeNode::Instance(instance) => {
+                let local = instance.local_point(point);
+                let dual = self.gradient_recursive(&instance.tree, local, epsilon / instance.scale);
+                // the scale of the distance and of the point cancel out in the gradient
+                Dual::new(
+                    dual.value * instance.scale,
+                    instance.rotation.rotate(dual.gradient),
+                )
+            }
+        }
+    }
+
+    /// Shades a single camera ray: the primary hit, one mirror bounce and the normal.
+    pub fn shade(&self, ray: Ray) -> Sample {
+        self.shade_hit(ray, &ray_march(self, ray))
+    }
+
+    /// Shades the rays of the `live` lanes of a packet, marched together.
+    pub fn shade_packet(&self, rays: &[Ray; LANES], live: Mask) -> [Option<Sample>; LANES] {
+        let hits = ray_march_packet(self, rays, live);
+        std::array::from_fn(|i| hits[i].as_ref().map(|hit| self.shade_hit(rays[i], hit)))
+    }
+
+    /// Shades rays in order, by packets when [`RenderSettings::packets`] is set.
+    pub fn shade_all(&self, rays: &[Ray]) -> Vec<Sample> {
+        if !self.settings.packets {
+            return rays.iter().map(|ray| self.shade(*ray)).collect();
+        }
+        rays.chunks(LANES)
+            .flat_map(|chunk| {
+                // the lanes past the end of the last packet are left out
+                let packet = std::array::from_fn(|i| chunk[i.min(chunk.len() - 1)]);
+                let live = std::array::from_fn(|i| i < chunk.len());
+                self.shade_packet(&packet, live).into_iter().flatten()
+            })
+            .collect()
+    }
+
+    /// Shades a camera ray that has already been marched to `hit`.
+    fn shade_hit(&self, ray: Ray, hit: &Hit) -> Sample {
+        let occ = 1.0 - (hit.iterations as f64 / self.settings.max_steps as f64).min(1.0);
+        let colour = if hit.did_hit {
+            let point = ray.point(hit.total_distance);
+            let normal = hit.normal;
+            let surface = if self.settings.environment_samples > 0 && !self.environment.is_void() {
+                hit.colour * self.environment_light(point, normal)
+            } else {
+                hit.colour
+            };
+            let bounce = ray.direction - normal * 2.0 * ray.direction.dot(normal);
+            Counters::count(|counters| counters.bounce_rays += 1);
+            let bounce_hit = ray_march(
+                self,
+                Ray::new(point + bounce * 0.01, bounce).at_time(ray.time),
+            );
+            let reflected = if bounce_hit.did_hit {
+                Some(bounce_hit.colour)
+            } else if !self.environment.is_void() {
+                Some(self.environment.sample(bounce))
+            } else {
+                None
+            };
+            let colour = match reflected {
+                Some(reflected) => {
+                    const PROPORTION: f64 = 0.5;
+                    reflected * PROPORTION + surface * (1.0 - PROPORTION)
+                }
+                None => surface,
+            };
+            (colour.x.min(1.0), colour.y.min(1.0), colour.z.min(1.0), 1.0)
+        } else {
+            let background = self.environment.sample(ray.direction);
+            (background.x, background.y, background.z, 0.0)
+        };
+        let colour = match &self.atmosphere {
+            Some(atmosphere) => {
+                let length = if hit.did_hit {
+                    hit.total_distance
+                } else {
+                    atmosphere.max_distance
+                };
+                let jitter =
+                    Rng::new(ray.direction.x.to_bits() ^ ray.direction.y.to_bits().rotate_left(32))
+                        .next_f64();
+                let (scattered, transmittance) = atmosphere.integrate(self, ray, length, jitter);
+                // surfaces get the darkening by marching steps `Render::final_pixel` gives
+                // them without an atmosphere, before it covers them
+                let darkening = if hit.did_hit { occ.powf(3.0) } else { 1.0 };
+                let behind = Vec3::new(colour.0, colour.1, colour.2) * (darkening * transmittance)
+                    + scattered;
+                (
+                    behind.x.min(1.0),
+                    behind.y.min(1.0),
+                    behind.z.min(1.0),
+                    colour.3,
+                )
+            }
+            None => colour,
+        };
+        let normal = if hit.did_hit {
+            hit.normal
+        } else {
+            self.get_normals(ray.point(hit.total_distance))
+        };
+        Sample {
+            colour,
+            steps: occ,
+            depth: hit.total_distance,
+            min_distance: hit.min_distance,
+            normal,
+            coverage: hit.id.map_or(Coverage::default(), Coverage::single),
+        }
+    }
+
+    /// Rays of the pixel's sampling pattern, with their filter weights and the index of the
+    /// pose each goes through. `poses` are the scene at each time sample of the shutter,
+    /// empty without motion blur.
+    #[allow(clippy::too_many_arguments)]
+    fn pixel_rays(
+        &self,
+        sampler: &PixelSampler,
+        poses: &[Scene],
+        px: u32,
+        py: u32,
+        width: u32,
+        height: u32,
+        near: f64,
+    ) -> Vec<(Ray, f64, usize)> {
+        let filter = sampler.filter();
+        let offsets = sampler.offsets(px, py);
+        let lens_samples = sampler.lens_samples(px, py, offsets.len());
+        let time_shift = Rng::for_pixel(px, py, sampler.seed()).next_u64() as usize;
+        let mut rays = Vec::new();
+        for (i, ((dx, dy), (u, v))) in offsets.into_iter().zip(lens_samples).enumerate() {
+            let weight = filter.weight(dx, dy);
+            if weight == 0.0 {
+                continue;
+            }
+            let pose_index = i.wrapping_add(time_shift) % poses.len().max(1);
+            let pose = poses.get(pose_index).unwrap_or(self);
+            let x = (px as f64 + 0.5 + dx) / width as f64;
+            let y = (py as f64 + 0.5 + dy) / height as f64;
+            let ray = pose
+                .camera
+                .lens_ray(x, y, u, v)
+                .at_time(pose.time)
+                .with_footprint(pose.camera.pixel_footprint(height))
+                .starting_at(near);
+            rays.push((ray, weight, pose_index));
+        }
+        rays
+    }
+
+    /// Shades every sample of the pixel's pattern and reconstructs it with the filter.
+    /// `poses` are the scene at each time sample of the shutter, empty without motion blur.
+    #[allow(clippy::too_many_arguments)]
+    fn render_pixel(
+        &self,
+        sampler: &PixelSampler,
+        poses: &[Scene],
+        px: u32,
+        py: u32,
+        width: u32,
+        height: u32,
+        near: f64,
+    ) -> Sample {
+        let rays = self.pixel_rays(sampler, poses, px, py, width, height, near);
+        let samples = rays.iter().map(|&(ray, weight, pose_index)| {
+            (poses.get(pose_index).unwrap_or(self).shade(ray), weight)
+        });
+        self.reconstruct(samples, px, py, width, height, near)
+    }
+
+    /// [`Scene::render_pixel`] for the pixels of a 2x2 block of a static shutter, given with
+    /// the distance their rays can skip. Neighbouring pixels have the same pattern, so
+    /// their samples are marched by packets: the first sample of every pixel together, then
+    /// the second, and so on.
+    fn render_block(
+        &self,
+        sampler: &PixelSampler,
+        pixels: &[(u32, u32, f64)],
+        width: u32,
+        height: u32,
+    ) -> Vec<Sample> {
+        let rays: Vec<Vec<(Ray, f64, usize)>> = pixels
+            .iter()
+            .map(|&(px, py, near)| self.pixel_rays(sampler, &[], px, py, width, height, near))
+            .collect();
+        let mut samples: Vec<Vec<(Sample, f64)>> = vec![Vec::new(); pixels.len()];
+        let longest = rays.iter().map(Vec::len).max().unwrap_or(0);
+        for k in 0..longest {
+            let live: Mask = std::array::from_fn(|i| rays.get(i).is_some_and(|r| k < r.len()));
+            // lanes without a sample are left out, their ray is only a placeholder
+            let Some(first) = live.iter().position(|&live| live) else {
+                continue;
+            };
+            let packet = std::array::from_fn(|i| rays[if live[i] { i } else { first }][k].0);
+            for (i, sample) in self.shade_packet(&packet, live).into_iter().enumerate() {
+                if let Some(sample) = sample {
+                    samples[i].push((sample, rays[i][k].1));
+                }
+            }
+        }
+        pixels
+            .iter()
+            .zip(samples)
+            .map(|(&(px, py, near), samples)| {
+                self.reconstruct(samples.into_iter(), px, py, width, height, near)
+            })
+            .collect()
+    }
+
+    /// Average of the samples of a pixel by their filter weights, or its centre ray if the
+    /// weights cancel out.
+    fn reconstruct(
+        &self,
+        samples: impl Iterator<Item = (Sample, f64)>,
+        px: u32,
+        py: u32,
+        width: u32,
+        height: u32,
+        near: f64,
+    ) -> Sample {
+        let mut total = Sample::zero();
+        let mut total_weight = 0.0;
+        for (sample, weight) in samples {
+            total.accumulate(&sample, weight);
+            total_weight += weight;
+        }
+        if total_weight.abs() < 1e-9 {
+            return self.render_centre(px, py, width, height, near);
+        }
+        total.scaled(1.0 / total_weight)
+    }
+
+    fn render_centre(&self, px: u32, py: u32, width: u32, height: u32, near: f64) -> Sample {
+        self.shade(self.centre_ray(px, py, width, height, near))
+    }
+
+    fn centre_ray(&self, px: u32, py: u32, width: u32, height: u32, near: f64) -> Ray {
+        let x = (px as f64 + 0.5) / width as f64;
+        let y = (py as f64 + 0.5) / height as f64;
+        self.camera
+            .ray(x, y)
+            .with_footprint(self.camera.pixel_footprint(height))
+            .starting_at(near)
+    }
+
+    /// Marches a cone `spread` wide per unit of distance around `ray`, for as long as the
+    /// whole cone is empty. Returns the distance every ray inside the cone can skip.
+    pub fn cone_march(&self, ray: Ray, spread: f64) -> f64 {
+        let mut t = 0.0;
+        for _ in 0..self.settings.max_steps {
+            let distance = self.distance(ray.point(t));
+            let radius = spread * t;
+            if distance <= 2.0 * radius + self.settings.hit_epsilon
+                || t > self.settings.max_distance
+            {
+                break;
+            }
+            // the cone keeps widening during the step: stepping by `s`, its cross-section
+            // reaches `s + spread * (t + s)` from here, which must stay within `distance`
+            t += (distance - radius) / (1.0 + spread);
+        }
+        t
+    }
+
+    /// Cone marching pre-pass: how far the primary rays of each tile of `cone_tile` pixels
+    /// can skip, indexed by tile row then column. The cones are wide enough for the filter's
+    /// support and marched through every pose of the shutter. Thin lens rays don't start
+    /// from the pinhole, so there is no pre-pass with depth of field. Only the tiles
+    /// overlapping `area` are marched, the others are left at zero.
+    fn cone_prepass(
+        &self,
+        poses: &[Scene],
+        filter_radius: f64,
+        width: u32,
+        height: u32,
+        area: Region,
+    ) -> Option<Vec<Vec<f64>>> {
+        let tile = self.settings.cone_tile;
+        let scenes: Vec<&Scene> = std::iter::once(self).chain(poses).collect();
+        if tile == 0
+            || scenes
+                .iter()
+                .any(|scene| scene.camera.aperture_radius > 0.0)
+        {
+            return None;
+        }
+        let (columns, rows) = (width.div_ceil(tile), height.div_ceil(tile));
+        let depths = (0..rows)
+            .map(|ty| {
+                (0..columns)
+                    .map(|tx| {
+                        if !Region::new(tx * tile, ty * tile, tile, tile).overlaps(area) {
+                            return 0.0;
+                        }
+                        let x = ((tx as f64 + 0.5) * tile as f64) / width as f64;
+                        let y = ((ty as f64 + 0.5) * tile as f64) / height as f64;
+                        scenes
+                            .iter()
+                            .map(|scene| {
+                                // half diagonal of the tile, plus the filter's reach
+                                let pixels =
+                                    (0.5 * tile as f64 + filter_radius) * std::f64::consts::SQRT_2;
+                                let spread = scene.camera.pixel_footprint(height) * pixels;
+                                scene.cone_march(scene.camera.ray(x, y), spread)
+                            })
+                            .fold(f64::INFINITY, f64::min)
+                    })
+                    .collect()
+            })
+            .collect();
+        Some(depths)
+    }
+
+    /// Ray-marches the centre of the frame and focuses the camera on whatever it hits.
+    /// Returns the new focal distance, or `None` if the centre ray escapes the scene.
+    pub fn auto_focus(&mut self) -> Option<f64> {
+        let ray = self.camera.ray(0.5, 0.5);
+        let distance = ray_hits(self, ray)?;
+        let focal_distance = distance * ray.direction.dot(self.camera.direction);
+        self.camera.focal_distance = focal_distance;
+        Some(focal_distance)
+    }
+
+    /// Light reaching `point` from the environment, averaged over the hemisphere around
+    /// `normal` with shadow rays, so that 1 means fully lit by a white environment.
+    pub fn environment_light(&self, point: Vec3, normal: Vec3) -> Vec3 {
+        let samples = self.settings.environment_samples;
+        let mut rng = Rng::new(
+            point.x.to_bits()
+                ^ point.y.to_bits().rotate_left(21)
+                ^ point.z.to_bits().rotate_left(42),
+        );
+        let mut total = Vec3::new(0.0, 0.0, 0.0);
+        for _ in 0..samples {
+            let (direction, pdf) = self.environment.sample_direction(&mut rng);
+            let cos = direction.dot(normal);
+            if cos <= 0.0 || pdf <= 0.0 {
+                continue;
+            }
+            Counters::count(|counters| counters.shadow_rays += 1);
+            if ray_hits(self, Ray::new(point + normal * 0.01, direction)).is_none() {
+                total += self.environment.sample(direction) * (cos / pdf);
+            }
+        }
+        total / (samples as f64 * std::f64::consts::PI)
+    }
+
+    /// Moves every object along its velocity to where it is at `time`. The objects inside
+    /// instances are shared with the other instances, and stay where they are.
+    pub fn advanced(&self, time: f64) -> Scene {
+        let mut scene = self.clone();
+        let elapsed = time - self.time;
+        for object in scene.objects_mut().into_iter().flatten() {
+            object.position += object.velocity * elapsed;
+        }
+        scene.time = time;
+        scene
+    }
+
+    /// Stratified times within the camera's shutter interval, empty if the shutter is instantaneous.
+    pub fn shutter_times(&self) -> Vec<f64> {
+        let open = self.time + self.camera.shutter_open;
+        let close = self.time + self.camera.shutter_close;
+        if close <= open {
+            return Vec::new();
+        }
+        let samples = self.settings.time_samples.max(1);
+        (0..samples)
+            .map(|i| open + (close - open) * (i as f64 + 0.5) / samples as f64)
+            .collect()
+    }
+
+    /// Renders the frame, or only [`RenderSettings::region`] of it if set. The rest of the
+    /// buffer is left empty.
     pub fn render(&self, width: u32, height: u32) -> Render {
-        let mut colours = vec![vec![(0.0, 0.0, 0.0, 1.0); width as usize]; height as usize];
-        let mut occl = vec![vec![0.0; width as usize]; height as usize];
-        let mut depth = vec![vec![0.0; width as usize]; height as usize];
-        let mut min_distances = vec![vec![100000.0; width as usize]; height as usize];
-        let mut normals = vec![vec![Vec3::new(0.0, 0.0, 0.0); width as usize]; height as usize];
+        self.render_posed(width, height, |time| self.advanced(time))
+    }
 
-        let mut worst_time = std::time::Duration::new(0, 0);
-        let mut average_time = std::time::Duration::new(0, 0);
-        for py in 0..height {
-            for px in 0..width {
-                let x = px as f64 / width as f64;
-                let y = py as f64 / height as f64;
+    /// [`Scene::render`], along with the time and work every pixel took.
+    pub fn render_with_stats(&self, width: u32, height: u32) -> (Render, RenderStats) {
+        self.render_posed_with_stats(width, height, |time| self.advanced(time))
+    }
 
-                /*let ray = self.camera.ray(x, y);
-                let mut t = 0.0;
-                let mut distance = 100000.0;
-                let mut colour = Vec3::new(0.0, 0.0, 0.0);
-                let mut total_distance = 0.0;
-                let mut min_distance = 100000.0f64;
-                let mut iterations = 0;
-                for _ in 0..500 {
-                    let point = ray.point(t);
-                    let (d, col) = self.distance_and_colour(point);
-                    colour = col;
-                    distance = d;
-                    t += distance;
-                    iterations += 1;
-                    total_distance += distance;
-                    min_distance = min_distance.min(distance);
-                    if distance < 0.001 || distance > 1000.0 {
-                        break;
+    /// Renders only `region` of the frame, into a buffer the size of the region. Pixels come
+    /// out the same as in a render of the whole frame, so the result can be
+    /// [pasted](Render::paste) into one seamlessly.
+    pub fn render_cropped(&self, width: u32, height: u32, region: Region) -> Render {
+        let (render, _) = self
+            .render_frame(|| self.frame(width, height, |time| self.advanced(time), region, true));
+        render
+    }
+
+    /// Renders with motion blur, `pose` giving the scene as it is at a time within the shutter.
+    /// Without a shutter interval this is the same as [`Scene::render`].
+    pub fn render_posed(&self, width: u32, height: u32, pose: impl Fn(f64) -> Scene) -> Render {
+        let (render, stats) = self.render_posed_with_stats(width, height, pose);
+
+        println!(
+            "Worst time: {:?}",
+            std::time::Duration::from_secs_f64(stats.worst_time())
+        );
+        println!(
+            "Average time: {:?}",
+            std::time::Duration::from_secs_f64(stats.average_time())
+        );
+
+        render
+    }
+
+    /// [`Scene::render_posed`], along with the time and work every pixel took.
+    pub fn render_posed_with_stats(
+        &self,
+        width: u32,
+        height: u32,
+        pose: impl Fn(f64) -> Scene,
+    ) -> (Render, RenderStats) {
+        let region = self.region(width, height);
+        self.render_frame(|| self.frame(width, height, pose, region, false))
+    }
+
+    /// Sets up a frame and renders it in full, keeping track of the work done.
+    fn render_frame(&self, frame: impl FnOnce() -> Frame) -> (Render, RenderStats) {
+        let start = std::time::Instant::now();
+        let before = Counters::current();
+        let frame = frame();
+        let mut stats = RenderStats::new(frame.region);
+        stats.setup = Counters::current().since(before);
+
+        let sampler = self.settings.anti_aliasing.sampler();
+        let mut render = Render::new(frame.buffer.width, frame.buffer.height);
+        render.object_names = self.object_names();
+        render.atmosphere = self.atmosphere.is_some();
+        let preview = frame.adaptive_preview(&Stop::never(), &mut stats);
+        frame.sample_pass(
+            &sampler,
+            preview.as_ref(),
+            0,
+            &mut render,
+            &Stop::never(),
+            &mut stats,
+        );
+        stats.total_time = start.elapsed();
+        (render, stats)
+    }
+
+    /// Renders in passes of increasing quality, calling `on_pass` with the image after each
+    /// one: a ray per 4x4 pixels, per 2x2 pixels, the full render, then the extra passes
+    /// averaged in. The render can stop early, between pixels, if it is cancelled or runs out
+    /// of time, and the best image so far is returned with the last pass it completed.
+    /// The first pass is only ever stopped by cancellation, so there is always an image.
+    pub fn render_progressive(
+        &self,
+        width: u32,
+        height: u32,
+        progressive: &ProgressiveSettings,
+        mut on_pass: impl FnMut(Pass, &Render),
+    ) -> (Render, Option<Pass>) {
+        let deadline = progressive
+            .time_budget
+            .map(|budget| std::time::Instant::now() + budget);
+        let cancel = progressive.cancel.as_deref();
+        let region = self.region(width, height);
+        let frame = self.frame(width, height, |time| self.advanced(time), region, false);
+        let mut render = Render::new(width, height);
+        render.object_names = self.object_names();
+        render.atmosphere = self.atmosphere.is_some();
+        let mut stats = RenderStats::new(frame.region);
+        let mut completed = None;
+
+        let passes = [
+            Pass::Coarse { block: 4 },
+            Pass::Coarse { block: 2 },
+            Pass::Full,
+        ]
+        .into_iter()
+        .chain((1..=progressive.extra_passes).map(|index| Pass::Extra { index }));
+        let mut preview = None;
+        for pass in passes {
+            let stop = Stop {
+                cancel,
+                deadline: deadline.filter(|_| completed.is_some()),
+            };
+            let finished = match pass {
+                Pass::Coarse { block } => frame.block_pass(
+                    block,
+                    frame.region,
+                    frame.buffer,
+                    &mut render,
+                    &stop,
+                    &mut stats,
+                ),
+                Pass::Full | Pass::Extra { .. } => {
+                    let (anti_aliasing, previous) = match pass {
+                        Pass::Extra { index } => {
+                            (self.settings.anti_aliasing.reseeded(index), index)
+                        }
+                        _ => (self.settings.anti_aliasing, 0),
+                    };
+                    if pass == Pass::Full {
+                        preview = frame.adaptive_preview(&stop, &mut stats);
                     }
+                    !stop.reached()
+                        && frame.sample_pass(
+                            &anti_aliasing.sampler(),
+                            preview.as_ref(),
+                            previous,
+                            &mut render,
+                            &stop,
+                            &mut stats,
+                        )
                 }
+            };
+            if !finished {
+                break;
+            }
+            completed = Some(pass);
+            on_pass(pass, &render);
+        }
+        (render, completed)
+    }
 
-                let normal = self.get_normals(ray.point(t));
-                normals[py as usize][px as usize] = normal;
+    /// The pixels to render: [`RenderSettings::region`] within the frame, or all of it.
+    fn region(&self, width: u32, height: u32) -> Region {
+        self.settings
+            .region
+            .map_or(Region::full(width, height), |region| {
+                region.clipped(width, height)
+            })
+    }
 
-                let occ = 1.0 - (iterations as f64 / 500.0).min(1.0);
-                let colour = if distance < 0.001 {
-                    colour
-                } else {
-                    Vec3::new(0.0, 0.0, 0.0)
-                };
-                let colour = (colour.x, colour.y, colour.z, 1.0);
-                min_distances[py as usize][px as usize] = min_distance;
-                colours[py as usize][px as usize] = colour;
-                occl[py as usize][px as usize] = occ;
-                depth[py as usize][px as usize] = total_distance;*/
+    /// The compiled scene, its poses over the shutter and the cone marching pre-pass, which
+    /// every pass over the pixels shares. The buffer covers just the region if `cropped`,
+    /// or else the whole frame.
+    fn frame(
+        &self,
+        width: u32,
+        height: u32,
+        pose: impl Fn(f64) -> Scene,
+        region: Region,
+        cropped: bool,
+    ) -> Frame {
+        let region = region.clipped(width, height);
+        let scene = self.compiled();
+        let poses: Vec<Scene> = self
+            .shutter_times()
+            .into_iter()
+            .map(|time| pose(time).compiled())
+            .collect();
+        let filter_radius = self.settings.anti_aliasing.filter.radius();
+        // the adaptive preview looks one pixel around the region
+        let tiles = scene.cone_prepass(
+            &poses,
+            filter_radius,
+            width,
+            height,
+            region.grown(1, width, height),
+        );
+        Frame {
+            scene,
+            poses,
+            tiles,
+            width,
+            height,
+            region,
+            buffer: if cropped {
+                region
+            } else {
+                Region::full(width, height)
+            },
+        }
+    }
+}
+
+/// Distance, colour and leaf of two subtrees combined by `operation`.
+fn combine_surfaces(
+    operation: Operation,
+    (left_dist, left_col, left_id): (f64, Vec3, SurfaceId),
+    (right_dist, right_col, right_id): (f64, Vec3, SurfaceId),
+) -> (f64, Vec3, SurfaceId) {
+    // the closest leaf wins unions, even smooth ones
+    let closest = if left_dist < right_dist {
+        left_id
+    } else {
+        right_id
+    };
+
+    match operation {
+        Operation::Union => {
+            if left_dist < right_dist {
+                (left_dist, left_col, left_id)
+            } else {
+                (right_dist, right_col, right_id)
+            }
+        }
+        /*Operation::SmoothUnion(k) => {
+            let h = (k - (left_dist - right_dist).abs()).max(0.0) / k;
+            let new_min = left_dist.min(right_dist) - h * h * k * (1.0 / 5.0);
+            //mix between the two colours
+            let how_close_to_left =
+                (left_dist - new_min) / (left_dist - right_dist).abs();
+            //println!("{}", how_close_to_left);
+            let how_close_to_right = 1.0 - how_close_to_left;
+            let col = left_col * how_close_to_left + right_col * how_close_to_right;
+
+            (new_min, col)
+        }*/
+        Operation::SmoothUnion(k) => {
+            let h = (k - (left_dist - right_dist).abs()).max(0.0) / k;
+            let new_min = left_dist.min(right_dist) - h * h * k * (1.0 / 5.0);
+            //mix between the two colours
+
+            /*let mean_col = (left_col + right_col) / 2.0;
+
+            let how_close_to_left =
+                ((left_dist - new_min) / (left_dist - right_dist).abs()).min(1.0);
+            //println!("{}", how_close_to_left);
+            let how_close_to_right = 1.0 - how_close_to_left;
+
+            if (left_dist - right_dist).abs() < 0.01 {
+                println!("dist : {} {}", left_dist, right_dist);
+                println!("close : {} {}", how_close_to_left, how_close_to_right);
+                return (new_min, mean_col);
+            }
+
+            // mix between the two colours with k as the factor
+            let col = left_col * how_close_to_right + right_col * how_close_to_left;
+            let col = mean_col * (h) + col * (1.0 - h);
+
+            (new_min, col)*/
+
+            let diff = left_dist - right_dist;
+            if diff.abs() < 0.001 {
+                let mean_col = (left_col + right_col) / 2.0;
+                (new_min, mean_col, closest)
+            } else {
+                let how_close_to_left = (diff / diff.abs()).min(1.0);
+                let how_close_to_right = 1.0 - how_close_to_left;
+                let col = left_col * how_close_to_right + right_col * how_close_to_left;
+                let mean_col = (left_col + right_col) / 2.0;
+                let col = mean_col * (h) + col * (1.0 - h);
+                (new_min, col, closest)
+            }
+        }
+        Operation::Intersection => {
+            if left_dist > right_dist {
+                (left_dist, left_col, left_id)
+            } else {
+                (right_dist, right_col, right_id)
+            }
+        }
+    }
+}
+
+/// Rectangle of pixels of a frame. Regions reaching past `u32::MAX` end there.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Region {
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Region {
+        Region {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// The whole frame.
+    pub fn full(width: u32, height: u32) -> Region {
+        Region::new(0, 0, width, height)
+    }
+
+    pub fn contains(&self, px: u32, py: u32) -> bool {
+        self.columns().contains(&px) && self.rows().contains(&py)
+    }
+
+    pub fn overlaps(&self, other: Region) -> bool {
+        self.x < other.x.saturating_add(other.width)
+            && other.x < self.x.saturating_add(self.width)
+            && self.y < other.y.saturating_add(other.height)
+            && other.y < self.y.saturating_add(self.height)
+    }
+
+    /// The part of the region inside a frame of `width` x `height` pixels.
+    pub fn clipped(&self, width: u32, height: u32) -> Region {
+        let (x, y) = (self.x.min(width), self.y.min(height));
+        Region::new(
+            x,
+            y,
+            self.x.saturating_add(self.width).min(width) - x,
+            self.y.saturating_add(self.height).min(height) - y,
+        )
+    }
+
+    /// The region with `margin` more pixels on every side, within the frame.
+    pub fn grown(&self, margin: u32, width: u32, height: u32) -> Region {
+        let (x, y) = (self.x.saturating_sub(margin), self.y.saturating_sub(margin));
+        Region::new(
+            x,
+            y,
+            self.x.saturating_add(self.width).saturating_add(margin) - x,
+            self.y.saturating_add(self.height).saturating_add(margin) - y,
+        )
+        .clipped(width, height)
+    }
+
+    /// Tiles of `size` x `size` pixels covering the frame, row by row. Tiles on the right
+    /// and bottom edges are cut to the frame.
+    pub fn tiles(width: u32, height: u32, size: u32) -> Vec<Region> {
+        let size = size.max(1);
+        (0..height)
+            .step_by(size as usize)
+            .flat_map(|y| {
+                (0..width)
+                    .step_by(size as usize)
+                    .map(move |x| Region::new(x, y, size, size).clipped(width, height))
+            })
+            .collect()
+    }
 
+    pub fn rows(&self) -> std::ops::Range<u32> {
+        self.y..self.y.saturating_add(self.height)
+    }
+
+    pub fn columns(&self) -> std::ops::Range<u32> {
+        self.x..self.x.saturating_add(self.width)
+    }
+}
+
+/// Stage of a progressive render.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pass {
+    /// One ray per `block` x `block` pixels, copied over the whole block.
+    Coarse { block: u32 },
+    /// Every pixel with the full sampling pattern, the same image as [`Scene::render`].
+    Full,
+    /// Another set of samples per pixel, averaged with the previous passes.
+    Extra { index: u32 },
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ProgressiveSettings {
+    /// Passes of extra samples after the full render.
+    pub extra_passes: u32,
+    /// Time after which the render stops and returns what it has.
+    pub time_budget: Option<std::time::Duration>,
+    /// Stops the render when set, from the callback or another thread.
+    pub cancel: Option<Arc<AtomicBool>>,
+}
+
+/// When a render has to stop early, checked between pixels.
+struct Stop<'a> {
+    cancel: Option<&'a AtomicBool>,
+    deadline: Option<std::time::Instant>,
+}
+
+impl Stop<'_> {
+    fn never() -> Stop<'static> {
+        Stop {
+            cancel: None,
+            deadline: None,
+        }
+    }
+
+    fn reached(&self) -> bool {
+        self.cancel
+            .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+            || self
+                .deadline
+                .is_some_and(|deadline| std::time::Instant::now() >= deadline)
+    }
+}
+
+/// Single-ray render around the region, to find where adaptive sampling needs to
+/// supersample.
+struct Preview {
+    render: Render,
+    /// Pixels of the frame the preview's buffer covers.
+    buffer: Region,
+}
+
+/// What the passes over the pixels of a render share.
+struct Frame {
+    scene: Scene,
+    /// The scene at each time sample of the shutter, empty without motion blur.
+    poses: Vec<Scene>,
+    /// Distance the primary rays of each tile can skip, from [`Scene::cone_prepass`].
+    tiles: Option<Vec<Vec<f64>>>,
+    width: u32,
+    height: u32,
+    /// Pixels to render.
+    region: Region,
+    /// Pixels of the frame the render's buffer covers.
+    buffer: Region,
+}
+
+impl Frame {
+    fn near(&self, px: u32, py: u32) -> f64 {
+        match &self.tiles {
+            Some(tiles) => {
+                let tile = self.scene.settings.cone_tile;
+                tiles[(py / tile) as usize][(px / tile) as usize]
+            }
+            None => 0.0,
+        }
+    }
+
+    /// With adaptive sampling, a first pass with one ray per pixel decides where to
+    /// supersample. It covers one more pixel around the region, so that pixels on its edges
+    /// are compared to the same neighbours as in a render of the whole frame.
+    fn adaptive_preview(&self, stop: &Stop, stats: &mut RenderStats) -> Option<Preview> {
+        self.scene.settings.anti_aliasing.adaptive_threshold?;
+        let buffer = self.region.grown(1, self.width, self.height);
+        let mut render = Render::new(buffer.width, buffer.height);
+        self.block_pass(1, buffer, buffer, &mut render, stop, stats)
+            .then_some(Preview { render, buffer })
+    }
+
+    /// Shades one ray per `block` x `block` pixels of `area` and copies it over the block,
+    /// marching 2x2 groups of blocks by packets. Blocks are aligned on the whole frame, so
+    /// any area gets the same pixels. `buffer` is the part of the frame `render` covers.
+    /// Returns whether the pass got to the end.
+    fn block_pass(
+        &self,
+        block: u32,
+        area: Region,
+        buffer: Region,
+        render: &mut Render,
+        stop: &Stop,
+        stats: &mut RenderStats,
+    ) -> bool {
+        let (width, height) = (self.width, self.height);
+        let group = 2 * block;
+        let aligned = |start: u32| start / group * group;
+        for gy in (aligned(area.y)..area.y + area.height).step_by(group as usize) {
+            for gx in (aligned(area.x)..area.x + area.width).step_by(group as usize) {
+                if stop.reached() {
+                    return false;
+                }
                 let start = std::time::Instant::now();
-                let ray = self.camera.ray(x, y);
-                let hit = ray_march(self, ray);
-                let bounce = if hit.did_hit {
-                    let normal = self.get_normals(ray.point(hit.total_distance));
-                    let bounce = ray.direction - normal * 2.0 * ray.direction.dot(normal);
-                    Some(ray_march(
-                        self,
-                        Ray::new(ray.point(hit.total_distance) + bounce * 0.01, bounce),
-                    ))
-                } else {
-                    None
-                };
-                let occ = 1.0 - (hit.iterations as f64 / 500.0).min(1.0);
-                let colour = if let Some(bounce) = bounce {
-                    if bounce.did_hit {
-                        let bounce = bounce.colour;
-                        let hit = hit.colour;
-                        const PROPORTION: f64 = 0.5;
-                        let bounce = bounce * PROPORTION;
-                        let hit = hit * (1.0 - PROPORTION);
-                        (
-                            (bounce.x + hit.x).min(1.0),
-                            (bounce.y + hit.y).min(1.0),
-                            (bounce.z + hit.z).min(1.0),
-                            1.0,
-                        )
-                    } else {
-                        (
-                            hit.colour.x,
-                            hit.colour.y,
-                            hit.colour.z,
-                            if hit.did_hit { 1.0 } else { 0.0 },
-                        )
+                let before = Counters::current();
+                let blocks: Vec<Region> = [(0, 0), (1, 0), (0, 1), (1, 1)]
+                    .into_iter()
+                    .map(|(dx, dy)| Region::new(gx + dx * block, gy + dy * block, block, block))
+                    .filter(|cells| cells.overlaps(area))
+                    .collect();
+                let rays: Vec<Ray> = blocks
+                    .iter()
+                    .map(|cells| {
+                        let px = (cells.x + block / 2).min(width - 1);
+                        let py = (cells.y + block / 2).min(height - 1);
+                        self.scene
+                            .centre_ray(px, py, width, height, self.near(px, py))
+                    })
+                    .collect();
+                let mut pixels = Vec::new();
+                for (cells, sample) in blocks.iter().zip(self.scene.shade_all(&rays)) {
+                    for py in cells.rows().filter(|&py| area.rows().contains(&py)) {
+                        for px in cells.columns().filter(|&px| area.columns().contains(&px)) {
+                            render.set(px - buffer.x, py - buffer.y, &sample);
+                            pixels.push((px, py));
+                        }
                     }
+                }
+                stats.record_shared(&pixels, start.elapsed(), Counters::current().since(before));
+            }
+        }
+        true
+    }
+
+    /// Renders every pixel of the region with the sampler's pattern. After `previous`
+    /// passes, the pixels of `render` hold their average, which the new samples are averaged
+    /// with. With adaptive sampling, pixels that are flat in the `preview` take its colour in
+    /// the first pass and are left alone after. Returns whether the pass got to the end.
+    fn sample_pass(
+        &self,
+        sampler: &PixelSampler,
+        preview: Option<&Preview>,
+        previous: u32,
+        render: &mut Render,
+        stop: &Stop,
+        stats: &mut RenderStats,
+    ) -> bool {
+        let threshold = self.scene.settings.anti_aliasing.adaptive_threshold;
+        // the samples of a static shutter are marched by packets over 2x2 pixels
+        let packets = self.scene.settings.packets && self.poses.is_empty();
+        let group = if packets { 2 } else { 1 };
+        let aligned = |start: u32| start / group * group;
+        let region = self.region;
+        for gy in (aligned(region.y)..region.y + region.height).step_by(group as usize) {
+            for gx in (aligned(region.x)..region.x + region.width).step_by(group as usize) {
+                if stop.reached() {
+                    return false;
+                }
+                let start = std::time::Instant::now();
+                let before = Counters::current();
+                // pixels of the group in the region, flat ones with their preview sample
+                let mut pixels = Vec::new();
+                let mut marched = Vec::new();
+                let cells = Region::new(gx, gy, group, group);
+                for py in cells.rows().filter(|&py| region.rows().contains(&py)) {
+                    for px in cells.columns().filter(|&px| region.columns().contains(&px)) {
+                        let flat = match (preview, threshold) {
+                            (Some(preview), Some(threshold)) => {
+                                let (vx, vy) = (px - preview.buffer.x, py - preview.buffer.y);
+                                (preview.render.colour_contrast(vx, vy) <= threshold)
+                                    .then(|| preview.render.get(vx, vy))
+                            }
+                            _ => None,
+                        };
+                        match flat {
+                            Some(_) if previous > 0 => continue,
+                            Some(_) => {}
+                            None => marched.push((px, py, self.near(px, py))),
+                        }
+                        pixels.push((px, py, flat));
+                    }
+                }
+                let mut samples = if packets {
+                    self.scene
+                        .render_block(sampler, &marched, self.width, self.height)
                 } else {
-                    (
-                        hit.colour.x,
-                        hit.colour.y,
-                        hit.colour.z,
-                        if hit.did_hit { 1.0 } else { 0.0 },
-                    )
-                };
-                /*let colour = if let Some(bounce) = bounce {
-                    bounce.colour
-                } else {
-                    hit.colour
-                };*/
-                let normal = self.get_normals(ray.point(hit.total_distance));
-                let end = start.elapsed();
-                worst_time = worst_time.max(end);
-                average_time += end;
-                normals[py as usize][px as usize] = normal;
-                min_distances[py as usize][px as usize] = hit.min_distance;
-                colours[py as usize][px as usize] = colour;
-                occl[py as usize][px as usize] = occ;
-                depth[py as usize][px as usize] = hit.total_distance;
+                    marched
+                        .iter()
+                        .map(|&(px, py, near)| {
+                            self.scene.render_pixel(
+                                sampler,
+                                &self.poses,
+                                px,
+                                py,
+                                self.width,
+                                self.height,
+                                near,
+                            )
+                        })
+                        .collect()
+                }
+                .into_iter();
+                let mut done = Vec::new();
+                for (px, py, flat) in pixels {
+                    let (x, y) = (px - self.buffer.x, py - self.buffer.y);
+                    let sample = match flat {
+                        Some(sample) => sample,
+                        None => samples.next().expect("a sample for every marched pixel"),
+                    };
+                    let sample = if previous == 0 {
+                        sample
+                    } else {
+                        let mut total = Sample::zero();
+                        total.accumulate(&render.get(x, y), previous as f64);
+                        total.accumulate(&sample, 1.0);
+                        total.scaled(1.0 / (previous + 1) as f64)
+                    };
+                    render.set(x, y, &sample);
+                    done.push((px, py));
+                }
+                stats.record_shared(&done, start.elapsed(), Counters::current().since(before));
             }
         }
+        true
+    }
+}
+
+/// Everything a single camera ray contributes to the render buffers.
+#[derive(Clone, Copy, Debug)]
+pub struct Sample {
+    pub colour: (f64, f64, f64, f64),
+    pub steps: f64,
+    pub depth: f64,
+    pub min_distance: f64,
+    pub normal: Vec3,
+    /// Object hit by the ray, or the share of the pixel each object covers.
+    pub coverage: Coverage,
+}
+
+impl Sample {
+    fn zero() -> Sample {
+        Sample {
+            colour: (0.0, 0.0, 0.0, 0.0),
+            steps: 0.0,
+            depth: 0.0,
+            min_distance: 0.0,
+            normal: Vec3::new(0.0, 0.0, 0.0),
+            coverage: Coverage::default(),
+        }
+    }
 
-        println!("Worst time: {:?}", worst_time);
-        println!("Average time: {:?}", average_time / (width * height));
+    fn accumulate(&mut self, other: &Sample, weight: f64) {
+        self.colour.0 += other.colour.0 * weight;
+        self.colour.1 += other.colour.1 * weight;
+        self.colour.2 += other.colour.2 * weight;
+        self.colour.3 += other.colour.3 * weight;
+        self.steps += other.steps * weight;
+        self.depth += other.depth * weight;
+        self.min_distance += other.min_distance * weight;
+        self.normal += other.normal * weight;
+        self.coverage.accumulate(&other.coverage, weight);
+    }
 
+    fn scaled(&self, factor: f64) -> Sample {
+        // negative filter lobes can push values slightly out of range
+        let clamp = |v: f64| (v * factor).clamp(0.0, 1.0);
+        let normal = self.normal * factor;
+        Sample {
+            colour: (
+                clamp(self.colour.0),
+                clamp(self.colour.1),
+                clamp(self.colour.2),
+                clamp(self.colour.3),
+            ),
+            steps: clamp(self.steps),
+            depth: self.depth * factor,
+            min_distance: self.min_distance * factor,
+            normal: if normal.length() > 0.0 {
+                normal.normalize()
+            } else {
+                normal
+            },
+            coverage: self.coverage.scaled(factor),
+        }
+    }
+}
+
+impl Render {
+    pub fn new(width: u32, height: u32) -> Render {
         Render {
-            colour: colours,
-            steps: occl,
-            depth,
-            min_distance: min_distances,
-            normals,
+            colour: vec![vec![(0.0, 0.0, 0.0, 1.0); width as usize]; height as usize],
+            steps: vec![vec![0.0; width as usize]; height as usize],
+            depth: vec![vec![0.0; width as usize]; height as usize],
+            min_distance: vec![vec![100000.0; width as usize]; height as usize],
+            normals: vec![vec![Vec3::new(0.0, 0.0, 0.0); width as usize]; height as usize],
+            coverage: vec![vec![Coverage::default(); width as usize]; height as usize],
+            object_names: Vec::new(),
+            atmosphere: false,
+        }
+    }
+
+    pub fn set(&mut self, x: u32, y: u32, sample: &Sample) {
+        let (x, y) = (x as usize, y as usize);
+        self.colour[y][x] = sample.colour;
+        self.steps[y][x] = sample.steps;
+        self.depth[y][x] = sample.depth;
+        self.min_distance[y][x] = sample.min_distance;
+        self.normals[y][x] = sample.normal;
+        self.coverage[y][x] = sample.coverage;
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> Sample {
+        let (x, y) = (x as usize, y as usize);
+        Sample {
+            colour: self.colour[y][x],
+            steps: self.steps[y][x],
+            depth: self.depth[y][x],
+            min_distance: self.min_distance[y][x],
+            normal: self.normals[y][x],
+            coverage: self.coverage[y][x],
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.colour.first().map_or(0, |row| row.len() as u32)
+    }
+
+    pub fn height(&self) -> u32 {
+        self.colour.len() as u32
+    }
+
+    /// Copy of the pixels of `region`, in a buffer the size of the region.
+    pub fn crop(&self, region: Region) -> Render {
+        let region = region.clipped(self.width(), self.height());
+        let mut cropped = Render::new(region.width, region.height);
+        cropped.object_names = self.object_names.clone();
+        cropped.atmosphere = self.atmosphere;
+        for py in region.rows() {
+            for px in region.columns() {
+                cropped.set(px - region.x, py - region.y, &self.get(px, py));
+            }
+        }
+        cropped
+    }
+
+    /// Copies a cropped render over this one, its top left corner at (`x`, `y`).
+    pub fn paste(&mut self, cropped: &Render, x: u32, y: u32) {
+        let region = Region::new(x, y, cropped.width(), cropped.height())
+            .clipped(self.width(), self.height());
+        for py in region.rows() {
+            for px in region.columns() {
+                self.set(px, py, &cropped.get(px - x, py - y));
+            }
+        }
+    }
+
+    /// Copies the pixels of `region` from a render of the same frame, such as one made with
+    /// [`RenderSettings::region`].
+    pub fn merge(&mut self, other: &Render, region: Region) {
+        self.paste(&other.crop(region), region.x, region.y);
+    }
+
+    /// Largest colour difference between a pixel and its 4 neighbours.
+    pub fn colour_contrast(&self, x: u32, y: u32) -> f64 {
+        let (x, y) = (x as usize, y as usize);
+        let (r, g, b, a) = self.colour[y][x];
+        let mut contrast: f64 = 0.0;
+        let neighbours = [
+            (x.wrapping_sub(1), y),
+            (x + 1, y),
+            (x, y.wrapping_sub(1)),
+            (x, y + 1),
+        ];
+        for (nx, ny) in neighbours {
+            let Some(&(nr, ng, nb, na)) = self.colour.get(ny).and_then(|row| row.get(nx)) else {
+                continue;
+            };
+            let diff = (r - nr)
+                .abs()
+                .max((g - ng).abs())
+                .max((b - nb).abs())
+                .max((a - na).abs());
+            contrast = contrast.max(diff);
         }
+        contrast
     }
 }
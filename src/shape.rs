@@ -1,7 +1,58 @@
-use crate::math::{Quat, Vec3};
+use crate::dual::{Dual, DualVec3};
+use crate::float::{Float, Vector};
+use crate::interval::{Aabb, Interval, IntervalVec3};
+use crate::math::{Mat4, Quat, Vec3};
+use crate::noise::Displacement;
+use crate::packet::{Lanes, Vec3x4, LANES};
 use std::fmt::Debug;
 use std::fmt::Formatter;
-use std::rc::Rc;
+use std::sync::Arc;
+
+/// What shaders get to know about the point they are evaluated at.
+#[derive(Clone, Copy, Debug)]
+pub struct ShaderContext {
+    /// World space position.
+    pub position: Vec3,
+    /// Position in the object's own space, after undoing its position, rotation and scale.
+    pub local: Vec3,
+    /// World space surface normal. Zero for vertex shaders, which run before any surface is found.
+    pub normal: Vec3,
+    /// Surface normal in the object's own space, zero for vertex shaders.
+    pub local_normal: Vec3,
+    /// Direction of the ray that reached the point, from the eye towards the surface.
+    /// Zero for vertex shaders.
+    pub view_direction: Vec3,
+    /// Scene time, in seconds.
+    pub time: f64,
+    pub frame: u32,
+}
+
+impl ShaderContext {
+    /// Adds what is known once a surface has been hit.
+    pub fn with_surface(
+        self,
+        object: &Object,
+        normal: Vec3,
+        view_direction: Vec3,
+    ) -> ShaderContext {
+        let local_normal = object.rotation.conjugate().rotate(normal) * object.scale;
+        ShaderContext {
+            normal,
+            local_normal: if local_normal.length() > 0.0 {
+                local_normal.normalize()
+            } else {
+                local_normal
+            },
+            view_direction,
+            ..self
+        }
+    }
+}
+
+/// Colours a surface point. Shaders are shared between threads along with their scene.
+pub type FragmentShader = Arc<dyn Fn(&ShaderContext) -> Vec3 + Send + Sync>;
+/// Deforms space: maps the evaluated world space point to the point the shape is sampled at.
+pub type VertexShader = Arc<dyn Fn(&ShaderContext) -> Vec3 + Send + Sync>;
 
 pub struct Object {
     pub shape: Shape,
@@ -9,8 +60,18 @@ pub struct Object {
     pub rotation: Quat,
     pub scale: Vec3,
     pub inflate: f64,
-    pub fragment_shader: Rc<dyn Fn(Vec3) -> Vec3>,
-    pub vertex_shader: Rc<dyn Fn(Vec3) -> Vec3>,
+    /// World units per second, used for motion blur.
+    pub velocity: Vec3,
+    pub displacement: Option<Displacement>,
+    pub fragment_shader: FragmentShader,
+    pub vertex_shader: Option<VertexShader>,
+    /// Id of the object's material in the material ID pass. Objects that look alike can
+    /// share one to be picked together.
+    pub material: u32,
+    /// Name to find the object by in the scene, and in the cryptomatte manifest.
+    pub name: Option<String>,
+    /// Labels to pick out groups of objects.
+    pub tags: Vec<String>,
 }
 
 impl Clone for Object {
@@ -21,8 +82,13 @@ impl Clone for Object {
             rotation: self.rotation,
             scale: self.scale,
             inflate: self.inflate,
+            velocity: self.velocity,
+            displacement: self.displacement.clone(),
             fragment_shader: self.fragment_shader.clone(),
             vertex_shader: self.vertex_shader.clone(),
+            material: self.material,
+            name: self.name.clone(),
+            tags: self.tags.clone(),
         }
     }
 }
@@ -31,8 +97,8 @@ impl Debug for Object {
     fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
         write!(
             f,
-            "Object {{ position: {:?}, rotation: {:?}, scale: {:?}, shape: {:?} }}",
-            self.position, self.rotation, self.scale, self.shape
+            "Object {{ name: {:?}, position: {:?}, rotation: {:?}, scale: {:?}, shape: {:?} }}",
+            self.name, self.position, self.rotation, self.scale, self.shape
         )
     }
 }
@@ -52,41 +118,91 @@ impl Object {
             scale,
             shape,
             inflate: 0.0,
-            fragment_shader: Rc::new(|point| Vec3::new(1.0, 0.0, 1.0)),
-            vertex_shader: Rc::new(|point| point),
+            velocity: Vec3::new(0.0, 0.0, 0.0),
+            displacement: None,
+            fragment_shader: Arc::new(|_ctx| Vec3::new(1.0, 0.0, 1.0)),
+            vertex_shader: None,
+            material: 0,
+            name: None,
+            tags: Vec::new(),
         }
     }
 
-    pub fn distance(&self, point: Vec3) -> f64 {
+    /// Brings a world space point into the object's own space.
+    pub fn local_point(&self, point: Vec3) -> Vec3 {
         // translate
-        let point = (point - self.position);
+        let point = point - self.position;
         // rotate
         let point = self.rotation.conjugate().rotate(point);
         // scale
-        let point = point / self.scale;
+        point / self.scale
+    }
 
+    /// Transform from the object's own space to world space, the inverse of
+    /// [`Object::local_point`].
+    pub fn matrix(&self) -> Mat4 {
+        Mat4::from_placement(self.position, self.rotation, self.scale)
+    }
+
+    pub fn shader_context(&self, point: Vec3, time: f64, frame: u32) -> ShaderContext {
+        ShaderContext {
+            position: point,
+            local: self.local_point(point),
+            normal: Vec3::new(0.0, 0.0, 0.0),
+            local_normal: Vec3::new(0.0, 0.0, 0.0),
+            view_direction: Vec3::new(0.0, 0.0, 0.0),
+            time,
+            frame,
+        }
+    }
+
+    /// Distance with the vertex shader applied, if there is one. Vertex shaders run in f64
+    /// whatever the precision of the point.
+    pub fn deformed_distance<V: Vector>(&self, point: V, time: f64, frame: u32) -> V::Float {
+        match &self.vertex_shader {
+            Some(vertex_shader) => {
+                let context = self.shader_context(point.to_f64(), time, frame);
+                self.distance(V::from_f64(vertex_shader(&context)))
+            }
+            None => self.distance(point),
+        }
+    }
+
+    pub fn distance<V: Vector>(&self, point: V) -> V::Float {
+        // the steps of `local_point`, in the precision of the point
+        let local = (point - V::from_f64(self.position)).rotate(self.rotation.conjugate())
+            / V::from_f64(self.scale);
+        self.local_distance(local)
+    }
+
+    /// Distance from a point already brought into the object's space by [`Object::local_point`].
+    /// Displacement noise is evaluated in f64 whatever the precision of the point.
+    pub fn local_distance<V: Vector>(&self, point: V) -> V::Float {
+        let float = V::Float::from_f64;
         let dist = match self.shape {
-            Shape::Sphere => point.length() - 1.0,
+            Shape::Sphere => point.length() - float(1.0),
             Shape::Cube => {
-                let d = point.abs() - 1.0;
-                d.max(Vec3::new(0.0, 0.0, 0.0)).length()
-                    + d.min(Vec3::new(0.0, 0.0, 0.0)).max_element()
+                let d = point.abs() - float(1.0);
+                let zero = V::from_f64(Vec3::new(0.0, 0.0, 0.0));
+                d.max(zero).length() + d.min(zero).max_element()
             }
             Shape::Mandelbulb { iterations, power } => {
+                let power = float(power);
+                let scale = V::from_f64(self.scale);
                 let mut z = point;
-                let mut dr = 1.0;
-                let mut r = 0.0;
+                let mut dr = float(1.0);
+                let mut r = float(0.0);
 
                 for _ in 0..iterations {
                     r = z.length();
-                    if r > 2.0 {
+                    if r > float(2.0) {
                         break;
                     }
 
                     // Convert to polar coordinates
-                    let mut theta = (z.z / r).acos();
-                    let mut phi = z.y.atan2(z.x);
-                    dr = r.powf(power - 1.0) * power * dr + 1.0;
+                    let mut theta = (z.z() / r).acos();
+                    let mut phi = z.y().atan2(z.x());
+                    dr = r.powf(power - float(1.0)) * power * dr + float(1.0);
 
                     // Scale and rotate the point
                     let zr = r.powf(power);
@@ -94,31 +210,188 @@ impl Object {
                     phi *= power;
 
                     // Convert back to cartesian coordinates
-                    z = Vec3 {
-                        x: zr * theta.sin() * phi.cos(),
-                        y: zr * theta.sin() * phi.sin(),
-                        z: zr * theta.cos(),
-                    } + point;
+                    z = V::new(
+                        zr * theta.sin() * phi.cos(),
+                        zr * theta.sin() * phi.sin(),
+                        zr * theta.cos(),
+                    ) + point;
+
+                    z = z * scale;
+                }
+
+                float(0.5) * r.ln() * r / dr
+            }
+        };
+
+        match &self.displacement {
+            // dividing by the Lipschitz bound keeps the displaced field safe to march
+            Some(displacement) => {
+                (dist - float(self.inflate) + float(displacement.offset(point.to_f64())))
+                    / (float(1.0) + float(displacement.lipschitz()))
+            }
+            None => dist - float(self.inflate),
+        }
+    }
+
+    /// Distance and its gradient from one evaluation with dual numbers. `None` for objects
+    /// with a vertex shader or a displacement, which can't be differentiated.
+    pub fn distance_dual(&self, point: DualVec3) -> Option<Dual> {
+        if self.vertex_shader.is_some() || self.displacement.is_some() {
+            return None;
+        }
+        let point = (point - self.position).rotate(self.rotation.conjugate()) / self.scale;
+        let one = DualVec3::constant(Vec3::new(1.0, 1.0, 1.0));
+        let zero = DualVec3::constant(Vec3::new(0.0, 0.0, 0.0));
+        let dist = match self.shape {
+            Shape::Sphere => point.length() - 1.0,
+            Shape::Cube => {
+                let d = point.abs() - one;
+                d.max(zero).length() + d.min(zero).max_element()
+            }
+            Shape::Mandelbulb { iterations, power } => {
+                let mut z = point;
+                let mut dr = Dual::constant(1.0);
+                let mut r = Dual::constant(0.0);
+
+                for _ in 0..iterations {
+                    r = z.length();
+                    if r.value > 2.0 {
+                        break;
+                    }
+
+                    let theta = (z.z / r).acos() * power;
+                    let phi = z.y.atan2(z.x) * power;
+                    dr = r.powf(power - 1.0) * power * dr + 1.0;
+
+                    let zr = r.powf(power);
+                    z = DualVec3::new(
+                        zr * theta.sin() * phi.cos(),
+                        zr * theta.sin() * phi.sin(),
+                        zr * theta.cos(),
+                    ) + point;
 
                     z = z * self.scale;
                 }
 
-                0.5 * r.ln() * r / dr
+                r.ln() * r * 0.5 / dr
             }
         };
+        Some(dist - self.inflate)
+    }
 
-        return dist - self.inflate;
+    /// Distance and its gradient: exact when the object can be differentiated, from a
+    /// tetrahedral finite difference `epsilon` wide otherwise.
+    pub fn distance_and_gradient(&self, point: Vec3, time: f64, frame: u32, epsilon: f64) -> Dual {
+        if let Some(dual) = self.distance_dual(DualVec3::variable(point)) {
+            return dual;
+        }
+        let taps = [
+            Vec3::new(1.0, -1.0, -1.0),
+            Vec3::new(-1.0, -1.0, 1.0),
+            Vec3::new(-1.0, 1.0, -1.0),
+            Vec3::new(1.0, 1.0, 1.0),
+        ];
+        let mut gradient = Vec3::new(0.0, 0.0, 0.0);
+        for tap in taps {
+            gradient += tap * self.deformed_distance(point + tap * epsilon, time, frame);
+        }
+        Dual::new(
+            self.deformed_distance(point, time, frame),
+            gradient / (4.0 * epsilon),
+        )
     }
 
-    pub fn set_fragment_shader(&mut self, fragment_shader: Rc<dyn Fn(Vec3) -> Vec3>) {
+    /// Guaranteed range of the object's distance over a box. Objects with a vertex shader
+    /// can't be bounded, and Mandelbulbs only beyond their escape radius.
+    pub fn distance_interval(&self, bounds: &Aabb) -> Interval {
+        if self.vertex_shader.is_some() {
+            return Interval::entire();
+        }
+        let local = (bounds.intervals() - IntervalVec3::constant(self.position))
+            .rotate(self.rotation.conjugate())
+            / self.scale;
+        let one = IntervalVec3::constant(Vec3::new(1.0, 1.0, 1.0));
+        let zero = IntervalVec3::constant(Vec3::new(0.0, 0.0, 0.0));
+        let dist = match self.shape {
+            Shape::Sphere => local.length() - Interval::point(1.0),
+            Shape::Cube => {
+                let d = local.abs() - one;
+                d.max(zero).length() + d.min(zero).max_element()
+            }
+            Shape::Mandelbulb { .. } => {
+                // beyond the escape radius, the iteration stops before changing anything
+                let r = local.length();
+                if r.min > 2.0 {
+                    Interval::new(0.5 * r.min.ln() * r.min, 0.5 * r.max.ln() * r.max)
+                } else {
+                    Interval::entire()
+                }
+            }
+        };
+        let dist = match &self.displacement {
+            Some(displacement) => {
+                // the noise can't stray further from its value at the centre of the box
+                // than its Lipschitz bound allows
+                let local = local.bounds();
+                let lipschitz = displacement.lipschitz();
+                let offset = Interval::point(displacement.offset(local.centre()))
+                    .widen(lipschitz * (local.size() * 0.5).length());
+                (dist - Interval::point(self.inflate) + offset) / (1.0 + lipschitz)
+            }
+            None => dist - Interval::point(self.inflate),
+        };
+        // rounding errors
+        dist.widen(1e-9)
+    }
+
+    /// [`Object::local_distance`] of a packet of points. Spheres and cubes are evaluated for
+    /// all the lanes at once, Mandelbulbs and displaced objects one lane at a time.
+    pub fn local_distance_packet<V: Vector>(&self, points: Vec3x4<V>) -> Lanes<V::Float> {
+        let float = |value| Lanes::splat(V::Float::from_f64(value));
+        let one = Vec3x4::splat(V::from_f64(Vec3::new(1.0, 1.0, 1.0)));
+        let zero = Vec3x4::splat(V::from_f64(Vec3::new(0.0, 0.0, 0.0)));
+        let dist = match (self.shape, &self.displacement) {
+            (Shape::Sphere, None) => points.length() - float(1.0),
+            (Shape::Cube, None) => {
+                let d = points.abs() - one;
+                d.max(zero).length() + d.min(zero).max_element()
+            }
+            _ => {
+                return Lanes(std::array::from_fn::<_, LANES, _>(|i| {
+                    self.local_distance(points.lane(i))
+                }))
+            }
+        };
+        dist - float(self.inflate)
+    }
+
+    pub fn set_fragment_shader(&mut self, fragment_shader: FragmentShader) {
         self.fragment_shader = fragment_shader;
     }
 
-    pub fn set_vertex_shader(&mut self, vertex_shader: Rc<dyn Fn(Vec3) -> Vec3>) {
-        self.vertex_shader = vertex_shader;
+    pub fn set_vertex_shader(&mut self, vertex_shader: VertexShader) {
+        self.vertex_shader = Some(vertex_shader);
+    }
+
+    pub fn set_displacement(&mut self, displacement: Displacement) {
+        self.displacement = Some(displacement);
     }
 
     pub fn set_inflate(&mut self, inflate: f64) {
         self.inflate = inflate;
     }
+
+    pub fn set_name(&mut self, name: &str) {
+        self.name = Some(name.to_string());
+    }
+
+    pub fn add_tag(&mut self, tag: &str) {
+        if !self.has_tag(tag) {
+            self.tags.push(tag.to_string());
+        }
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|other| other == tag)
+    }
 }
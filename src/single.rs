@@ -0,0 +1,157 @@
+//! Single-precision twins of the math types, for marching rays in f32 when the precision
+//! of f64 isn't needed.
+//!
+//! Operations follow their f64 counterparts in [`crate::math`] step by step, so the two only
+//! differ by rounding.
+
+use crate::math::{Quat, Vec3};
+
+#[derive(Clone, Copy, Debug)]
+pub struct Vec3f {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+impl Vec3f {
+    pub fn new(x: f32, y: f32, z: f32) -> Vec3f {
+        Vec3f { x, y, z }
+    }
+
+    pub fn dot(&self, other: Vec3f) -> f32 {
+        self.x * other.x + self.y * other.y + self.z * other.z
+    }
+
+    pub fn cross(&self, other: Vec3f) -> Vec3f {
+        Vec3f {
+            x: self.y * other.z - self.z * other.y,
+            y: self.z * other.x - self.x * other.z,
+            z: self.x * other.y - self.y * other.x,
+        }
+    }
+
+    pub fn length(&self) -> f32 {
+        (self.x * self.x + self.y * self.y + self.z * self.z).sqrt()
+    }
+
+    pub fn abs(&self) -> Vec3f {
+        Vec3f::new(self.x.abs(), self.y.abs(), self.z.abs())
+    }
+
+    pub fn max(&self, other: Vec3f) -> Vec3f {
+        Vec3f::new(
+            self.x.max(other.x),
+            self.y.max(other.y),
+            self.z.max(other.z),
+        )
+    }
+
+    pub fn min(&self, other: Vec3f) -> Vec3f {
+        Vec3f::new(
+            self.x.min(other.x),
+            self.y.min(other.y),
+            self.z.min(other.z),
+        )
+    }
+
+    pub fn max_element(&self) -> f32 {
+        self.x.max(self.y).max(self.z)
+    }
+}
+
+impl From<Vec3> for Vec3f {
+    fn from(v: Vec3) -> Vec3f {
+        Vec3f::new(v.x as f32, v.y as f32, v.z as f32)
+    }
+}
+
+impl From<Vec3f> for Vec3 {
+    fn from(v: Vec3f) -> Vec3 {
+        Vec3::new(v.x as f64, v.y as f64, v.z as f64)
+    }
+}
+
+impl std::ops::Add for Vec3f {
+    type Output = Vec3f;
+
+    fn add(self, other: Vec3f) -> Vec3f {
+        Vec3f::new(self.x + other.x, self.y + other.y, self.z + other.z)
+    }
+}
+
+impl std::ops::Sub for Vec3f {
+    type Output = Vec3f;
+
+    fn sub(self, other: Vec3f) -> Vec3f {
+        Vec3f::new(self.x - other.x, self.y - other.y, self.z - other.z)
+    }
+}
+
+impl std::ops::Sub<f32> for Vec3f {
+    type Output = Vec3f;
+
+    fn sub(self, scalar: f32) -> Vec3f {
+        Vec3f::new(self.x - scalar, self.y - scalar, self.z - scalar)
+    }
+}
+
+impl std::ops::Mul<f32> for Vec3f {
+    type Output = Vec3f;
+
+    fn mul(self, scalar: f32) -> Vec3f {
+        Vec3f::new(self.x * scalar, self.y * scalar, self.z * scalar)
+    }
+}
+
+impl std::ops::Mul<Vec3f> for Vec3f {
+    type Output = Vec3f;
+
+    fn mul(self, other: Vec3f) -> Vec3f {
+        Vec3f::new(self.x * other.x, self.y * other.y, self.z * other.z)
+    }
+}
+
+impl std::ops::Div<f32> for Vec3f {
+    type Output = Vec3f;
+
+    fn div(self, scalar: f32) -> Vec3f {
+        Vec3f::new(self.x / scalar, self.y / scalar, self.z / scalar)
+    }
+}
+
+impl std::ops::Div<Vec3f> for Vec3f {
+    type Output = Vec3f;
+
+    fn div(self, other: Vec3f) -> Vec3f {
+        Vec3f::new(self.x / other.x, self.y / other.y, self.z / other.z)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Quatf {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub w: f32,
+}
+
+impl Quatf {
+    /// Rotates a point like [`Quat::rotate`].
+    pub fn rotate(&self, v: Vec3f) -> Vec3f {
+        let qv = Vec3f::new(self.x, self.y, self.z);
+        let uv = qv.cross(v);
+        let uuv = qv.cross(uv);
+        uv * self.w * 2.0 + uuv * 2.0 + v
+    }
+}
+
+impl From<Quat> for Quatf {
+    fn from(q: Quat) -> Quatf {
+        Quatf {
+            x: q.x as f32,
+            y: q.y as f32,
+            z: q.z as f32,
+            w: q.w as f32,
+        }
+    }
+}
@@ -0,0 +1,259 @@
+//! Render statistics: how much time and work every pixel took, to find the expensive parts
+//! of a frame and track performance over time.
+//!
+//! The work is counted by the renderer into per-thread [`Counters`], which the render
+//! samples around each pixel.
+
+use crate::math::Vec3;
+use crate::scene::Region;
+use crate::texture::Gradient;
+use image::RgbaImage;
+use std::cell::Cell;
+use std::time::Duration;
+
+thread_local! {
+    static COUNTERS: Cell<Counters> = Cell::new(Counters::default());
+}
+
+/// Work done by the renderer.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Counters {
+    /// Evaluations of the whole scene's distance field, one per lane of a packet still
+    /// marching.
+    pub evaluations: u64,
+    /// Sphere tracing steps, over all rays.
+    pub iterations: u64,
+    /// Rays reflected off surfaces.
+    pub bounce_rays: u64,
+    /// Rays towards lights and the environment to find what is in shadow.
+    pub shadow_rays: u64,
+}
+
+impl Counters {
+    /// Everything counted on this thread so far.
+    pub fn current() -> Counters {
+        COUNTERS.with(Cell::get)
+    }
+
+    pub(crate) fn count(update: impl FnOnce(&mut Counters)) {
+        COUNTERS.with(|counters| {
+            let mut current = counters.get();
+            update(&mut current);
+            counters.set(current);
+        })
+    }
+
+    /// Work done since `earlier` was taken.
+    pub fn since(&self, earlier: Counters) -> Counters {
+        Counters {
+            evaluations: self.evaluations - earlier.evaluations,
+            iterations: self.iterations - earlier.iterations,
+            bounce_rays: self.bounce_rays - earlier.bounce_rays,
+            shadow_rays: self.shadow_rays - earlier.shadow_rays,
+        }
+    }
+
+    fn add(&mut self, other: Counters) {
+        self.evaluations += other.evaluations;
+        self.iterations += other.iterations;
+        self.bounce_rays += other.bounce_rays;
+        self.shadow_rays += other.shadow_rays;
+    }
+
+    /// Share `i` of `n` even shares, the remainder going to the first ones.
+    fn share(&self, i: u64, n: u64) -> Counters {
+        let part = |total: u64| total / n + u64::from(i < total % n);
+        Counters {
+            evaluations: part(self.evaluations),
+            iterations: part(self.iterations),
+            bounce_rays: part(self.bounce_rays),
+            shadow_rays: part(self.shadow_rays),
+        }
+    }
+}
+
+/// Time and work of every pixel of a render.
+#[derive(Clone, Debug)]
+pub struct RenderStats {
+    /// Pixels of the frame the statistics cover.
+    pub region: Region,
+    /// Wall time spent on each pixel, in seconds, by row.
+    pub time: Vec<Vec<f64>>,
+    /// Work done for each pixel, by row.
+    pub counters: Vec<Vec<Counters>>,
+    /// Work done before the pixels, by the cone marching pre-pass.
+    pub setup: Counters,
+    /// Wall time of the whole render, setup included.
+    pub total_time: Duration,
+}
+
+impl RenderStats {
+    pub fn new(region: Region) -> RenderStats {
+        let (width, height) = (region.width as usize, region.height as usize);
+        RenderStats {
+            region,
+            time: vec![vec![0.0; width]; height],
+            counters: vec![vec![Counters::default(); width]; height],
+            setup: Counters::default(),
+            total_time: Duration::ZERO,
+        }
+    }
+
+    /// Adds the time and work of a pixel of the frame. Pixels outside the region are ignored.
+    pub fn record(&mut self, px: u32, py: u32, time: Duration, counters: Counters) {
+        self.record_shared(&[(px, py)], time, counters);
+    }
+
+    /// Adds time and work shared by several pixels, such as a packet of rays, in even parts
+    /// among those inside the region.
+    pub fn record_shared(&mut self, pixels: &[(u32, u32)], time: Duration, counters: Counters) {
+        let inside: Vec<(u32, u32)> = pixels
+            .iter()
+            .copied()
+            .filter(|&(px, py)| self.region.contains(px, py))
+            .collect();
+        let n = inside.len() as u64;
+        for (i, (px, py)) in inside.into_iter().enumerate() {
+            let (x, y) = ((px - self.region.x) as usize, (py - self.region.y) as usize);
+            self.time[y][x] += time.as_secs_f64() / n as f64;
+            self.counters[y][x].add(counters.share(i as u64, n));
+        }
+    }
+
+    /// Work of the whole render, setup and pixels.
+    pub fn total(&self) -> Counters {
+        let mut total = self.setup;
+        for counters in self.counters.iter().flatten() {
+            total.add(*counters);
+        }
+        total
+    }
+
+    /// Slowest pixel time, in seconds.
+    pub fn worst_time(&self) -> f64 {
+        self.time.iter().flatten().fold(0.0, |a, &b| a.max(b))
+    }
+
+    /// Mean pixel time, in seconds.
+    pub fn average_time(&self) -> f64 {
+        let pixels = (self.region.width * self.region.height).max(1);
+        self.time.iter().flatten().sum::<f64>() / pixels as f64
+    }
+
+    /// Writes heatmaps of the time and the distance evaluations of every pixel to
+    /// `time.png` and `evals.png` in `dir_name`.
+    pub fn to_png(&self, dir_name: &str) -> image::ImageResult<()> {
+        std::fs::create_dir_all(dir_name)?;
+        heatmap(&self.time).save(format!("{}/time.png", dir_name))?;
+        let evaluations: Vec<Vec<f64>> = self
+            .counters
+            .iter()
+            .map(|row| row.iter().map(|c| c.evaluations as f64).collect())
+            .collect();
+        heatmap(&evaluations).save(format!("{}/evals.png", dir_name))
+    }
+
+    /// Summary of the render as a JSON object: totals, rates and percentiles of the time
+    /// (in microseconds) and evaluations per pixel.
+    pub fn to_json(&self) -> String {
+        let times: Vec<f64> = self.time.iter().flatten().map(|t| t * 1e6).collect();
+        let evaluations: Vec<f64> = self
+            .counters
+            .iter()
+            .flatten()
+            .map(|c| c.evaluations as f64)
+            .collect();
+        let total = self.total();
+        let seconds = self.total_time.as_secs_f64();
+        let rate = |count: u64| {
+            if seconds > 0.0 {
+                count as f64 / seconds
+            } else {
+                0.0
+            }
+        };
+        let fields = [
+            ("width", self.region.width.to_string()),
+            ("height", self.region.height.to_string()),
+            ("total_time_s", json_number(seconds)),
+            ("pixel_time_us", percentiles(times)),
+            ("pixel_evals", percentiles(evaluations)),
+            ("total_evals", total.evaluations.to_string()),
+            ("total_iterations", total.iterations.to_string()),
+            ("total_bounce_rays", total.bounce_rays.to_string()),
+            ("total_shadow_rays", total.shadow_rays.to_string()),
+            ("evals_per_second", json_number(rate(total.evaluations))),
+            ("iterations_per_second", json_number(rate(total.iterations))),
+        ];
+        let body: Vec<String> = fields
+            .iter()
+            .map(|(name, value)| format!("  \"{}\": {}", name, value))
+            .collect();
+        format!("{{\n{}\n}}\n", body.join(",\n"))
+    }
+
+    /// Writes [`RenderStats::to_json`] to `stats.json` in `dir_name`.
+    pub fn save_json(&self, dir_name: &str) -> std::io::Result<()> {
+        std::fs::create_dir_all(dir_name)?;
+        std::fs::write(format!("{}/stats.json", dir_name), self.to_json())
+    }
+}
+
+/// Mean, median, 90th, 99th percentiles and maximum of some values, as a JSON object.
+fn percentiles(mut values: Vec<f64>) -> String {
+    values.sort_by(f64::total_cmp);
+    let at = |p: f64| match values.len() {
+        0 => 0.0,
+        n => values[((n - 1) as f64 * p).round() as usize],
+    };
+    let mean = values.iter().sum::<f64>() / values.len().max(1) as f64;
+    format!(
+        "{{ \"mean\": {}, \"p50\": {}, \"p90\": {}, \"p99\": {}, \"max\": {} }}",
+        json_number(mean),
+        json_number(at(0.5)),
+        json_number(at(0.9)),
+        json_number(at(0.99)),
+        json_number(at(1.0))
+    )
+}
+
+/// JSON has no infinities or NaN.
+fn json_number(value: f64) -> String {
+    if value.is_finite() {
+        format!("{:.3}", value)
+    } else {
+        "null".to_string()
+    }
+}
+
+/// Values as colours from black through red and yellow to white, scaled to the 99th
+/// percentile so a few outliers don't wash out the rest.
+fn heatmap(values: &[Vec<f64>]) -> RgbaImage {
+    let height = values.len() as u32;
+    let width = values.first().map_or(0, |row| row.len() as u32);
+    let mut sorted: Vec<f64> = values.iter().flatten().copied().collect();
+    sorted.sort_by(f64::total_cmp);
+    let scale = match sorted.len() {
+        0 => 1.0,
+        n => sorted[(n - 1) * 99 / 100].max(f64::MIN_POSITIVE),
+    };
+    let ramp = Gradient::new(vec![
+        (0.0, Vec3::new(0.0, 0.0, 0.0)),
+        (0.4, Vec3::new(0.8, 0.0, 0.0)),
+        (0.8, Vec3::new(1.0, 0.9, 0.0)),
+        (1.0, Vec3::new(1.0, 1.0, 1.0)),
+    ]);
+    let mut image = RgbaImage::new(width, height);
+    for (y, row) in values.iter().enumerate() {
+        for (x, value) in row.iter().enumerate() {
+            let colour = ramp.sample(value / scale);
+            let to_byte = |v: f64| (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+            image.put_pixel(
+                x as u32,
+                y as u32,
+                image::Rgba([to_byte(colour.x), to_byte(colour.y), to_byte(colour.z), 255]),
+            );
+        }
+    }
+    image
+}
@@ -0,0 +1,189 @@
+//! Texture functions for fragment shaders.
+//!
+//! Patterns return a mask in [0, 1] meant to be fed to [`mix`] or a [`Gradient`], so they
+//! compose freely. They are usually evaluated on `ShaderContext::local` so that textures
+//! follow their object around.
+
+use crate::math::Vec3;
+use image::error::{ImageError, ParameterError, ParameterErrorKind};
+use std::path::Path;
+
+pub fn mix(a: Vec3, b: Vec3, t: f64) -> Vec3 {
+    a.lerp(b, t)
+}
+
+/// 3D checkerboard of unit cubes.
+pub fn checker(p: Vec3) -> f64 {
+    let sum = p.x.floor() + p.y.floor() + p.z.floor();
+    sum.rem_euclid(2.0)
+}
+
+/// Parallel slabs along `axis`, one unit wide, `duty` being the fraction covered by the stripe.
+pub fn stripes(p: Vec3, axis: Vec3, duty: f64) -> f64 {
+    if p.dot(axis).rem_euclid(1.0) < duty {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+/// Concentric rings around the Y axis, like tree rings, one unit apart.
+pub fn rings(p: Vec3, duty: f64) -> f64 {
+    let radius = (p.x * p.x + p.z * p.z).sqrt();
+    if radius.rem_euclid(1.0) < duty {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+/// Bricks laid along X with rows along Y, every other row offset by half a brick.
+/// Returns 1 on bricks and 0 in the mortar.
+pub fn brick(p: Vec3, size: Vec3, mortar: f64) -> f64 {
+    let row = (p.y / size.y).floor();
+    let shift = if row.rem_euclid(2.0) == 0.0 { 0.0 } else { 0.5 };
+    let x = (p.x / size.x + shift).rem_euclid(1.0) * size.x;
+    let y = (p.y / size.y).rem_euclid(1.0) * size.y;
+    let z = (p.z / size.z).rem_euclid(1.0) * size.z;
+    let inside = |v: f64, size: f64| v > mortar * 0.5 && v < size - mortar * 0.5;
+    if inside(x, size.x) && inside(y, size.y) && inside(z, size.z) {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+/// Colour ramp, linearly interpolated between sorted stops.
+#[derive(Clone, Debug)]
+pub struct Gradient {
+    stops: Vec<(f64, Vec3)>,
+}
+
+impl Gradient {
+    pub fn new(mut stops: Vec<(f64, Vec3)>) -> Gradient {
+        stops.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Gradient { stops }
+    }
+
+    pub fn two(from: Vec3, to: Vec3) -> Gradient {
+        Gradient::new(vec![(0.0, from), (1.0, to)])
+    }
+
+    pub fn sample(&self, t: f64) -> Vec3 {
+        let Some(first) = self.stops.first() else {
+            return Vec3::new(0.0, 0.0, 0.0);
+        };
+        if t <= first.0 {
+            return first.1;
+        }
+        let next = self.stops.partition_point(|stop| stop.0 <= t);
+        if next == self.stops.len() {
+            return self.stops[next - 1].1;
+        }
+        let (t0, c0) = self.stops[next - 1];
+        let (t1, c1) = self.stops[next];
+        mix(c0, c1, (t - t0) / (t1 - t0))
+    }
+
+    /// Ramp along `axis`, `from` and `to` being the positions of the first and last stops.
+    pub fn along(&self, p: Vec3, axis: Vec3, from: f64, to: f64) -> Vec3 {
+        self.sample((p.dot(axis) - from) / (to - from))
+    }
+}
+
+/// How a 2D image is wrapped around an object.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Projection {
+    /// Projected along the three axes and blended by the normal. `sharpness` controls how
+    /// quickly one projection takes over from the others.
+    Triplanar { scale: f64, sharpness: f64 },
+    /// Longitude and latitude around the origin.
+    Spherical,
+    /// Wrapped around the Y axis, `height` units per repeat.
+    Cylindrical { height: f64 },
+}
+
+/// Bilinearly filtered image, repeating in both directions.
+#[derive(Clone, Debug)]
+pub struct ImageTexture {
+    width: u32,
+    height: u32,
+    pixels: Vec<Vec3>,
+}
+
+impl ImageTexture {
+    /// Fails on images without pixels, which have nothing to sample.
+    pub fn load(path: impl AsRef<Path>) -> image::ImageResult<ImageTexture> {
+        let image = image::open(path)?.into_rgb32f();
+        let (width, height) = image.dimensions();
+        if width == 0 || height == 0 {
+            return Err(ImageError::Parameter(ParameterError::from_kind(
+                ParameterErrorKind::DimensionMismatch,
+            )));
+        }
+        let pixels = image
+            .pixels()
+            .map(|p| Vec3::new(p[0] as f64, p[1] as f64, p[2] as f64))
+            .collect();
+        Ok(ImageTexture {
+            width,
+            height,
+            pixels,
+        })
+    }
+
+    fn texel(&self, x: i64, y: i64) -> Vec3 {
+        let x = x.rem_euclid(self.width as i64) as usize;
+        let y = y.rem_euclid(self.height as i64) as usize;
+        self.pixels[y * self.width as usize + x]
+    }
+
+    /// Colour at texture coordinates, (0, 0) being the top left corner.
+    pub fn sample_uv(&self, u: f64, v: f64) -> Vec3 {
+        let x = u * self.width as f64 - 0.5;
+        let y = v * self.height as f64 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        mix(
+            mix(self.texel(x0, y0), self.texel(x0 + 1, y0), fx),
+            mix(self.texel(x0, y0 + 1), self.texel(x0 + 1, y0 + 1), fx),
+            fy,
+        )
+    }
+
+    /// Colour of the image projected onto the object. `local` and `normal` are in the
+    /// object's space; the normal is only used by triplanar mapping.
+    pub fn sample(&self, projection: Projection, local: Vec3, normal: Vec3) -> Vec3 {
+        match projection {
+            Projection::Triplanar { scale, sharpness } => {
+                let p = local * scale;
+                let weights = Vec3::new(
+                    normal.x.abs().powf(sharpness),
+                    normal.y.abs().powf(sharpness),
+                    normal.z.abs().powf(sharpness),
+                );
+                let total = weights.x + weights.y + weights.z;
+                let weights = if total > 0.0 {
+                    weights / total
+                } else {
+                    Vec3::new(1.0, 1.0, 1.0) / 3.0
+                };
+                self.sample_uv(p.z, -p.y) * weights.x
+                    + self.sample_uv(p.x, -p.z) * weights.y
+                    + self.sample_uv(p.x, -p.y) * weights.z
+            }
+            Projection::Spherical => {
+                let direction = local.normalize();
+                let u = 0.5 + direction.z.atan2(direction.x) / (2.0 * std::f64::consts::PI);
+                let v = direction.y.clamp(-1.0, 1.0).acos() / std::f64::consts::PI;
+                self.sample_uv(u, v)
+            }
+            Projection::Cylindrical { height } => {
+                let u = 0.5 + local.z.atan2(local.x) / (2.0 * std::f64::consts::PI);
+                let v = -local.y / height;
+                self.sample_uv(u, v)
+            }
+        }
+    }
+}
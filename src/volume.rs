@@ -0,0 +1,246 @@
+//! Participating media: fog, smoke and clouds the rays travel through before reaching a
+//! surface or the background.
+
+use crate::camera::Ray;
+use crate::math::Vec3;
+use crate::noise::{Fractal, Noise};
+use crate::scene::{ray_hits, Scene};
+use crate::shape::Object;
+use crate::stats::Counters;
+use std::f64::consts::PI;
+use std::fmt::Debug;
+use std::fmt::Formatter;
+use std::sync::Arc;
+
+/// Density of a medium at a point of the scene, in extinction per unit of distance.
+pub type DensityField = Arc<dyn Fn(Vec3) -> f64 + Send + Sync>;
+
+#[derive(Clone)]
+pub enum Medium {
+    /// Fog pooling at the ground: `density` at `base_height`, thinning out exponentially
+    /// above it at the rate `falloff`.
+    HeightFog {
+        density: f64,
+        falloff: f64,
+        base_height: f64,
+        colour: Vec3,
+    },
+    /// Constant density inside an object, like a foggy sphere.
+    Bounded {
+        bounds: Object,
+        density: f64,
+        colour: Vec3,
+    },
+    /// Arbitrary density inside an object, for clouds and smoke. The field is only
+    /// evaluated inside `bounds`.
+    Field {
+        bounds: Object,
+        density: DensityField,
+        colour: Vec3,
+    },
+}
+
+impl Debug for Medium {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        match self {
+            Medium::HeightFog {
+                density,
+                falloff,
+                base_height,
+                colour,
+            } => write!(
+                f,
+                "HeightFog {{ density: {:?}, falloff: {:?}, base_height: {:?}, colour: {:?} }}",
+                density, falloff, base_height, colour
+            ),
+            Medium::Bounded {
+                bounds,
+                density,
+                colour,
+            } => write!(
+                f,
+                "Bounded {{ bounds: {:?}, density: {:?}, colour: {:?} }}",
+                bounds, density, colour
+            ),
+            Medium::Field { bounds, colour, .. } => {
+                write!(f, "Field {{ bounds: {:?}, colour: {:?} }}", bounds, colour)
+            }
+        }
+    }
+}
+
+impl Medium {
+    /// Billowing cloud filling `bounds`: dense in the middle, frayed by fractal noise
+    /// near the surface of the object.
+    pub fn cloud(bounds: Object, noise: Noise, fractal: Fractal, density: f64) -> Medium {
+        let shape = bounds.clone();
+        Medium::Field {
+            bounds,
+            density: Arc::new(move |point| {
+                let local = shape.local_point(point);
+                let inside = -shape.distance(point) + 0.4 * fractal.fbm(&noise, local);
+                (inside * 4.0).clamp(0.0, 1.0) * density
+            }),
+            colour: Vec3::new(1.0, 1.0, 1.0),
+        }
+    }
+
+    pub fn density(&self, point: Vec3) -> f64 {
+        match self {
+            Medium::HeightFog {
+                density,
+                falloff,
+                base_height,
+                ..
+            } => density * (-falloff * (point.y - base_height)).exp().min(1.0),
+            Medium::Bounded {
+                bounds, density, ..
+            } => {
+                if bounds.distance(point) < 0.0 {
+                    *density
+                } else {
+                    0.0
+                }
+            }
+            Medium::Field {
+                bounds, density, ..
+            } => {
+                if bounds.distance(point) < 0.0 {
+                    density(point).max(0.0)
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+
+    /// Fraction of the light scattered rather than absorbed, per channel.
+    pub fn colour(&self) -> Vec3 {
+        match self {
+            Medium::HeightFog { colour, .. }
+            | Medium::Bounded { colour, .. }
+            | Medium::Field { colour, .. } => *colour,
+        }
+    }
+}
+
+/// Distant light whose light scatters in the media. Objects in its way cast light shafts.
+#[derive(Clone, Copy, Debug)]
+pub struct Sun {
+    /// Direction towards the sun.
+    pub direction: Vec3,
+    pub colour: Vec3,
+}
+
+/// Everything between the camera and the surfaces.
+#[derive(Clone, Debug)]
+pub struct Atmosphere {
+    pub media: Vec<Medium>,
+    pub sun: Option<Sun>,
+    /// Light scattered from every direction, keeping media visible in the shade.
+    pub ambient: Vec3,
+    /// Henyey-Greenstein asymmetry, from -1 (back scattering) to 1 (forward scattering).
+    pub anisotropy: f64,
+    /// Length of the ray integrated for rays that hit nothing.
+    pub max_distance: f64,
+    pub steps: u32,
+    /// Steps towards the sun to find how much of its light the media let through.
+    pub shadow_steps: u32,
+    pub shadow_distance: f64,
+}
+
+impl Atmosphere {
+    pub fn new(media: Vec<Medium>) -> Atmosphere {
+        Atmosphere {
+            media,
+            sun: None,
+            ambient: Vec3::new(0.3, 0.3, 0.3),
+            anisotropy: 0.3,
+            max_distance: 30.0,
+            steps: 48,
+            shadow_steps: 6,
+            shadow_distance: 4.0,
+        }
+    }
+
+    pub fn set_sun(&mut self, direction: Vec3, colour: Vec3) {
+        self.sun = Some(Sun {
+            direction: direction.normalize(),
+            colour,
+        });
+    }
+
+    /// Extinction and scattering colour of every medium together at `point`.
+    fn extinction(&self, point: Vec3) -> (f64, Vec3) {
+        let mut total = 0.0;
+        let mut colour = Vec3::new(0.0, 0.0, 0.0);
+        for medium in &self.media {
+            let density = medium.density(point);
+            if density > 0.0 {
+                total += density;
+                colour += medium.colour() * density;
+            }
+        }
+        if total > 0.0 {
+            (total, colour / total)
+        } else {
+            (0.0, colour)
+        }
+    }
+
+    fn phase(&self, cos: f64) -> f64 {
+        let g = self.anisotropy;
+        (1.0 - g * g) / (4.0 * PI * (1.0 + g * g - 2.0 * g * cos).powf(1.5))
+    }
+
+    /// Fraction of the sun's light reaching `point`: zero in the shadow of an object,
+    /// dimmed by the media in between otherwise.
+    fn sun_transmittance(&self, scene: &Scene, point: Vec3, sun: &Sun) -> f64 {
+        Counters::count(|counters| counters.shadow_rays += 1);
+        if ray_hits(scene, Ray::new(point, sun.direction)).is_some() {
+            return 0.0;
+        }
+        let step = self.shadow_distance / self.shadow_steps as f64;
+        let optical_depth: f64 = (0..self.shadow_steps)
+            .map(|i| {
+                self.extinction(point + sun.direction * ((i as f64 + 0.5) * step))
+                    .0
+                    * step
+            })
+            .sum();
+        (-optical_depth).exp()
+    }
+
+    /// Single scattering along `ray` up to `length`. Returns the light scattered towards
+    /// the camera and the fraction of the light from behind the media that gets through.
+    /// `jitter` in [0, 1) offsets the samples to trade banding for noise.
+    pub fn integrate(&self, scene: &Scene, ray: Ray, length: f64, jitter: f64) -> (Vec3, f64) {
+        let step = length / self.steps as f64;
+        let mut scattered = Vec3::new(0.0, 0.0, 0.0);
+        let mut transmittance = 1.0;
+        for i in 0..self.steps {
+            let point = ray.point((i as f64 + jitter) * step);
+            let (extinction, colour) = self.extinction(point);
+            if extinction <= 0.0 {
+                continue;
+            }
+            let mut light = self.ambient;
+            if let Some(sun) = &self.sun {
+                let visibility = self.sun_transmittance(scene, point, sun);
+                if visibility > 0.0 {
+                    let phase = 4.0 * PI * self.phase(ray.direction.dot(sun.direction));
+                    light += sun.colour * (visibility * phase);
+                }
+            }
+            // light scattered within the step, integrated exactly for a constant density
+            let absorbed = 1.0 - (-extinction * step).exp();
+            scattered += colour * light * (absorbed * transmittance);
+            transmittance *= 1.0 - absorbed;
+            if transmittance < 0.001 {
+                transmittance = 0.0;
+                break;
+            }
+        }
+        (scattered, transmittance)
+    }
+}
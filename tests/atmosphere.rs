@@ -0,0 +1,54 @@
+//! An atmosphere fades what is behind it with distance by itself: the pixels of a render
+//! through one are the colours it gives, without the depth fade on top.
+
+use surplace::environment::Environment;
+use surplace::math::{Quat, Vec3};
+use surplace::scene::Scene;
+use surplace::shape::{Object, Shape};
+use surplace::volume::{Atmosphere, Medium};
+
+const WIDTH: u32 = 32;
+const HEIGHT: u32 = 32;
+
+/// A sphere far down the view, in fog, in front of a light sky.
+fn scene() -> Scene {
+    let mut scene = Scene::empty();
+    scene.set_first_object(Object::new(
+        Vec3::new(0.0, 0.0, -25.0),
+        Quat::identity(),
+        Vec3::new(2.0, 2.0, 2.0),
+        Shape::Sphere,
+    ));
+    scene.environment = Environment::Solid(Vec3::new(0.6, 0.7, 0.9));
+    let mut atmosphere = Atmosphere::new(vec![Medium::HeightFog {
+        density: 0.05,
+        falloff: 0.1,
+        base_height: 0.0,
+        colour: Vec3::new(0.9, 0.9, 0.9),
+    }]);
+    atmosphere.steps = 8;
+    scene.atmosphere = Some(atmosphere);
+    scene.camera.position = Vec3::new(0.0, 0.0, 1.0);
+    scene.camera.set_aspect_ratio(WIDTH, HEIGHT);
+    scene
+}
+
+#[test]
+fn pixels_are_the_atmosphere_colour() {
+    let render = scene().render(WIDTH, HEIGHT);
+    assert!(render.atmosphere);
+    let to_byte = |v: f64| (v.min(1.0) * 255.0).round() as u8;
+    // the far sphere in the middle, and the sky in a corner
+    for (x, y) in [(WIDTH / 2, HEIGHT / 2), (0, 0)] {
+        let (r, g, b, _) = render.colour[y as usize][x as usize];
+        let pixel = render.final_pixel(x, y);
+        assert_eq!(
+            pixel.0,
+            [to_byte(r), to_byte(g), to_byte(b), 255],
+            "pixel ({x}, {y})"
+        );
+        assert!(r > 0.1, "pixel ({x}, {y}) sees fog, not black: {r}");
+    }
+    let depth = render.depth[(HEIGHT / 2) as usize][(WIDTH / 2) as usize];
+    assert!(depth > 20.0, "the middle hits the far sphere, at {depth}");
+}
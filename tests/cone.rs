@@ -0,0 +1,88 @@
+//! The cone marching pre-pass only lets the primary rays skip empty space: it must save
+//! steps without changing what they hit.
+
+use surplace::math::{Quat, Vec3};
+use surplace::scene::Scene;
+use surplace::shape::{Object, Shape};
+
+const WIDTH: u32 = 64;
+const HEIGHT: u32 = 32;
+
+/// A sphere, a pillar and a Mandelbulb some way down the view, with empty space in front.
+fn scene() -> Scene {
+    let mut scene = Scene::empty();
+    scene.set_first_object(Object::new(
+        Vec3::new(-3.0, 0.0, -8.0),
+        Quat::identity(),
+        Vec3::new(1.0, 1.0, 1.0),
+        Shape::Sphere,
+    ));
+    let mut pillar = Object::new(
+        Vec3::new(3.0, 0.0, -8.0),
+        Quat::rot_y(0.5),
+        Vec3::new(1.0, 2.0, 1.0),
+        Shape::Cube,
+    );
+    pillar.set_inflate(0.1);
+    scene.add_object(pillar);
+    scene.add_object(Object::new(
+        Vec3::new(0.0, 0.0, -8.0),
+        Quat::rot_x(0.5),
+        Vec3::new(1.0, 1.0, 1.0),
+        Shape::Mandelbulb {
+            iterations: 8,
+            power: 8.0,
+        },
+    ));
+    scene.camera.position = Vec3::new(0.0, 0.0, 1.0);
+    scene.camera.set_aspect_ratio(WIDTH, HEIGHT);
+    scene
+}
+
+#[test]
+fn cone_prepass_saves_steps_for_the_same_image() {
+    let mut scene = scene();
+    scene.settings.cone_tile = 0;
+    let (plain, plain_stats) = scene.render_with_stats(WIDTH, HEIGHT);
+    scene.settings.cone_tile = 8;
+    let (coned, coned_stats) = scene.render_with_stats(WIDTH, HEIGHT);
+
+    // the pre-pass changes how many steps the rays take, and so how dark the final image
+    // is, but not what they hit. Rays grazing a silhouette can stop just inside or just
+    // outside the hit distance depending on where their steps land.
+    let footprint = scene.camera.pixel_footprint(HEIGHT);
+    let mut grazing = 0;
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            let (with, without) = (coned.get(x, y), plain.get(x, y));
+            if with.colour.3 == without.colour.3 {
+                assert_eq!(coned.object_id(x, y), plain.object_id(x, y));
+                let (a, b) = (with.colour, without.colour);
+                let difference = [a.0 - b.0, a.1 - b.1, a.2 - b.2].map(f64::abs);
+                assert!(
+                    difference.iter().all(|&d| d < 0.01),
+                    "pixel ({x}, {y}): {b:?} without the pre-pass, {a:?} with it"
+                );
+                continue;
+            }
+            let (hit, miss) = if with.colour.3 > without.colour.3 {
+                (with, without)
+            } else {
+                (without, with)
+            };
+            assert!(
+                miss.min_distance < footprint * hit.depth,
+                "pixel ({x}, {y}) hits {hit:?} only one way, missing far away: {miss:?}"
+            );
+            grazing += 1;
+        }
+    }
+    assert!(grazing <= 4, "{grazing} pixels changed at the silhouettes");
+    let (plain, coned) = (plain_stats.total(), coned_stats.total());
+    assert!(
+        coned.iterations < plain.iterations,
+        "{} steps with the pre-pass, setup included, {} without",
+        coned.iterations,
+        plain.iterations
+    );
+}
@@ -0,0 +1,123 @@
+//! The objects inside instances keep their place among the scene's objects, so that the
+//! timeline, the ID passes and the names all mean the same object by the same index. Their
+//! shared subtrees don't keep scenes from being rendered on other threads.
+
+use std::sync::Arc;
+use surplace::animation::{Interpolation, Timeline};
+use surplace::math::{Quat, Vec3};
+use surplace::scene::{Instance, ObjectTree, Operation, Render, Scene, TreeNode};
+use surplace::shape::{Object, Shape};
+
+const WIDTH: u32 = 32;
+const HEIGHT: u32 = 32;
+
+fn sphere(position: Vec3, name: &str) -> Object {
+    let mut sphere = Object::new(
+        position,
+        Quat::identity(),
+        Vec3::new(1.0, 1.0, 1.0),
+        Shape::Sphere,
+    );
+    sphere.set_name(name);
+    sphere
+}
+
+/// Two instances of a pair of spheres, then a sphere of its own.
+fn scene() -> Scene {
+    let pair = Arc::new(TreeNode::Node(ObjectTree::new(
+        Operation::Union,
+        vec![
+            TreeNode::Leaf(sphere(Vec3::new(-1.0, 0.0, 0.0), "left")),
+            TreeNode::Leaf(sphere(Vec3::new(1.0, 0.0, 0.0), "right")),
+        ],
+    )));
+    let mut scene = Scene::empty();
+    scene.scene = TreeNode::Node(ObjectTree::new(
+        Operation::Union,
+        vec![
+            TreeNode::Instance(Instance::new(
+                pair.clone(),
+                Vec3::new(-3.0, 3.0, -10.0),
+                Quat::identity(),
+                1.0,
+            )),
+            TreeNode::Instance(Instance::new(
+                pair,
+                Vec3::new(3.0, 3.0, -10.0),
+                Quat::identity(),
+                1.0,
+            )),
+            TreeNode::Leaf(sphere(Vec3::new(0.0, -3.0, -10.0), "own")),
+        ],
+    ));
+    scene.camera.position = Vec3::new(0.0, 0.0, 1.0);
+    scene.camera.set_aspect_ratio(WIDTH, HEIGHT);
+    scene
+}
+
+#[test]
+fn timeline_and_id_pass_agree_after_instances() {
+    let mut scene = scene();
+    let index = scene
+        .objects()
+        .iter()
+        .position(|object| object.name.as_deref() == Some("own"))
+        .unwrap();
+    assert_eq!(index, 4, "the four shared spheres come first");
+    assert_eq!(scene.objects_mut().len(), scene.objects().len());
+
+    // the timeline moves the sphere of its own in front of the camera
+    let target = Vec3::new(0.0, 0.0, -6.0);
+    let mut timeline = Timeline::new();
+    timeline
+        .object(index)
+        .position
+        .insert(0.0, target, Interpolation::Linear);
+    timeline.apply(&mut scene, 0.0);
+    let moved = scene.objects()[index].position;
+    assert_eq!((moved.x, moved.y, moved.z), (target.x, target.y, target.z));
+
+    // and the middle of the frame sees it under the same id and name
+    scene.camera.look_at(target);
+    let render = scene.render(WIDTH, HEIGHT);
+    assert_eq!(render.object_id(WIDTH / 2, HEIGHT / 2), Some(index as u32));
+    assert!(scene.object_names()[index].ends_with("own"));
+}
+
+#[test]
+fn timeline_leaves_shared_objects_alone() {
+    let mut scene = scene();
+    let mut timeline = Timeline::new();
+    timeline
+        .object(0)
+        .position
+        .insert(0.0, Vec3::new(5.0, 5.0, 5.0), Interpolation::Linear);
+    timeline.apply(&mut scene, 0.0);
+    let left = scene.objects()[0].position;
+    assert_eq!((left.x, left.y, left.z), (-1.0, 0.0, 0.0));
+}
+
+/// Compiles only for types that can be shared between threads.
+fn send_and_sync<T: Send + Sync>() {}
+
+#[test]
+fn scenes_render_on_other_threads() {
+    send_and_sync::<Scene>();
+    send_and_sync::<Render>();
+    let scene = scene().compiled();
+    let local = scene.render(WIDTH, HEIGHT);
+    // both instances share their subtree and its compiled program across the threads
+    let threaded = std::thread::scope(|scope| {
+        let renders: Vec<_> = (0..2)
+            .map(|_| scope.spawn(|| scene.render(WIDTH, HEIGHT)))
+            .collect();
+        renders
+            .into_iter()
+            .map(|render| render.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+    for render in threaded {
+        assert_eq!(render.colour, local.colour);
+        assert_eq!(render.depth, local.depth);
+    }
+}
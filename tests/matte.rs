@@ -0,0 +1,92 @@
+//! The cryptomatte manifest is JSON, and must stay valid whatever the objects are called.
+
+use surplace::math::{Quat, Vec3};
+use surplace::matte::name_hash;
+use surplace::scene::Scene;
+use surplace::shape::{Object, Shape};
+
+const WIDTH: u32 = 16;
+const HEIGHT: u32 = 16;
+
+/// Reads a JSON string starting at its opening quote. Returns it unescaped and the rest
+/// of the text after it.
+fn json_string(text: &str) -> (String, &str) {
+    let mut chars = text.strip_prefix('"').expect("a string").char_indices();
+    let mut string = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return (string, &text[i + 2..]),
+            '\\' => match chars.next().expect("an escape").1 {
+                'n' => string.push('\n'),
+                't' => string.push('\t'),
+                'r' => string.push('\r'),
+                'u' => {
+                    let hex: String = (0..4).map(|_| chars.next().unwrap().1).collect();
+                    string.push(char::from_u32(u32::from_str_radix(&hex, 16).unwrap()).unwrap());
+                }
+                c @ ('"' | '\\' | '/') => string.push(c),
+                c => panic!("unknown escape \\{c}"),
+            },
+            c => {
+                assert!(c >= ' ', "unescaped control character {c:?}");
+                string.push(c);
+            }
+        }
+    }
+    panic!("unterminated string");
+}
+
+/// Names and hashes of a manifest, a JSON object of strings.
+fn manifest_entries(manifest: &str) -> Vec<(String, String)> {
+    let mut rest = manifest.strip_prefix('{').expect("an object");
+    let mut entries = Vec::new();
+    while !rest.starts_with('}') {
+        let (name, after) = json_string(rest);
+        let (hash, after) = json_string(after.strip_prefix(':').expect("a colon"));
+        entries.push((name, hash));
+        rest = after.strip_prefix(',').unwrap_or(after);
+    }
+    assert_eq!(rest, "}");
+    entries
+}
+
+#[test]
+fn manifest_escapes_names() {
+    let name = "say \"hi\" \\ to\tthe\nsphere\u{1}";
+    let mut sphere = Object::new(
+        Vec3::new(0.0, 0.0, -4.0),
+        Quat::identity(),
+        Vec3::new(1.0, 1.0, 1.0),
+        Shape::Sphere,
+    );
+    sphere.set_name(name);
+    let mut scene = Scene::empty();
+    scene.set_first_object(sphere);
+    scene.camera.set_aspect_ratio(WIDTH, HEIGHT);
+    let render = scene.render(WIDTH, HEIGHT);
+    assert_eq!(render.object_id(WIDTH / 2, HEIGHT / 2), Some(0));
+
+    let path = std::env::temp_dir().join(format!("surplace-matte-{}.exr", std::process::id()));
+    render.to_exr(path.to_str().unwrap()).unwrap();
+    let meta = exr::meta::MetaData::read_from_file(&path, false).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    // the objects' and the materials' manifests
+    let entries: Vec<(String, String)> = meta.headers[0]
+        .own_attributes
+        .other
+        .iter()
+        .filter(|(field, _)| field.to_string().ends_with("/manifest"))
+        .flat_map(|(_, value)| match value {
+            exr::meta::attribute::AttributeValue::Text(text) => manifest_entries(&text.to_string()),
+            value => panic!("manifest {value:?} is not text"),
+        })
+        .collect();
+    let full_name = render.object_name(0);
+    assert!(full_name.ends_with(name));
+    let hash = format!("{:08x}", name_hash(&full_name));
+    assert!(
+        entries.contains(&(full_name.clone(), hash)),
+        "{full_name:?} is missing from {entries:?}"
+    );
+}
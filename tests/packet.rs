@@ -0,0 +1,186 @@
+//! Packets of rays must give the same image as rays marched one by one, lanes that go
+//! separate ways included, in either precision.
+
+use std::sync::Arc;
+use surplace::math::{Quat, Vec3};
+use surplace::packet::{Vec3x4, LANES};
+use surplace::program::Program;
+use surplace::sampling::{AntiAliasing, Filter, SamplePattern};
+use surplace::scene::{
+    ray_march, Instance, ObjectTree, Operation, Precision, Sample, Scene, TreeNode,
+};
+use surplace::shape::{Object, Shape};
+use surplace::single::Vec3f;
+
+const WIDTH: u32 = 64;
+const HEIGHT: u32 = 32;
+
+/// A sphere blended with a pillar, a Mandelbulb, and a row of instances of a shared
+/// Mandelbulb behind them.
+fn scene() -> Scene {
+    let mut scene = Scene::empty();
+    scene.camera.position = Vec3::new(0.0, 0.0, 1.0);
+    scene.camera.set_aspect_ratio(WIDTH, HEIGHT);
+    let mut pillar = Object::new(
+        Vec3::new(-2.0, 0.0, -4.0),
+        Quat::rot_y(0.5),
+        Vec3::new(0.5, 1.5, 0.5),
+        Shape::Cube,
+    );
+    pillar.set_inflate(0.1);
+    let mandelbulb = |position| {
+        Object::new(
+            position,
+            Quat::rot_x(0.5),
+            Vec3::new(1.0, 1.0, 1.0),
+            Shape::Mandelbulb {
+                iterations: 8,
+                power: 8.0,
+            },
+        )
+    };
+    let shared = Arc::new(TreeNode::Leaf(mandelbulb(Vec3::new(0.0, 0.0, 0.0))));
+    let mut children = vec![
+        TreeNode::Node(ObjectTree::new(
+            Operation::SmoothUnion(0.5),
+            vec![
+                TreeNode::Leaf(Object::new(
+                    Vec3::new(-3.0, 0.0, -4.0),
+                    Quat::identity(),
+                    Vec3::new(1.0, 1.0, 1.0),
+                    Shape::Sphere,
+                )),
+                TreeNode::Leaf(pillar),
+            ],
+        )),
+        TreeNode::Leaf(mandelbulb(Vec3::new(1.0, 0.0, -4.0))),
+    ];
+    for i in 0..6 {
+        children.push(TreeNode::Instance(Instance::new(
+            shared.clone(),
+            Vec3::new(i as f64 * 2.0 - 5.0, 1.5, -9.0),
+            Quat::rot_y(i as f64 * 0.4),
+            0.8,
+        )));
+    }
+    scene.scene = TreeNode::Node(ObjectTree::new(Operation::Union, children));
+    scene.camera.look_at(Vec3::new(0.0, 0.0, -4.0));
+    scene
+}
+
+/// Whether two samples are the same to the bit. Debug prints floats exactly.
+fn same(a: &Sample, b: &Sample) -> bool {
+    format!("{a:?}") == format!("{b:?}")
+}
+
+#[test]
+fn packet_distances_match_scalar() {
+    let scene = scene();
+    let program = Program::compile(&scene.scene, scene.time, scene.frame);
+    // lanes far apart from each other, so that they disagree on what to cull
+    let points: Vec<Vec3> = (0..4000)
+        .map(|i| {
+            let i = i as f64;
+            Vec3::new(
+                7.0 * (i * 0.37).sin(),
+                3.0 * (i * 1.13).cos(),
+                -6.0 + 6.0 * (i * 0.71).sin(),
+            )
+        })
+        .collect();
+    for lanes in points.chunks_exact(LANES) {
+        let lanes: [Vec3; LANES] = std::array::from_fn(|i| lanes[i]);
+        let packet = program.distance_packet(Vec3x4::from_lanes(lanes));
+        let single = program.distance_packet_f32(Vec3x4::from_lanes(lanes.map(Vec3f::from)));
+        for (i, lane) in lanes.into_iter().enumerate() {
+            let scalar = program.distance(lane);
+            assert!(
+                packet.0[i].to_bits() == scalar.to_bits(),
+                "lane {i} at {lane:?}: packet {} but scalar {scalar}",
+                packet.0[i]
+            );
+            let scalar = program.distance_f32(Vec3f::from(lane));
+            assert!(
+                single.0[i].to_bits() == scalar.to_bits(),
+                "lane {i} at {lane:?} in f32: packet {} but scalar {scalar}",
+                single.0[i]
+            );
+        }
+    }
+}
+
+#[test]
+fn packet_render_matches_scalar() {
+    for precision in [Precision::Double, Precision::Single] {
+        let mut scene = scene();
+        scene.settings.precision = precision;
+        scene.settings.packets = true;
+        let packets = scene.render(WIDTH, HEIGHT);
+        scene.settings.packets = false;
+        let scalar = scene.render(WIDTH, HEIGHT);
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                assert!(
+                    same(&packets.get(x, y), &scalar.get(x, y)),
+                    "pixel ({x}, {y}) in {precision:?}: {:?} marched by packets, {:?} one by one",
+                    packets.get(x, y),
+                    scalar.get(x, y)
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn diverging_packets_match_scalar() {
+    let scene = scene().compiled();
+    // rows of rays across the silhouettes of the objects, some hitting and some missing
+    let rays: Vec<_> = (0..WIDTH)
+        .flat_map(|x| [0.3, 0.5, 0.62].map(|y| scene.camera.ray(x as f64 / WIDTH as f64, y)))
+        .collect();
+    let hits: Vec<bool> = rays
+        .iter()
+        .map(|ray| ray_march(&scene, *ray).did_hit)
+        .collect();
+    assert!(
+        hits.chunks(LANES)
+            .any(|lanes| lanes.contains(&true) && lanes.contains(&false)),
+        "no packet has both hits and misses"
+    );
+    let mut packets = scene.clone();
+    packets.settings.packets = true;
+    let mut scalar = scene;
+    scalar.settings.packets = false;
+    for (i, (a, b)) in packets
+        .shade_all(&rays)
+        .iter()
+        .zip(scalar.shade_all(&rays))
+        .enumerate()
+    {
+        assert!(same(a, &b), "ray {i}: {a:?} in a packet, {b:?} on its own");
+    }
+}
+
+#[test]
+fn packets_do_no_more_work_than_scalar() {
+    let mut supersampled = scene();
+    let mut anti_aliasing = AntiAliasing::new(SamplePattern::Stratified, 4, Filter::Mitchell);
+    anti_aliasing.adaptive_threshold = Some(0.05);
+    supersampled.settings.anti_aliasing = anti_aliasing;
+    // an odd size leaves blocks of pixels cut by the frame
+    for (mut scene, width, height) in [(scene(), WIDTH, HEIGHT), (supersampled, 33, 17)] {
+        scene.settings.packets = true;
+        let (packets, packet_stats) = scene.render_with_stats(width, height);
+        scene.settings.packets = false;
+        let (scalar, scalar_stats) = scene.render_with_stats(width, height);
+        assert_eq!(packet_stats.total(), scalar_stats.total());
+        for y in 0..height {
+            for x in 0..width {
+                assert!(
+                    same(&packets.get(x, y), &scalar.get(x, y)),
+                    "pixel ({x}, {y}) of {width}x{height}"
+                );
+            }
+        }
+    }
+}
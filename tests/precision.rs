@@ -0,0 +1,140 @@
+//! Renders and distances in single precision must stay within a small tolerance of the
+//! f64 ones.
+
+use surplace::math::{Quat, Vec3};
+use surplace::scene::{Precision, Render, Scene};
+use surplace::shape::{Object, Shape};
+use surplace::single::Vec3f;
+
+const WIDTH: u32 = 96;
+const HEIGHT: u32 = 48;
+
+fn demo_scene(precision: Precision) -> Scene {
+    let mut scene = Scene::empty();
+    scene.camera.position = Vec3::new(0.0, 0.0, 1.0);
+    scene.camera.set_aspect_ratio(WIDTH, HEIGHT);
+    scene.set_first_object(Object::new(
+        Vec3::new(-3.0, 0.0, -4.0),
+        Quat::identity(),
+        Vec3::new(1.0, 1.0, 1.0),
+        Shape::Sphere,
+    ));
+    let mut cube = Object::new(
+        Vec3::new(3.0, 0.0, -4.0),
+        Quat::rot_y(0.5),
+        Vec3::new(1.0, 2.0, 1.0),
+        Shape::Cube,
+    );
+    cube.set_inflate(0.1);
+    scene.add_object(cube);
+    let mut mandelbulb = Object::new(
+        Vec3::new(0.0, 0.0, -4.0),
+        Quat::rot_x(0.5),
+        Vec3::new(1.0, 1.0, 1.0),
+        Shape::Mandelbulb {
+            iterations: 10,
+            power: 8.0,
+        },
+    );
+    mandelbulb.set_inflate(0.001);
+    scene.add_object(mandelbulb);
+    scene.camera.look_at(Vec3::new(0.0, 0.0, -4.0));
+    scene.settings.precision = precision;
+    scene
+}
+
+fn render(precision: Precision) -> Render {
+    demo_scene(precision).render(WIDTH, HEIGHT)
+}
+
+#[test]
+fn single_precision_render_matches_double() {
+    let double = render(Precision::Double);
+    let single = render(Precision::Single);
+    let mut total = 0;
+    let mut largest = 0;
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            let a = double.final_pixel(x, y).0;
+            let b = single.final_pixel(x, y).0;
+            for (a, b) in a.into_iter().zip(b) {
+                total += a.abs_diff(b) as u32;
+                largest = largest.max(a.abs_diff(b));
+            }
+        }
+    }
+    let mean = total as f64 / (WIDTH * HEIGHT * 4) as f64;
+    assert!(mean < 0.5, "mean channel difference {mean}");
+    assert!(largest <= 16, "largest channel difference {largest}");
+}
+
+#[test]
+fn single_precision_distance_matches_double() {
+    let scene = demo_scene(Precision::Double);
+    let compiled = scene.compiled();
+    for i in 0..1000 {
+        let t = i as f64 / 1000.0;
+        let point = Vec3::new(
+            10.0 * t - 5.0,
+            (37.0 * t).sin() * 3.0,
+            (23.0 * t).cos() * 3.0 - 4.0,
+        );
+        let double = scene.distance(point);
+        let tolerance = 1e-4 * (1.0 + double.abs());
+        for single in [
+            scene.distance_f32(Vec3f::from(point)),
+            compiled.distance_f32(Vec3f::from(point)),
+        ] {
+            assert!(
+                (single as f64 - double).abs() < tolerance,
+                "{point:?}: {single} against {double}"
+            );
+        }
+    }
+}
+
+/// Largest error of f32 distances to a sphere and a cube a thousand units out, at points
+/// near their surfaces, where the marches need the distance to be right.
+#[test]
+fn single_precision_error_far_from_origin() {
+    let centre = Vec3::new(600.0, -500.0, 620.0);
+    let mut scene = Scene::empty();
+    scene.set_first_object(Object::new(
+        centre,
+        Quat::identity(),
+        Vec3::new(1.0, 1.0, 1.0),
+        Shape::Sphere,
+    ));
+    scene.add_object(Object::new(
+        centre + Vec3::new(3.0, 0.0, 0.0),
+        Quat::rot_y(0.5),
+        Vec3::new(1.0, 1.0, 1.0),
+        Shape::Cube,
+    ));
+    let compiled = scene.compiled();
+    let mut largest: f64 = 0.0;
+    let mut near = 0;
+    for i in 0..2000 {
+        let t = i as f64 / 2000.0;
+        let offset = Vec3::new(
+            4.0 * t - 0.5,
+            (37.0 * t).sin() * 1.2,
+            (23.0 * t).cos() * 1.2,
+        );
+        let point = centre + offset;
+        let double = scene.distance(point);
+        if double.abs() > 0.1 {
+            continue;
+        }
+        near += 1;
+        for single in [
+            scene.distance_f32(Vec3f::from(point)),
+            compiled.distance_f32(Vec3f::from(point)),
+        ] {
+            largest = largest.max((single as f64 - double).abs());
+        }
+    }
+    assert!(near > 100, "only {near} points near the surfaces");
+    // the points alone are rounded by up to half an f32 ulp at 1e3, about 3e-5
+    assert!(largest < 1e-4, "largest error {largest}");
+}
@@ -0,0 +1,243 @@
+//! Compiled scenes must give the same distances as the tree they were compiled from, to the
+//! bit, whatever the shape of the tree and however much of it the bounds let them skip.
+
+use std::sync::Arc;
+use surplace::math::{Quat, Vec3};
+use surplace::scene::{Instance, ObjectTree, Operation, Scene, TreeNode};
+use surplace::shape::{Object, Shape};
+
+fn object(position: Vec3, rotation: Quat, scale: Vec3, shape: Shape) -> Object {
+    Object::new(position, rotation, scale, shape)
+}
+
+fn sphere(x: f64, y: f64, z: f64) -> Object {
+    object(
+        Vec3::new(x, y, z),
+        Quat::identity(),
+        Vec3::new(1.0, 1.0, 1.0),
+        Shape::Sphere,
+    )
+}
+
+fn mandelbulb(position: Vec3, rotation: Quat) -> Object {
+    let mut mandelbulb = object(
+        position,
+        rotation,
+        Vec3::new(1.0, 1.0, 1.0),
+        Shape::Mandelbulb {
+            iterations: 8,
+            power: 8.0,
+        },
+    );
+    mandelbulb.set_inflate(0.001);
+    mandelbulb
+}
+
+/// The first scene of the demo: a sphere, a pillar and a Mandelbulb.
+fn demo_scene() -> Scene {
+    let mut scene = Scene::empty();
+    scene.set_first_object(sphere(-3.0, 0.0, -4.0));
+    let mut pillar = object(
+        Vec3::new(3.0, 0.0, -4.0),
+        Quat::rot_y(0.5),
+        Vec3::new(1.0, 2.0, 1.0),
+        Shape::Cube,
+    );
+    pillar.set_inflate(0.1);
+    scene.add_object(pillar);
+    scene.add_object(mandelbulb(Vec3::new(0.0, 0.0, -4.0), Quat::rot_x(0.5)));
+    scene
+}
+
+/// The blended scenes of the demo, a sphere with a cube and a sphere with a sphere.
+fn blended_scenes() -> Vec<Scene> {
+    let mut cube = object(
+        Vec3::new(1.0, 0.0, -4.0),
+        Quat::rot_y(0.5),
+        Vec3::new(1.0, 2.0, 1.0),
+        Shape::Cube,
+    );
+    cube.set_inflate(0.1);
+    let mut rotated = sphere(1.0, 0.0, -4.0);
+    rotated.rotation = Quat::rot_y(0.5);
+    [(0.5, cube), (2.0, rotated)]
+        .into_iter()
+        .map(|(blending, other)| {
+            let mut scene = Scene::empty();
+            scene.scene = TreeNode::Node(ObjectTree::new(
+                Operation::SmoothUnion(blending),
+                vec![
+                    TreeNode::Leaf(sphere(-1.0, 0.0, -4.0)),
+                    TreeNode::Leaf(other),
+                ],
+            ));
+            scene
+        })
+        .collect()
+}
+
+/// Two spheres added one after the other, the last scene of the demo.
+fn added_scene() -> Scene {
+    let mut scene = Scene::empty();
+    scene.set_first_object(sphere(-1.0, 0.0, -4.0));
+    scene.add_object(sphere(1.0, 0.0, -4.0));
+    scene
+}
+
+/// Objects added to a root that is alternately a union and a smooth union, so that nothing
+/// gets flattened and every object is a level deeper than the last.
+fn deep_scene(depth: usize) -> Scene {
+    let mut scene = Scene::empty();
+    scene.set_first_object(sphere(0.0, 0.0, -4.0));
+    for i in 1..depth {
+        let angle = i as f64 * 0.7;
+        let next = sphere(
+            3.0 * angle.cos(),
+            0.1 * i as f64 - 2.0,
+            -4.0 + 3.0 * angle.sin(),
+        );
+        if i % 2 == 0 {
+            scene.add_object(next);
+        } else {
+            let root = std::mem::replace(&mut scene.scene, TreeNode::Leaf(sphere(0.0, 0.0, 0.0)));
+            scene.scene = TreeNode::Node(ObjectTree::new(
+                Operation::SmoothUnion(0.3),
+                vec![root, TreeNode::Leaf(next)],
+            ));
+        }
+    }
+    scene
+}
+
+/// Smooth unions and intersections whose second child needs a deeper stack than the first,
+/// which the compiled program evaluates first.
+fn swapped_scene() -> Scene {
+    let pair = |x: f64, operation: Operation| {
+        TreeNode::Node(ObjectTree::new(
+            operation,
+            vec![
+                TreeNode::Leaf(sphere(x, 0.0, -4.0)),
+                TreeNode::Leaf(object(
+                    Vec3::new(x + 0.5, 0.3, -4.0),
+                    Quat::rot_z(0.3),
+                    Vec3::new(0.5, 1.5, 0.5),
+                    Shape::Cube,
+                )),
+            ],
+        ))
+    };
+    let mut scene = Scene::empty();
+    scene.scene = TreeNode::Node(ObjectTree::new(
+        Operation::SmoothUnion(0.4),
+        vec![
+            TreeNode::Leaf(sphere(0.0, 1.0, -4.0)),
+            pair(-2.0, Operation::Intersection),
+            TreeNode::Leaf(sphere(0.0, -1.0, -4.0)),
+            pair(2.0, Operation::SmoothUnion(0.2)),
+        ],
+    ));
+    scene
+}
+
+/// A grid of instances of a shared Mandelbulb among objects that can't be bounded: one
+/// with a vertex shader and a smooth union. Scaled and nested instances too.
+fn instanced_scene() -> Scene {
+    let shared = Arc::new(TreeNode::Leaf(mandelbulb(
+        Vec3::new(0.0, 0.0, 0.0),
+        Quat::identity(),
+    )));
+    let mut children = Vec::new();
+    for i in 0..36 {
+        let position = Vec3::new((i % 6) as f64 * 3.0 - 7.5, 0.0, -(i / 6) as f64 * 3.0 - 4.0);
+        let scale = 0.6 + 0.1 * (i % 5) as f64;
+        children.push(TreeNode::Instance(Instance::new(
+            shared.clone(),
+            position,
+            Quat::rot_y(i as f64 * 0.3),
+            scale,
+        )));
+    }
+    let pair = Arc::new(TreeNode::Node(ObjectTree::new(
+        Operation::Union,
+        vec![
+            TreeNode::Instance(Instance::new(
+                shared.clone(),
+                Vec3::new(-1.0, 0.0, 0.0),
+                Quat::identity(),
+                0.5,
+            )),
+            TreeNode::Leaf(sphere(1.0, 0.0, 0.0)),
+        ],
+    )));
+    children.push(TreeNode::Instance(Instance::new(
+        pair,
+        Vec3::new(0.0, 4.0, -8.0),
+        Quat::rot_x(0.4),
+        1.5,
+    )));
+    let mut wobbly = sphere(0.0, -3.0, -6.0);
+    wobbly.set_vertex_shader(Arc::new(|ctx| {
+        ctx.position + Vec3::new(0.3 * (2.0 * ctx.position.y + ctx.time).sin(), 0.0, 0.0)
+    }));
+    children.push(TreeNode::Leaf(wobbly));
+    children.push(TreeNode::Node(ObjectTree::new(
+        Operation::SmoothUnion(0.5),
+        vec![
+            TreeNode::Leaf(sphere(-5.0, 3.0, -6.0)),
+            TreeNode::Leaf(sphere(-4.0, 3.0, -6.0)),
+        ],
+    )));
+    let mut scene = Scene::empty();
+    scene.scene = TreeNode::Node(ObjectTree::new(Operation::Union, children));
+    scene.time = 0.7;
+    scene
+}
+
+/// Points on a grid through and around the scenes, the camera among them.
+fn points() -> Vec<Vec3> {
+    let mut points = vec![Vec3::new(0.0, 0.0, 1.0)];
+    for x in -20..=20 {
+        for y in -10..=10 {
+            for z in -30..=6 {
+                points.push(Vec3::new(x as f64 * 0.43, y as f64 * 0.41, z as f64 * 0.47));
+            }
+        }
+    }
+    points
+}
+
+fn assert_compiled_matches(name: &str, scene: &Scene) {
+    let compiled = scene.compiled();
+    for point in points() {
+        let expected = scene.distance(point);
+        let actual = compiled.distance(point);
+        assert!(
+            actual.to_bits() == expected.to_bits(),
+            "{name}: compiled distance {actual} at {point:?}, the tree gives {expected}"
+        );
+    }
+}
+
+#[test]
+fn compiled_demo_scenes_match_tree() {
+    assert_compiled_matches("demo", &demo_scene());
+    for (i, scene) in blended_scenes().iter().enumerate() {
+        assert_compiled_matches(&format!("blended {i}"), scene);
+    }
+    assert_compiled_matches("added", &added_scene());
+}
+
+#[test]
+fn compiled_deep_tree_matches_tree() {
+    assert_compiled_matches("deep", &deep_scene(100));
+}
+
+#[test]
+fn compiled_swapped_children_match_tree() {
+    assert_compiled_matches("swapped", &swapped_scene());
+}
+
+#[test]
+fn compiled_culled_instances_match_tree() {
+    assert_compiled_matches("instanced", &instanced_scene());
+}
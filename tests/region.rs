@@ -0,0 +1,65 @@
+//! Regions are cut to the frame they are in, however far past its edges they reach.
+
+use surplace::scene::Region;
+
+#[test]
+fn tiles_are_cut_at_the_frame_edges() {
+    let tiles = Region::tiles(10, 7, 4);
+    assert_eq!(
+        tiles,
+        vec![
+            Region::new(0, 0, 4, 4),
+            Region::new(4, 0, 4, 4),
+            Region::new(8, 0, 2, 4),
+            Region::new(0, 4, 4, 3),
+            Region::new(4, 4, 4, 3),
+            Region::new(8, 4, 2, 3),
+        ]
+    );
+    let covered: u32 = tiles.iter().map(|tile| tile.width * tile.height).sum();
+    assert_eq!(covered, 10 * 7);
+    assert_eq!(
+        Region::tiles(10, 7, 0).len(),
+        70,
+        "tiles are at least a pixel"
+    );
+    assert!(Region::tiles(0, 7, 4).is_empty());
+}
+
+#[test]
+fn grown_regions_stay_in_the_frame() {
+    assert_eq!(
+        Region::new(0, 0, 4, 4).grown(2, 10, 7),
+        Region::new(0, 0, 6, 6)
+    );
+    assert_eq!(
+        Region::new(8, 4, 2, 3).grown(2, 10, 7),
+        Region::new(6, 2, 4, 5)
+    );
+    assert_eq!(
+        Region::new(3, 3, 2, 2).grown(1, 10, 7),
+        Region::new(2, 2, 4, 4)
+    );
+    assert_eq!(Region::full(10, 7).grown(5, 10, 7), Region::full(10, 7));
+}
+
+#[test]
+fn regions_reaching_past_the_largest_pixel_end_there() {
+    let edge = Region::new(u32::MAX - 2, u32::MAX - 2, u32::MAX, u32::MAX);
+    let (width, height) = (u32::MAX, u32::MAX);
+    assert_eq!(
+        edge.clipped(width, height),
+        Region::new(u32::MAX - 2, u32::MAX - 2, 2, 2)
+    );
+    assert_eq!(
+        edge.grown(u32::MAX, width, height),
+        Region::full(u32::MAX, u32::MAX)
+    );
+    assert_eq!(
+        Region::new(5, 5, u32::MAX, u32::MAX).clipped(10, 7),
+        Region::new(5, 5, 5, 2)
+    );
+    assert!(edge.contains(u32::MAX - 1, u32::MAX - 1));
+    assert!(edge.overlaps(Region::new(u32::MAX - 1, 0, 1, u32::MAX)));
+    assert_eq!(Region::tiles(10, 7, u32::MAX), vec![Region::full(10, 7)]);
+}